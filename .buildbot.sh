@@ -9,6 +9,8 @@ case ${CI_TRACER_KIND} in
 esac
 
 export RUSTFLAGS="-C tracer=${CI_TRACER_KIND} -D warnings"
+# The internal workspace is built in release mode, so ask for trace verification explicitly.
+export YKD_VERIFY_IR=1
 
 # Use the most recent successful ykrustc build.
 tar jxf /opt/ykrustc-bin-snapshots/ykrustc-${CI_TRACER_KIND}-stage2-latest.tar.bz2
@@ -0,0 +1,31 @@
+#!/bin/sh
+
+# Fail if the line coverage in the LCOV report `$1` (e.g. that written by
+# `cargo xtask coverage` to `target/coverage/lcov.info`) is below `$2` percent.
+
+set -e
+
+if [ $# -ne 2 ]; then
+    echo "usage: $0 <lcov report> <minimum line coverage percentage>"
+    exit 1
+fi
+
+LCOV=$1
+MIN=$2
+
+# Each source file's record contains `LF:<lines found>` and `LH:<lines hit>`.
+awk -F: -v min="${MIN}" '
+    /^LF:/ { found += $2 }
+    /^LH:/ { hit += $2 }
+    END {
+        if (found == 0) {
+            print "No coverage data found"
+            exit 1
+        }
+        pc = 100 * hit / found
+        printf "Line coverage: %.2f%% (%d/%d lines)\n", pc, hit, found
+        if (pc < min) {
+            printf "Line coverage is below %d%%\n", min
+            exit 1
+        }
+    }' "${LCOV}"
@@ -0,0 +1,15 @@
+//! Feed arbitrary bytes to the pack decoder. SIR is read from the executable, so a corrupt
+//! section must result in an error, never a panic.
+
+#![no_main]
+
+use fallible_iterator::FallibleIterator;
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+use ykpack::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    let mut curs = Cursor::new(data);
+    let mut dec = Decoder::from(&mut curs);
+    while let Ok(Some(_)) = dec.next() {}
+});
@@ -1,16 +1,24 @@
 //! The Yorick TIR trace compiler.
 
 use crate::{
-    find_symbol, stack_builder::StackBuilder, CompileError, CompiledTrace, IndirectLoc, Location,
-    RegAlloc, RegAndOffset,
+    find_symbol, stack_builder::StackBuilder, CompileError, CompileOptions, CompiledTrace,
+    IndirectLoc, Location, RegAlloc, RegAndOffset,
 };
 use dynasmrt::{x64::Rq::*, DynamicLabel, DynasmApi, DynasmLabelApi, Register};
 use std::alloc::{alloc, Layout};
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
 use std::mem;
-use std::process::Command;
-use ykpack::{IRPlace, LocalDecl, SignedIntTy, Ty, TyKind, UnsignedIntTy};
+use std::panic;
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::sync::{atomic::AtomicU64, Once};
+use ykpack::{FloatTy, IRPlace, LocalDecl, SignedIntTy, Ty, TyKind, UnsignedIntTy};
 use yksg::{FrameInfo, StopgapInterpreter};
 use yktrace::sir::{INTERP_STEP_ARG, SIR};
 use yktrace::tir::{BinOp, CallOperand, Guard, GuardKind, Local, Statement, TirOp, TirTrace};
@@ -35,6 +43,13 @@ lazy_static! {
                                      R10.code(), RBX.code(), R12.code(), R13.code(), R14.code(),
                                      R15.code()];
 
+    // If `YKD_PRINT_IR=1`, print each TIR trace to stderr before it is compiled.
+    static ref PRINT_IR: bool = env_flag("YKD_PRINT_IR");
+    // If `YKD_PRINT_DISASM=1`, print the disassembly of each compiled trace to stderr.
+    static ref PRINT_DISASM: bool = env_flag("YKD_PRINT_DISASM");
+    // If `YKD_VERIFY_IR=1`, verify each TIR trace before it is compiled, even in release builds.
+    static ref VERIFY_IR: bool = env_flag("YKD_VERIFY_IR");
+
     // The interpreter context is always allocated to this reserved register.
     // This register should not appear in REG_POOL.
     static ref ICTX_REG: u8 = RDI.code();
@@ -82,7 +97,29 @@ macro_rules! binop_add_sub {
                     }
                     _ => unreachable!(format!("{}", SIR.ty(&opnd2.ty()))),
                 },
-                Location::Mem(..) => todo!(),
+                Location::Mem(ro) => match size {
+                    1 => {
+                        dynasm!(self.asm
+                            ; $op Rb(opnd1_reg), BYTE [Rq(ro.reg) + ro.off]
+                        );
+                    }
+                    2 => {
+                        dynasm!(self.asm
+                            ; $op Rw(opnd1_reg), WORD [Rq(ro.reg) + ro.off]
+                        );
+                    }
+                    4 => {
+                        dynasm!(self.asm
+                            ; $op Rd(opnd1_reg), DWORD [Rq(ro.reg) + ro.off]
+                        );
+                    }
+                    8 => {
+                        dynasm!(self.asm
+                            ; $op Rq(opnd1_reg), QWORD [Rq(ro.reg) + ro.off]
+                        );
+                    }
+                    _ => unreachable!(format!("{}", SIR.ty(&opnd2.ty()))),
+                },
                 Location::Const { val, .. } => {
                     let val = val.i64_cast();
                     match size {
@@ -120,10 +157,11 @@ macro_rules! binop_add_sub {
     }
 }
 
-/// Generates functions for mul/div-style operations.
+/// Generates functions for mul/div-style operations. `$signed` says whether the operation treats
+/// its operands as signed integers (i.e. `imul` and `idiv`).
 /// The first operand must be in a register.
 macro_rules! binop_mul_div {
-    ($name: ident, $op:expr) => {
+    ($name: ident, $op:expr, $signed:expr) => {
         fn $name(&mut self, opnd1_reg: u8, opnd2: &IRPlace) {
             // mul and div overwrite RAX, RDX, so save them first.
             dynasm!(self.asm
@@ -135,7 +173,34 @@ macro_rules! binop_mul_div {
                 ; mov rax, Rq(opnd1_reg)
             );
             let size = SIR.ty(&opnd2.ty()).size();
-            let src_loc = self.iplace_to_location(opnd2);
+            // Division divides RDX:RAX (or the narrower equivalent) by the operand, so the first
+            // operand must be extended to fill both. Multiplication ignores the upper bits, so
+            // this is harmless there.
+            if $signed {
+                match size {
+                    1 => dynasm!(self.asm; movsx rax, al),
+                    2 => dynasm!(self.asm; movsx rax, ax),
+                    4 => dynasm!(self.asm; movsxd rax, eax),
+                    _ => {}
+                }
+                dynasm!(self.asm
+                    ; cqo
+                );
+            } else {
+                match size {
+                    1 => dynasm!(self.asm; movzx eax, al),
+                    2 => dynasm!(self.asm; movzx eax, ax),
+                    4 => dynasm!(self.asm; mov eax, eax),
+                    _ => {}
+                }
+            }
+            let src_loc = match self.iplace_to_location(opnd2) {
+                // RAX and RDX have been overwritten above, so if the second operand lives in
+                // either, use the value we saved on the stack.
+                Location::Reg(r) if r == RAX.code() => Location::new_mem(RSP.code(), 8),
+                Location::Reg(r) if r == RDX.code() => Location::new_mem(RSP.code(), 0),
+                l => l,
+            };
             match src_loc {
                 Location::Reg(r) => match size {
                     1 => {
@@ -160,7 +225,29 @@ macro_rules! binop_mul_div {
                     }
                     _ => unreachable!(format!("{}", SIR.ty(&opnd2.ty()))),
                 },
-                Location::Mem(..) => todo!(),
+                Location::Mem(ro) => match size {
+                    1 => {
+                        dynasm!(self.asm
+                            ; $op BYTE [Rq(ro.reg) + ro.off]
+                        );
+                    }
+                    2 => {
+                        dynasm!(self.asm
+                            ; $op WORD [Rq(ro.reg) + ro.off]
+                        );
+                    }
+                    4 => {
+                        dynasm!(self.asm
+                            ; $op DWORD [Rq(ro.reg) + ro.off]
+                        );
+                    }
+                    8 => {
+                        dynasm!(self.asm
+                            ; $op QWORD [Rq(ro.reg) + ro.off]
+                        );
+                    }
+                    _ => unreachable!(format!("{}", SIR.ty(&opnd2.ty()))),
+                },
                 Location::Const { val, .. } => {
                     // It's safe to use TEMP_REG here, because opnd2 isn't in a register and if
                     // opnd1_reg was TEMP_REG then we've already moved it into RAX.
@@ -204,8 +291,8 @@ macro_rules! binop_mul_div {
             // Restore RAX, RDX
             dynasm!(self.asm
                 ; mov Rq(opnd1_reg), rax
-                ; pop rax
                 ; pop rdx
+                ; pop rax
             );
         }
     }
@@ -283,9 +370,199 @@ pub extern "sysv64" fn bh_push_vec(
 }
 
 /// Compile a TIR trace.
-pub fn compile_trace(tt: TirTrace) -> CompiledTrace {
-    CompiledTrace {
-        mc: TraceCompiler::compile(tt, false),
+pub fn compile_trace(tt: TirTrace) -> Result<CompiledTrace, CompileError> {
+    compile_trace_with_options(tt, &CompileOptions::default())
+}
+
+/// Compile a TIR trace using the given options.
+pub fn compile_trace_with_options(
+    tt: TirTrace,
+    opts: &CompileOptions,
+) -> Result<CompiledTrace, CompileError> {
+    if let Some(n) = opts.loop_iterations {
+        // The loop counter is compared against an `i32` immediate.
+        if n == 0 || i32::try_from(n).is_err() {
+            return Err(CompileError::InvalidLoopIterations(n));
+        }
+    }
+    if *PRINT_IR {
+        eprintln!("--- Begin TIR trace ---\n{}--- End TIR trace ---", tt);
+    }
+    // The trace is consumed by the compiler, so if we might need to dump it, render it up-front.
+    let dump = dump_trace_path().map(|path| (path, tt.to_string()));
+    let compile = move || {
+        if cfg!(debug_assertions) || *VERIFY_IR {
+            if let Err(errs) = tt.verify() {
+                let errs = errs.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                panic!("Invalid TIR trace:\n{}\n{}", errs.join("\n"), tt);
+            }
+        }
+        TraceCompiler::compile(tt, opts, false)
+    };
+    match dump {
+        Some((path, trace)) => {
+            let _pending = PendingDump::new(path, trace);
+            compile()
+        }
+        None => compile(),
+    }
+}
+
+/// If `YKD_DUMP_TRACE_ON_ABORT` is set, returns the path that a trace should be written to if
+/// compiling it panics. A value of `1` means `/tmp/yk_trace_<pid>.txt`, any other value is taken
+/// to be a path.
+fn dump_trace_path() -> Option<PathBuf> {
+    match env::var_os("YKD_DUMP_TRACE_ON_ABORT") {
+        Some(v) if v.is_empty() => None,
+        Some(v) if v == "1" => Some(PathBuf::from(format!(
+            "/tmp/yk_trace_{}.txt",
+            process::id()
+        ))),
+        Some(v) => Some(PathBuf::from(v)),
+        None => None,
+    }
+}
+
+thread_local! {
+    /// The file to write the trace this thread is compiling to, and the rendered trace, if the
+    /// trace should be dumped should compilation panic. See `install_trace_dump_hook`.
+    static PENDING_DUMP: RefCell<Option<(PathBuf, String)>> = RefCell::new(None);
+}
+
+/// Registers a trace as this thread's pending dump for as long as it is alive.
+struct PendingDump;
+
+impl PendingDump {
+    fn new(path: PathBuf, trace: String) -> Self {
+        PENDING_DUMP.with(|d| *d.borrow_mut() = Some((path, trace)));
+        PendingDump
+    }
+}
+
+impl Drop for PendingDump {
+    fn drop(&mut self) {
+        let _ = PENDING_DUMP.try_with(|d| d.borrow_mut().take());
+    }
+}
+
+/// Install a panic hook which, if a thread panics while compiling a trace with
+/// `YKD_DUMP_TRACE_ON_ABORT` set, writes the trace, followed by the panic message, to the file
+/// named by the variable. A panic hook runs before the process aborts, so this works even though
+/// we build with `panic = "abort"`. Whichever hook was previously installed is called afterwards,
+/// so that the host application's hook, if any, still runs. Only the first call has any effect.
+pub fn install_trace_dump_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let prev = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            write_pending_dump(&info.to_string());
+            prev(info);
+        }));
+    });
+}
+
+/// If this thread has a pending trace dump, append `s` to it.
+fn append_to_pending_dump(s: &str) {
+    let _ = PENDING_DUMP.try_with(|d| {
+        if let Some((_, trace)) = &mut *d.borrow_mut() {
+            trace.push_str(s);
+        }
+    });
+}
+
+/// If this thread has a pending trace dump, write it, followed by `msg`.
+fn write_pending_dump(msg: &str) {
+    let pending = PENDING_DUMP
+        .try_with(|d| d.try_borrow_mut().ok().and_then(|mut d| d.take()))
+        .ok()
+        .flatten();
+    if let Some((path, trace)) = pending {
+        let contents = format!("{}\n--- Panic message ---\n{}\n", trace, msg);
+        match fs::write(&path, contents) {
+            Ok(()) => eprintln!(
+                "Trace compilation failed: TIR written to {}",
+                path.display()
+            ),
+            Err(err) => eprintln!("Failed to write TIR to {}: {}", path.display(), err),
+        }
+    }
+}
+
+/// Returns true if the environment variable `name` is set to `1`.
+fn env_flag(name: &str) -> bool {
+    env::var(name).map(|v| v == "1").unwrap_or(false)
+}
+
+/// The number of bytes overwritten by `patch_jump`.
+pub(crate) const PATCH_JUMP_SIZE: usize = 13;
+
+/// Overwrite the code at `addr` with a jump to `target`. A 5-byte relative jump is used if
+/// `target` is close enough to `addr`, otherwise a 13-byte jump via `TEMP_REG`. The code at
+/// `addr` is assumed not to be running while it is patched.
+pub(crate) unsafe fn patch_jump(addr: *mut u8, target: *const u8) -> io::Result<()> {
+    let mut code = Vec::with_capacity(PATCH_JUMP_SIZE);
+    let rel = (target as i64).wrapping_sub(addr as i64 + 5);
+    match i32::try_from(rel) {
+        Ok(rel) => {
+            // jmp rel32
+            code.push(0xe9);
+            code.extend_from_slice(&rel.to_le_bytes());
+        }
+        Err(_) => {
+            // mov r11, imm64; jmp r11
+            debug_assert_eq!(*TEMP_REG, R11.code());
+            code.extend_from_slice(&[0x49, 0xbb]);
+            code.extend_from_slice(&(target as u64).to_le_bytes());
+            code.extend_from_slice(&[0x41, 0xff, 0xe3]);
+        }
+    }
+
+    // Make the pages containing the patched code writeable while we patch it.
+    let page_size = usize::try_from(libc::sysconf(libc::_SC_PAGESIZE)).unwrap();
+    let start = addr as usize - addr as usize % page_size;
+    let len = addr as usize + code.len() - start;
+    let prot = |flags| {
+        if libc::mprotect(start as *mut libc::c_void, len, flags) == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    };
+    prot(libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC)?;
+    std::ptr::copy_nonoverlapping(code.as_ptr(), addr, code.len());
+    prot(libc::PROT_READ | libc::PROT_EXEC)
+}
+
+/// Disassemble `code` with the help of `rasm2`, returning one instruction per line.
+pub(crate) fn disassemble_to_string(code: &[u8]) -> Result<String, Box<dyn Error>> {
+    let res = Command::new("rasm2")
+        .arg("-d")
+        .arg("-b 64") // x86_64.
+        .arg(hex::encode(code))
+        .output()?;
+    if !res.status.success() {
+        return Err(format!("rasm2 failed: {}", String::from_utf8_lossy(&res.stderr)).into());
+    }
+    Ok(String::from_utf8(res.stdout)?)
+}
+
+/// Print the disassembly of `code` to stderr with the help of `rasm2`. If `rasm2` can't be run,
+/// the raw bytes are printed instead.
+fn disassemble(code: &[u8]) {
+    if code.is_empty() {
+        eprintln!("  <empty buffer>");
+    } else {
+        match disassemble_to_string(code) {
+            Ok(asm) => {
+                for line in asm.lines() {
+                    eprintln!("  {}", line);
+                }
+            }
+            Err(_) => {
+                eprintln!("  Failed to invoke rasm2. Raw bytes follow...");
+                eprintln!("  {}", hex::encode(code));
+            }
+        }
     }
 }
 
@@ -299,6 +576,9 @@ pub struct TraceCompiler {
     variable_location_map: HashMap<Local, Location>,
     /// Local decls of the TIR trace.
     pub local_decls: HashMap<Local, LocalDecl>,
+    /// Maps trace locals to the index of the TIR op at which they die. Locals which are absent
+    /// are assumed to live until the end of the trace.
+    pub last_uses: HashMap<Local, usize>,
     /// Stack builder for allocating objects on the stack.
     stack_builder: StackBuilder,
     /// Stores the memory addresses of local functions.
@@ -312,6 +592,7 @@ impl TraceCompiler {
             register_content_map: REG_POOL.iter().map(|r| (*r, RegAlloc::Free)).collect(),
             variable_location_map: HashMap::new(),
             local_decls,
+            last_uses: HashMap::new(),
             stack_builder: StackBuilder::default(),
             addr_map,
         };
@@ -337,6 +618,8 @@ impl TraceCompiler {
         match &ty.kind {
             TyKind::UnsignedInt(ui) => !matches!(ui, UnsignedIntTy::U128),
             TyKind::SignedInt(si) => !matches!(si, SignedIntTy::I128),
+            // FIXME: floats could live in XMM registers, but we don't allocate those yet.
+            TyKind::Float(_) => false,
             TyKind::Array { .. } => false,
             TyKind::Slice(_) => false,
             TyKind::Ref(_) | TyKind::Bool | TyKind::Char => true,
@@ -386,7 +669,9 @@ impl TraceCompiler {
                 ret
             } else {
                 let ty = SIR.ty(&decl.ty);
-                let loc = self.stack_builder.alloc(ty.size(), ty.align());
+                let loc = self
+                    .stack_builder
+                    .alloc_named(l.to_string(), ty.size(), ty.align());
                 self.variable_location_map.insert(l, loc.clone());
                 loc
             }
@@ -406,17 +691,56 @@ impl TraceCompiler {
     fn spill_local_to_stack(&mut self, local: &Local) -> Location {
         let tyid = self.local_decls[&local].ty;
         let ty = SIR.ty(&tyid);
-        self.stack_builder.alloc(ty.size(), ty.align())
+        self.stack_builder
+            .alloc_named(local.to_string(), ty.size(), ty.align())
     }
 
     /// Assign a `Location` to a `Local` turning live. If possible, find it a register for it to
     /// live in, or failing that, allocate space on the stack.
-    fn local_live(&mut self, local: &Local) {
+    pub fn local_live(&mut self, local: &Local) {
         debug_assert!(self.variable_location_map.get(local).is_none());
+        if *local != INTERP_STEP_ARG
+            && Self::can_live_in_register(&self.local_decls[local])
+            && self.get_free_register().is_none()
+        {
+            // All registers are occupied. Rather than always spilling the newcomer, we spill
+            // whichever competing local dies last, as that keeps a register busy for longest.
+            if let Some(reg) = self.evict_register(local) {
+                self.register_content_map
+                    .insert(reg, RegAlloc::Local(*local));
+                self.variable_location_map
+                    .insert(*local, Location::Reg(reg));
+                return;
+            }
+        }
         // Assign a Location to this Local.
         self.local_to_location(*local);
     }
 
+    /// Find the register allocated local that dies last. If it dies after `local`, move it to the
+    /// stack and return the register it occupied. Otherwise return `None`.
+    fn evict_register(&mut self, local: &Local) -> Option<u8> {
+        let last_use = |l: &Local| self.last_uses.get(l).copied().unwrap_or(usize::MAX);
+        let (reg, victim) = self
+            .register_content_map
+            .iter()
+            .filter_map(|(reg, ra)| match ra {
+                RegAlloc::Local(l) => Some((*reg, *l)),
+                RegAlloc::Free => None,
+            })
+            .max_by_key(|(_, l)| last_use(l))?;
+        if last_use(&victim) <= last_use(local) {
+            return None;
+        }
+
+        let loc = self.spill_local_to_stack(&victim);
+        let size = SIR.ty(&self.local_decls[&victim].ty).size();
+        self.store_raw(&loc, &Location::Reg(reg), size);
+        self.variable_location_map.insert(victim, loc);
+        self.register_content_map.insert(reg, RegAlloc::Free);
+        Some(reg)
+    }
+
     /// Notifies the register allocator that a local has died and that its storage may be freed.
     pub fn local_dead(&mut self, local: &Local) -> Result<(), CompileError> {
         match self
@@ -499,18 +823,64 @@ impl TraceCompiler {
         }
     }
 
+    /// Load the call argument `arg` into the register `arg_reg`. `saved_regs` are the caller-save
+    /// registers which were pushed to the stack (in order) before the call, and `extra_qwords` is
+    /// the number of quad-words which have been pushed on top of them since.
+    fn load_call_arg(
+        &mut self,
+        arg_reg: u8,
+        arg: &IRPlace,
+        saved_regs: &[u8],
+        extra_qwords: usize,
+    ) {
+        match self.iplace_to_location(arg) {
+            Location::Reg(reg) => {
+                // Find the index of the register in the caller-save area. The first register
+                // pushed is at the highest stack offset (from the stack pointer), hence reversing
+                // the order of `saved_regs`.
+                let saved_idx = saved_regs.iter().rev().position(|&r| r == reg);
+                if let Some(idx) = saved_idx {
+                    // We saved this register to the stack during caller-save. Since there is
+                    // overlap between caller-save registers and argument registers, we may have
+                    // overwritten the value in the meantime. So we should load the value back
+                    // from the stack.
+                    let off = i32::try_from((idx + extra_qwords) * QWORD_REG_SIZE).unwrap();
+                    dynasm!(self.asm
+                        ; mov Rq(arg_reg), [rsp + off]
+                    );
+                } else {
+                    // We didn't save this register, so it remains intact.
+                    dynasm!(self.asm
+                        ; mov Rq(arg_reg), Rq(reg)
+                    );
+                }
+            }
+            Location::Mem(ro) => dynasm!(self.asm
+                ; mov Rq(arg_reg), [Rq(ro.reg) + ro.off]
+            ),
+            Location::Indirect { .. } => todo!(),
+            Location::Const { val, .. } => {
+                // FIXME assumes constant fits in a register.
+                dynasm!(self.asm
+                    ; mov Rq(arg_reg), QWORD val.i64_cast()
+                );
+            }
+        }
+    }
+
     /// Compile a call to a native symbol using the Sys-V ABI. This is used for occasions where you
     /// don't want to, or cannot, inline the callee (e.g. it's a foreign function).
     ///
     /// For now we do something very simple. There are limitations (FIXME):
     ///
-    ///  - We assume there are no more than 6 arguments (spilling is not yet implemented).
-    ///
     ///  - We push all of the callee save registers on the stack, and local variable arguments are
     ///    then loaded back from the stack into the correct ABI-specified registers. We can
     ///    optimise this later by only loading an argument from the stack if it cannot be loaded
     ///    from its original register location (because another argument overwrote it already).
     ///
+    ///  - We assume each argument fits in a quad-word. Arguments beyond the sixth are passed on the
+    ///    stack, one quad-word each.
+    ///
     ///  - We assume the return value fits in rax. 128-bit return values are not yet supported.
     ///
     ///  - We don't support varags calls.
@@ -526,10 +896,6 @@ impl TraceCompiler {
             todo!("unknown call target");
         };
 
-        if args.len() > 6 {
-            todo!("call with spilled args");
-        }
-
         // Save Sys-V caller save registers to the stack, but skip the one (if there is one) that
         // will store the return value. It's safe to assume the caller expects this to be
         // clobbered.
@@ -545,59 +911,35 @@ impl TraceCompiler {
         }
         self.save_regs(&*save_regs);
 
-        // Helper function to find the index of a caller-save register previously pushed to the
-        // stack. The first register pushed is at the highest stack offset (from the stack
-        // pointer), hence reversing the order of `save_regs`. Returns `None` if `reg` was never
-        // saved during caller-save.
-        let saved_stack_index = |reg: u8| -> Option<i32> {
-            save_regs
-                .iter()
-                .rev()
-                .position(|&r| r == reg)
-                .map(|i| i32::try_from(i).unwrap())
-        };
+        // Sys-V ABI dictates the first 6 arguments are passed in these registers. The remaining
+        // arguments are passed on the stack.
+        let arg_regs = [RDI, RSI, RDX, RCX, R8, R9];
+        let (reg_args, stack_args) = args.split_at(args.len().min(arg_regs.len()));
 
-        // Sys-V ABI dictates the first 6 arguments are passed in these registers.
-        // The order is reversed so they pop() in the right order.
-        let mut arg_regs = vec![R9, R8, RCX, RDX, RSI, RDI]
-            .iter()
-            .map(|r| r.code())
-            .collect::<Vec<u8>>();
+        // The stack pointer is 16-byte aligned in trace code (see `ret()`), and must also be at
+        // the point of the call. Each saved register and stack argument takes a quad-word, so we
+        // may need a quad-word of padding.
+        let pad_qwords = (save_regs.len() + stack_args.len()) % 2;
+        if pad_qwords != 0 {
+            dynasm!(self.asm
+                ; sub rsp, i32::try_from(QWORD_REG_SIZE).unwrap()
+            );
+        }
 
-        for arg in args {
-            // In which register will this argument be passed?
-            // `unwrap()` must succeed, as we checked there are no more than 6 args above.
-            let arg_reg = arg_regs.pop().unwrap();
+        // Push the stack arguments in right-to-left order, so that the first of them ends up at
+        // the top of the stack.
+        for (i, arg) in stack_args.iter().enumerate().rev() {
+            let pushed = pad_qwords + stack_args.len() - 1 - i;
+            self.load_call_arg(*TEMP_REG, arg, &save_regs, pushed);
+            dynasm!(self.asm
+                ; push Rq(*TEMP_REG)
+            );
+        }
+        let extra_qwords = pad_qwords + stack_args.len();
 
-            // Now load the argument into the correct argument register.
-            match self.iplace_to_location(arg) {
-                Location::Reg(reg) => {
-                    if let Some(idx) = saved_stack_index(reg) {
-                        // We saved this register to the stack during caller-save. Since there is
-                        // overlap between caller-save registers and argument registers, we may
-                        // have overwritten the value in the meantime. So we should load the value
-                        // back from the stack.
-                        dynasm!(self.asm
-                            ; mov Rq(arg_reg), [rsp + idx * i32::try_from(QWORD_REG_SIZE).unwrap()]
-                        );
-                    } else {
-                        // We didn't save this register, so it remains intact.
-                        dynasm!(self.asm
-                            ; mov Rq(arg_reg), Rq(reg)
-                        );
-                    }
-                }
-                Location::Mem(ro) => dynasm!(self.asm
-                    ; mov Rq(arg_reg), [Rq(ro.reg) + ro.off]
-                ),
-                Location::Indirect { .. } => todo!(),
-                Location::Const { val, .. } => {
-                    // FIXME assumes constant fits in a register.
-                    dynasm!(self.asm
-                        ; mov Rq(arg_reg), QWORD val.i64_cast()
-                    );
-                }
-            }
+        // Now load the register arguments into the correct argument registers.
+        for (arg, arg_reg) in reg_args.iter().zip(arg_regs.iter()) {
+            self.load_call_arg(arg_reg.code(), arg, &save_regs, extra_qwords);
         }
 
         let sym_addr = if let Some(addr) = self.addr_map.get(sym) {
@@ -617,6 +959,13 @@ impl TraceCompiler {
             ; mov Rq(*TEMP_REG), rax
         );
 
+        // Pop the stack arguments and padding.
+        if extra_qwords != 0 {
+            dynasm!(self.asm
+                ; add rsp, i32::try_from(extra_qwords * QWORD_REG_SIZE).unwrap()
+            );
+        }
+
         // Restore caller-save registers.
         self.restore_regs(&save_regs);
 
@@ -644,10 +993,21 @@ impl TraceCompiler {
         opnd2: &IRPlace,
         checked: bool,
     ) {
+        if let BinOp::Shl | BinOp::Shr = op {
+            // The shift amount may be of a different type to the value being shifted.
+            return self.c_shift(dest, op, opnd1, opnd2, checked);
+        }
+
         let opnd1_ty = SIR.ty(&opnd1.ty());
         debug_assert!(opnd1_ty == SIR.ty(&opnd2.ty()));
 
-        // For now this whole function assumes we are operating on integers.
+        if opnd1_ty.is_float() {
+            // Checked operations only exist for integers.
+            debug_assert!(!checked);
+            return self.c_binop_float(dest, op, opnd1, opnd2);
+        }
+
+        // From here on we assume we are operating on integers.
         if !opnd1_ty.is_int() {
             todo!("binops for non-integers");
         }
@@ -659,6 +1019,14 @@ impl TraceCompiler {
             _ => {}
         }
 
+        if opnd1_ty.size() == 16 {
+            // 128-bit integers don't fit in a register and are dealt with separately.
+            match op {
+                BinOp::Add => return self.checked_add_128(dest, opnd1, opnd2, checked),
+                _ => todo!("{} on 128-bit integers", op),
+            }
+        }
+
         // We do this in three stages.
         // 1) Copy the first operand into the temp register.
         self.load_reg_iplace(*TEMP_REG, opnd1);
@@ -669,19 +1037,19 @@ impl TraceCompiler {
             BinOp::Sub => self.c_binop_sub(*TEMP_REG, opnd2),
             BinOp::Mul => {
                 if opnd1_ty.is_signed_int() {
-                    todo!("signed mul"); // use IMUL
+                    self.c_binop_imul(*TEMP_REG, opnd2);
                 } else {
                     self.c_binop_mul(*TEMP_REG, opnd2);
                 }
             }
             BinOp::Div => {
                 if opnd1_ty.is_signed_int() {
-                    todo!("signed div"); // use IDIV
+                    self.c_binop_idiv(*TEMP_REG, opnd2);
                 } else {
                     self.c_binop_div(*TEMP_REG, opnd2);
                 }
             }
-            _ => todo!(),
+            _ => todo!("{}", op),
         }
 
         // 3) Move the result to where it is supposed to live.
@@ -690,35 +1058,249 @@ impl TraceCompiler {
         if checked {
             // If it is a checked operation, then we have to build a (value, overflow-flag) tuple.
             // Let's do the flag first, so as to read EFLAGS closest to where they are set.
-            let dest_ro = dest_loc.unwrap_mem();
+            self.store_overflow_flag(dest, &dest_loc, opnd1_ty.is_signed_int());
+        }
+        self.store_raw(&dest_loc, &*TEMP_LOC, size);
+    }
+
+    /// Set the overflow flag of the (value, overflow-flag) tuple `dest` (which lives at
+    /// `dest_loc`) from EFLAGS. Must be called directly after the arithmetic instruction which
+    /// may have overflowed.
+    fn store_overflow_flag(&mut self, dest: &IRPlace, dest_loc: &Location, signed: bool) {
+        let dest_ro = dest_loc.unwrap_mem();
+        let sir_ty = SIR.ty(&dest.ty());
+        let tty = sir_ty.unwrap_tuple();
+        let flag_off = i32::try_from(tty.fields.offsets[1]).unwrap();
+
+        if signed {
+            dynasm!(self.asm
+                ; jo >overflow
+            );
+        } else {
+            dynasm!(self.asm
+                ; jc >overflow
+            );
+        }
+        dynasm!(self.asm
+            ; mov BYTE [Rq(dest_ro.reg) + dest_ro.off + flag_off], 0
+            ; jmp >done
+            ; overflow:
+            ; mov BYTE [Rq(dest_ro.reg) + dest_ro.off + flag_off], 1
+            ; done:
+        );
+    }
+
+    /// Compile an addition of two 128-bit integers. Since these always live on the stack, the sum
+    /// is computed in place in the destination: the low halves are added with `add` and the high
+    /// halves with `adc`, which takes the carry out of the low halves into account. If `checked`
+    /// is true, `dest` is a (value, overflow-flag) tuple and the flag is set too.
+    fn checked_add_128(&mut self, dest: &IRPlace, opnd1: &IRPlace, opnd2: &IRPlace, checked: bool) {
+        let dest_loc = self.iplace_to_location(dest);
+        let val_off = if checked {
+            let sir_ty = SIR.ty(&dest.ty());
+            i32::try_from(sir_ty.unwrap_tuple().fields.offsets[0]).unwrap()
+        } else {
+            0
+        };
+        let val_loc = dest_loc.clone().offset(val_off);
+        let mut src1_loc = self.iplace_to_location(opnd1);
+        let mut src2_loc = self.iplace_to_location(opnd2);
+        if val_loc == src2_loc {
+            // We are about to overwrite the destination with the first operand, so if the second
+            // operand lives there too, swap the operands so that we don't lose it.
+            mem::swap(&mut src1_loc, &mut src2_loc);
+        }
+        self.store_raw(&val_loc, &src1_loc, 16);
+
+        let val_ro = val_loc.unwrap_mem();
+        let src2_ro = match &src2_loc {
+            Location::Mem(ro) => Some(ro.clone()),
+            Location::Indirect {
+                ptr: IndirectLoc::Reg(reg),
+                off,
+            } => Some(RegAndOffset {
+                reg: *reg,
+                off: *off,
+            }),
+            _ => None,
+        };
+        if let Some(ro) = src2_ro {
+            debug_assert!(ro.reg != *TEMP_REG);
+            dynasm!(self.asm
+                ; mov Rq(*TEMP_REG), QWORD [Rq(ro.reg) + ro.off]
+                ; add QWORD [Rq(val_ro.reg) + val_ro.off], Rq(*TEMP_REG)
+                ; mov Rq(*TEMP_REG), QWORD [Rq(ro.reg) + ro.off + 8]
+                ; adc QWORD [Rq(val_ro.reg) + val_ro.off + 8], Rq(*TEMP_REG)
+            );
+        } else if let Location::Const { val, .. } = &src2_loc {
+            // `mov` doesn't affect EFLAGS, so the carry survives loading the high half.
+            let v = val.u128_cast();
+            dynasm!(self.asm
+                ; mov Rq(*TEMP_REG), QWORD v as i64
+                ; add QWORD [Rq(val_ro.reg) + val_ro.off], Rq(*TEMP_REG)
+                ; mov Rq(*TEMP_REG), QWORD (v >> 64) as i64
+                ; adc QWORD [Rq(val_ro.reg) + val_ro.off + 8], Rq(*TEMP_REG)
+            );
+        } else {
+            todo!("128-bit add of {:?}", src2_loc);
+        }
+
+        if checked {
+            let signed = SIR.ty(&opnd1.ty()).is_signed_int();
+            self.store_overflow_flag(dest, &dest_loc, signed);
+        }
+    }
+
+    /// Compile a left or right shift of `opnd1` by `opnd2` bits. Right shifts of signed integers
+    /// are arithmetic. As in Rust, the shift amount is masked to the bit width of `opnd1`, and a
+    /// checked shift overflows if the (unmasked) amount is not less than the bit width.
+    fn c_shift(
+        &mut self,
+        dest: &IRPlace,
+        op: BinOp,
+        opnd1: &IRPlace,
+        opnd2: &IRPlace,
+        checked: bool,
+    ) {
+        let opnd1_ty = SIR.ty(&opnd1.ty());
+        if !opnd1_ty.is_int() || opnd1_ty.size() > 8 {
+            todo!("{} on {}", op, opnd1_ty);
+        }
+        let size = opnd1_ty.size();
+        let bits = i8::try_from(size * 8).unwrap();
+        let signed = opnd1_ty.is_signed_int();
+        let dest_loc = self.iplace_to_location(dest);
+        let flag_ro = if checked {
             let sir_ty = SIR.ty(&dest.ty());
-            let tty = sir_ty.unwrap_tuple();
-            let flag_off = i32::try_from(tty.fields.offsets[1]).unwrap();
+            let flag_off = i32::try_from(sir_ty.unwrap_tuple().fields.offsets[1]).unwrap();
+            Some(dest_loc.clone().offset(flag_off).unwrap_mem().clone())
+        } else {
+            None
+        };
 
-            if opnd1_ty.is_signed_int() {
+        self.load_reg_iplace(*TEMP_REG, opnd1);
+        match self.iplace_to_location(opnd2) {
+            Location::Const { val, .. } => {
+                if let Some(ro) = flag_ro {
+                    let overflow = val.u128_cast() >= u128::try_from(bits).unwrap();
+                    dynasm!(self.asm
+                        ; mov BYTE [Rq(ro.reg) + ro.off], overflow as i8
+                    );
+                }
+                let amount = (val.u128_cast() & u128::try_from(bits - 1).unwrap()) as i8;
+                match (op, signed, size) {
+                    (BinOp::Shl, _, 1) => dynasm!(self.asm; shl Rb(*TEMP_REG), amount),
+                    (BinOp::Shl, _, 2) => dynasm!(self.asm; shl Rw(*TEMP_REG), amount),
+                    (BinOp::Shl, _, 4) => dynasm!(self.asm; shl Rd(*TEMP_REG), amount),
+                    (BinOp::Shl, _, 8) => dynasm!(self.asm; shl Rq(*TEMP_REG), amount),
+                    (BinOp::Shr, false, 1) => dynasm!(self.asm; shr Rb(*TEMP_REG), amount),
+                    (BinOp::Shr, false, 2) => dynasm!(self.asm; shr Rw(*TEMP_REG), amount),
+                    (BinOp::Shr, false, 4) => dynasm!(self.asm; shr Rd(*TEMP_REG), amount),
+                    (BinOp::Shr, false, 8) => dynasm!(self.asm; shr Rq(*TEMP_REG), amount),
+                    (BinOp::Shr, true, 1) => dynasm!(self.asm; sar Rb(*TEMP_REG), amount),
+                    (BinOp::Shr, true, 2) => dynasm!(self.asm; sar Rw(*TEMP_REG), amount),
+                    (BinOp::Shr, true, 4) => dynasm!(self.asm; sar Rd(*TEMP_REG), amount),
+                    (BinOp::Shr, true, 8) => dynasm!(self.asm; sar Rq(*TEMP_REG), amount),
+                    _ => unreachable!(),
+                }
+            }
+            amount_loc => {
+                // Variable shift amounts must be in CL, so save RCX and (zero-extend) the amount
+                // into it.
+                let amount_size = SIR.ty(&opnd2.ty()).size();
+                if amount_size > 8 {
+                    todo!("{} by {}", op, SIR.ty(&opnd2.ty()));
+                }
                 dynasm!(self.asm
-                    ; jo >overflow
+                    ; push rcx
                 );
-            } else {
+                self.store_raw(&Location::Reg(RCX.code()), &amount_loc, amount_size);
+                match amount_size {
+                    1 => dynasm!(self.asm; movzx ecx, cl),
+                    2 => dynasm!(self.asm; movzx ecx, cx),
+                    4 => dynasm!(self.asm; mov ecx, ecx),
+                    _ => {}
+                }
+                if let Some(ro) = flag_ro {
+                    dynasm!(self.asm
+                        ; cmp rcx, i32::from(bits)
+                        ; setae BYTE [Rq(ro.reg) + ro.off]
+                    );
+                }
+                dynasm!(self.asm
+                    ; and ecx, i32::from(bits - 1)
+                );
+                match (op, signed, size) {
+                    (BinOp::Shl, _, 1) => dynasm!(self.asm; shl Rb(*TEMP_REG), cl),
+                    (BinOp::Shl, _, 2) => dynasm!(self.asm; shl Rw(*TEMP_REG), cl),
+                    (BinOp::Shl, _, 4) => dynasm!(self.asm; shl Rd(*TEMP_REG), cl),
+                    (BinOp::Shl, _, 8) => dynasm!(self.asm; shl Rq(*TEMP_REG), cl),
+                    (BinOp::Shr, false, 1) => dynasm!(self.asm; shr Rb(*TEMP_REG), cl),
+                    (BinOp::Shr, false, 2) => dynasm!(self.asm; shr Rw(*TEMP_REG), cl),
+                    (BinOp::Shr, false, 4) => dynasm!(self.asm; shr Rd(*TEMP_REG), cl),
+                    (BinOp::Shr, false, 8) => dynasm!(self.asm; shr Rq(*TEMP_REG), cl),
+                    (BinOp::Shr, true, 1) => dynasm!(self.asm; sar Rb(*TEMP_REG), cl),
+                    (BinOp::Shr, true, 2) => dynasm!(self.asm; sar Rw(*TEMP_REG), cl),
+                    (BinOp::Shr, true, 4) => dynasm!(self.asm; sar Rd(*TEMP_REG), cl),
+                    (BinOp::Shr, true, 8) => dynasm!(self.asm; sar Rq(*TEMP_REG), cl),
+                    _ => unreachable!(),
+                }
                 dynasm!(self.asm
-                    ; jc >overflow
+                    ; pop rcx
                 );
             }
-            dynasm!(self.asm
-                ; mov BYTE [Rq(dest_ro.reg) + dest_ro.off + flag_off], 0
-                ; jmp >done
-                ; overflow:
-                ; mov BYTE [Rq(dest_ro.reg) + dest_ro.off + flag_off], 1
-                ; done:
-            );
         }
         self.store_raw(&dest_loc, &*TEMP_LOC, size);
     }
 
+    /// Compile a binary operation on floating point operands using SSE2. Since floats are never
+    /// register allocated, we shuttle the operands through `TEMP_REG` into the scratch registers
+    /// XMM0 and XMM1 and the result back out again.
+    fn c_binop_float(&mut self, dest: &IRPlace, op: BinOp, opnd1: &IRPlace, opnd2: &IRPlace) {
+        let ty = SIR.ty(&opnd1.ty());
+        let fty = match &ty.kind {
+            TyKind::Float(fty) => fty.clone(),
+            _ => unreachable!(),
+        };
+        debug_assert_eq!(ty.size(), fty.size());
+
+        self.load_reg_iplace(*TEMP_REG, opnd1);
+        match fty {
+            FloatTy::F32 => dynasm!(self.asm; movd xmm0, Rd(*TEMP_REG)),
+            FloatTy::F64 => dynasm!(self.asm; movq xmm0, Rq(*TEMP_REG)),
+        }
+        self.load_reg_iplace(*TEMP_REG, opnd2);
+        match fty {
+            FloatTy::F32 => dynasm!(self.asm; movd xmm1, Rd(*TEMP_REG)),
+            FloatTy::F64 => dynasm!(self.asm; movq xmm1, Rq(*TEMP_REG)),
+        }
+
+        match (op, &fty) {
+            (BinOp::Add, FloatTy::F32) => dynasm!(self.asm; addss xmm0, xmm1),
+            (BinOp::Add, FloatTy::F64) => dynasm!(self.asm; addsd xmm0, xmm1),
+            (BinOp::Sub, FloatTy::F32) => dynasm!(self.asm; subss xmm0, xmm1),
+            (BinOp::Sub, FloatTy::F64) => dynasm!(self.asm; subsd xmm0, xmm1),
+            (BinOp::Mul, FloatTy::F32) => dynasm!(self.asm; mulss xmm0, xmm1),
+            (BinOp::Mul, FloatTy::F64) => dynasm!(self.asm; mulsd xmm0, xmm1),
+            (BinOp::Div, FloatTy::F32) => dynasm!(self.asm; divss xmm0, xmm1),
+            (BinOp::Div, FloatTy::F64) => dynasm!(self.asm; divsd xmm0, xmm1),
+            _ => todo!("{} on {}", op, ty),
+        }
+
+        match fty {
+            FloatTy::F32 => dynasm!(self.asm; movd Rd(*TEMP_REG), xmm0),
+            FloatTy::F64 => dynasm!(self.asm; movq Rq(*TEMP_REG), xmm0),
+        }
+        let dest_loc = self.iplace_to_location(dest);
+        self.store_raw(&dest_loc, &*TEMP_LOC, ty.size());
+    }
+
     binop_add_sub!(c_binop_add, add);
     binop_add_sub!(c_binop_sub, sub);
-    binop_mul_div!(c_binop_mul, mul);
-    binop_mul_div!(c_binop_div, div);
+    binop_mul_div!(c_binop_mul, mul, false);
+    binop_mul_div!(c_binop_div, div, false);
+    binop_mul_div!(c_binop_imul, imul, true);
+    binop_mul_div!(c_binop_idiv, idiv, true);
 
     fn c_condition(&mut self, dest: &IRPlace, binop: &BinOp, op1: &IRPlace, op2: &IRPlace) {
         let src1 = self.iplace_to_location(op1);
@@ -778,37 +1360,59 @@ impl TraceCompiler {
         dynasm!(self.asm
          ; mov Rq(*TEMP_REG), 1
         );
-        match binop {
-            BinOp::Eq => {
+        // Ordered comparisons must use the signed or unsigned condition codes depending on the
+        // type of the operands.
+        match (binop, ty.is_signed_int()) {
+            (BinOp::Eq, _) => {
                 dynasm!(self.asm
                     ; je >skip
                 );
             }
-            BinOp::Ne => {
+            (BinOp::Ne, _) => {
                 dynasm!(self.asm
                     ; jne >skip
                 );
             }
-            BinOp::Lt => {
+            (BinOp::Lt, true) => {
                 dynasm!(self.asm
                     ; jl >skip
                 );
             }
-            BinOp::Le => {
+            (BinOp::Lt, false) => {
+                dynasm!(self.asm
+                    ; jb >skip
+                );
+            }
+            (BinOp::Le, true) => {
                 dynasm!(self.asm
                     ; jle >skip
                 );
             }
-            BinOp::Gt => {
+            (BinOp::Le, false) => {
+                dynasm!(self.asm
+                    ; jbe >skip
+                );
+            }
+            (BinOp::Gt, true) => {
                 dynasm!(self.asm
                     ; jg >skip
                 );
             }
-            BinOp::Ge => {
+            (BinOp::Gt, false) => {
+                dynasm!(self.asm
+                    ; ja >skip
+                );
+            }
+            (BinOp::Ge, true) => {
                 dynasm!(self.asm
                     ; jge >skip
                 );
             }
+            (BinOp::Ge, false) => {
+                dynasm!(self.asm
+                    ; jae >skip
+                );
+            }
             _ => unreachable!(),
         }
         dynasm!(self.asm
@@ -897,7 +1501,7 @@ impl TraceCompiler {
             Statement::Call(target, args, dest) => self.c_call(target, args, dest)?,
             Statement::Cast(dest, src) => self.c_cast(dest, src),
             Statement::Nop | Statement::Debug(..) => {}
-            Statement::Unimplemented(s) => todo!("{:?}", s),
+            Statement::Unimplemented(s) => return Err(CompileError::Unimplemented(s.clone())),
         }
 
         Ok(())
@@ -942,17 +1546,194 @@ impl TraceCompiler {
     }
 
     fn c_cast(&mut self, dest: &IRPlace, src: &IRPlace) {
-        let src_loc = self.iplace_to_location(src);
         let ty = &*SIR.ty(&src.ty()); // Type of the source.
         let cty = SIR.ty(&dest.ty()); // Type of the cast.
-        match ty.kind {
-            TyKind::UnsignedInt(_) => self.c_cast_uint(src_loc, &ty, &cty),
-            _ => todo!(),
+        if ty.is_float() || cty.is_float() {
+            self.c_cast_float(src, &ty, &cty);
+        } else {
+            match ty.kind {
+                TyKind::UnsignedInt(_) => {
+                    let src_loc = self.iplace_to_location(src);
+                    self.c_cast_uint(src_loc, &ty, &cty)
+                }
+                _ => todo!(),
+            }
         }
         let dest_loc = self.iplace_to_location(dest);
         self.store_raw(&dest_loc, &*TEMP_LOC, SIR.ty(&dest.ty()).size());
     }
 
+    /// Casts between floats of different sizes, and between floats and integers, leaving the
+    /// result in `TEMP_REG`.
+    fn c_cast_float(&mut self, src: &IRPlace, ty: &Ty, cty: &Ty) {
+        for t in &[ty, cty] {
+            if let TyKind::Float(fty) = &t.kind {
+                debug_assert_eq!(t.size(), fty.size());
+            }
+        }
+        self.load_reg_iplace(*TEMP_REG, src);
+        match (&ty.kind, &cty.kind) {
+            (TyKind::Float(fty), TyKind::Float(cfty)) => match (fty, cfty) {
+                (FloatTy::F32, FloatTy::F64) => dynasm!(self.asm
+                    ; movd xmm0, Rd(*TEMP_REG)
+                    ; cvtss2sd xmm0, xmm0
+                    ; movq Rq(*TEMP_REG), xmm0
+                ),
+                (FloatTy::F64, FloatTy::F32) => dynasm!(self.asm
+                    ; movq xmm0, Rq(*TEMP_REG)
+                    ; cvtsd2ss xmm0, xmm0
+                    ; movd Rd(*TEMP_REG), xmm0
+                ),
+                _ => (),
+            },
+            (TyKind::Float(fty), _) if cty.is_int() && cty.size() <= QWORD_REG_SIZE as u64 => {
+                self.c_cast_float_to_int(fty, cty)
+            }
+            (_, TyKind::Float(cfty)) if ty.is_int() && ty.size() <= QWORD_REG_SIZE as u64 => {
+                self.c_cast_int_to_float(ty, cfty)
+            }
+            _ => todo!("cast from {} to {}", ty, cty),
+        }
+    }
+
+    /// Converts the float of type `fty` in `TEMP_REG` to the integer type `cty`, leaving the
+    /// result in `TEMP_REG`. Like Rust, NaN becomes 0 and out of range values saturate to the
+    /// integer type's minimum or maximum: on their own, the truncating conversion instructions
+    /// would produce the "integer indefinite" value instead.
+    fn c_cast_float_to_int(&mut self, fty: &FloatTy, cty: &Ty) {
+        let bits = cty.int_bits().unwrap();
+        // The bounds are powers of two and so exactly representable as floats. Anything at or
+        // below `lo`, or at or above `hi`, saturates.
+        let shift = 64 - bits;
+        let (min, max, lo, hi) = if cty.is_signed_int() {
+            let half = 2f64.powi(i32::try_from(bits).unwrap() - 1);
+            (i64::MIN >> shift, i64::MAX >> shift, -half, half)
+        } else {
+            let max = (u64::MAX >> shift) as i64;
+            (0, max, 0.0, 2f64.powi(i32::try_from(bits).unwrap()))
+        };
+
+        match fty {
+            FloatTy::F32 => dynasm!(self.asm
+                ; movd xmm0, Rd(*TEMP_REG)
+                ; xor Rd(*TEMP_REG), Rd(*TEMP_REG)
+                ; ucomiss xmm0, xmm0
+            ),
+            FloatTy::F64 => dynasm!(self.asm
+                ; movq xmm0, Rq(*TEMP_REG)
+                ; xor Rd(*TEMP_REG), Rd(*TEMP_REG)
+                ; ucomisd xmm0, xmm0
+            ),
+        }
+        // A NaN is unordered with itself.
+        dynasm!(self.asm; jp >done);
+        self.cmp_xmm0_const(fty, lo);
+        dynasm!(self.asm
+            ; mov Rq(*TEMP_REG), QWORD min
+            ; jbe >done
+        );
+        self.cmp_xmm0_const(fty, hi);
+        dynasm!(self.asm
+            ; mov Rq(*TEMP_REG), QWORD max
+            ; jae >done
+        );
+        if bits == 64 && !cty.is_signed_int() {
+            // Values of 2^63 or more don't fit in the signed conversion's result, so bring them
+            // into range first and put the top bit back afterwards.
+            self.cmp_xmm0_const(fty, 2f64.powi(63));
+            dynasm!(self.asm; jb >convert);
+            match fty {
+                FloatTy::F32 => dynasm!(self.asm
+                    ; subss xmm0, xmm1
+                    ; cvttss2si Rq(*TEMP_REG), xmm0
+                ),
+                FloatTy::F64 => dynasm!(self.asm
+                    ; subsd xmm0, xmm1
+                    ; cvttsd2si Rq(*TEMP_REG), xmm0
+                ),
+            }
+            dynasm!(self.asm
+                ; bts Rq(*TEMP_REG), 63
+                ; jmp >done
+                ; convert:
+            );
+        }
+        match fty {
+            FloatTy::F32 => dynasm!(self.asm; cvttss2si Rq(*TEMP_REG), xmm0),
+            FloatTy::F64 => dynasm!(self.asm; cvttsd2si Rq(*TEMP_REG), xmm0),
+        }
+        dynasm!(self.asm; done:);
+    }
+
+    /// Loads the constant `val` into XMM1 as a float of type `fty` and compares XMM0 with it,
+    /// clobbering `TEMP_REG`.
+    fn cmp_xmm0_const(&mut self, fty: &FloatTy, val: f64) {
+        match fty {
+            FloatTy::F32 => dynasm!(self.asm
+                ; mov Rd(*TEMP_REG), (val as f32).to_bits() as i32
+                ; movd xmm1, Rd(*TEMP_REG)
+                ; ucomiss xmm0, xmm1
+            ),
+            FloatTy::F64 => dynasm!(self.asm
+                ; mov Rq(*TEMP_REG), QWORD val.to_bits() as i64
+                ; movq xmm1, Rq(*TEMP_REG)
+                ; ucomisd xmm0, xmm1
+            ),
+        }
+    }
+
+    /// Converts the integer of type `ty` in `TEMP_REG` to a float of type `cfty`, leaving the
+    /// result in `TEMP_REG`.
+    fn c_cast_int_to_float(&mut self, ty: &Ty, cfty: &FloatTy) {
+        // The conversion reads all of the register, so extend the source value into it.
+        match (ty.size(), ty.is_signed_int()) {
+            (1, true) => dynasm!(self.asm; movsx Rq(*TEMP_REG), Rb(*TEMP_REG)),
+            (2, true) => dynasm!(self.asm; movsx Rq(*TEMP_REG), Rw(*TEMP_REG)),
+            (4, true) => dynasm!(self.asm; movsxd Rq(*TEMP_REG), Rd(*TEMP_REG)),
+            (1, false) => dynasm!(self.asm; movzx Rq(*TEMP_REG), Rb(*TEMP_REG)),
+            (2, false) => dynasm!(self.asm; movzx Rq(*TEMP_REG), Rw(*TEMP_REG)),
+            // Writing a 32-bit register zero-extends it into the 64-bit register.
+            (4, false) => dynasm!(self.asm; mov Rd(*TEMP_REG), Rd(*TEMP_REG)),
+            _ => (),
+        }
+        if ty.size() == 8 && !ty.is_signed_int() {
+            // The conversion is signed, so a u64 with its top bit set would come out negative.
+            // Instead, halve the value, keeping the low bit so that it still rounds correctly,
+            // convert that and then double the result.
+            dynasm!(self.asm
+                ; test Rq(*TEMP_REG), Rq(*TEMP_REG)
+                ; jns >convert
+                ; shr Rq(*TEMP_REG), 1
+                ; jnc >even
+                ; or Rq(*TEMP_REG), 1
+                ; even:
+            );
+            match cfty {
+                FloatTy::F32 => dynasm!(self.asm
+                    ; cvtsi2ss xmm0, Rq(*TEMP_REG)
+                    ; addss xmm0, xmm0
+                ),
+                FloatTy::F64 => dynasm!(self.asm
+                    ; cvtsi2sd xmm0, Rq(*TEMP_REG)
+                    ; addsd xmm0, xmm0
+                ),
+            }
+            dynasm!(self.asm
+                ; jmp >done
+                ; convert:
+            );
+        }
+        match cfty {
+            FloatTy::F32 => dynasm!(self.asm; cvtsi2ss xmm0, Rq(*TEMP_REG)),
+            FloatTy::F64 => dynasm!(self.asm; cvtsi2sd xmm0, Rq(*TEMP_REG)),
+        }
+        dynasm!(self.asm; done:);
+        match cfty {
+            FloatTy::F32 => dynasm!(self.asm; movd Rd(*TEMP_REG), xmm0),
+            FloatTy::F64 => dynasm!(self.asm; movq Rq(*TEMP_REG), xmm0),
+        }
+    }
+
     fn c_cast_uint(&mut self, src: Location, ty: &Ty, cty: &Ty) {
         match src {
             Location::Reg(reg) => {
@@ -1023,9 +1804,15 @@ impl TraceCompiler {
                     _ => todo!("{}", ty.size()),
                 }
             }
-            Location::Mem(_) => todo!(),
-            Location::Indirect { .. } => todo!(),
-            Location::Const { .. } => todo!(),
+            Location::Mem(_) | Location::Indirect { .. } | Location::Const { .. } => {
+                if ty.size() > 8 {
+                    todo!("{}", ty.size());
+                }
+                // Loading a value narrower than a register leaves the register's upper bits
+                // undefined, so we load into `TEMP_REG` and then zero-extend it in place.
+                self.store_raw(&*TEMP_LOC, &src, ty.size());
+                self.c_cast_uint(TEMP_LOC.clone(), ty, cty);
+            }
         }
     }
 
@@ -1037,6 +1824,16 @@ impl TraceCompiler {
     fn c_guard(&mut self, guard: &Guard, dl: DynamicLabel) {
         // FIXME some of the terminators from which we build these guards can have cleanup blocks.
         // Currently we don't run any cleanup, but should we?
+        if let Some(passes) = guard.const_outcome() {
+            // Constant guards which always pass are usually removed during TIR construction. One
+            // which always fails unconditionally enters the guard failure code.
+            if !passes {
+                dynasm!(self.asm
+                    ; jmp =>dl
+                );
+            }
+            return;
+        }
         match guard {
             Guard {
                 val,
@@ -1166,27 +1963,7 @@ impl TraceCompiler {
         // To help us figure out what has gone wrong, we can print the disassembled instruction
         // stream with the help of `rasm2`.
         eprintln!("Executable code buffer:");
-        let code = &*self.asm.finalize().unwrap();
-        if code.is_empty() {
-            eprintln!("  <empty buffer>");
-        } else {
-            let hex_code = hex::encode(code);
-            let res = Command::new("rasm2")
-                .arg("-d")
-                .arg("-b 64") // x86_64.
-                .arg(hex_code.clone())
-                .output()
-                .unwrap();
-            if !res.status.success() {
-                eprintln!("  Failed to invoke rasm2. Raw bytes follow...");
-                eprintln!("  {}", hex_code);
-            } else {
-                let asm = String::from_utf8(res.stdout).unwrap();
-                for line in asm.lines() {
-                    eprintln!("  {}", line);
-                }
-            }
-        }
+        disassemble(&*self.asm.finalize().unwrap());
 
         // Print the register allocation.
         eprintln!("\nRegister allocation (place -> reg):");
@@ -1198,24 +1975,24 @@ impl TraceCompiler {
                 local_to_reg_name(location)
             );
         }
+        let layout = self.stack_builder.layout();
+        eprintln!("\nStack layout:");
+        eprint!("{}", layout);
         eprintln!();
+        // Also include the stack layout in the file written if `YKD_DUMP_TRACE_ON_ABORT` is set.
+        append_to_pending_dump(&format!("--- Stack layout ---\n{}", layout));
 
         panic!("stopped due to trace compilation error");
     }
 
-    /// Emit a return instruction.
-    fn ret(&mut self, gl: Vec<(&Guard, HashMap<&Local, Location>, DynamicLabel)>) {
-        // Reset the stack/base pointers and return from the trace. We also need to generate the
-        // code that reserves stack space for spilled locals here, since we don't know at the
-        // beginning of the trace how many locals are going to be spilled.
-        // We also need to reserve space for any live local that remains in a register at the point
-        // of a guard failure. Finally, we push the CALLEE-SAVED registers onto the stack, so the
-        // stack looks as follows:
-        // RSP                                         RBP
-        // +------------+-------------+----------------+-------+-----+
-        // | SAVED REGS | LIVE LOCALS | SPILLED LOCALS | ALIGN | RBP |
-        // +------------+-------------+----------------+-------+-----+
-
+    /// Return the sizes of the stack space reserved below RBP by the trace's prologue: space for
+    /// spilled locals, space for live locals at guard failures, and padding. The stack looks as
+    /// follows:
+    /// RSP                                                 RBP
+    /// +------------+-------+-------------+----------------+-----+
+    /// | SAVED REGS | ALIGN | LIVE LOCALS | SPILLED LOCALS | RBP |
+    /// +------------+-------+-------------+----------------+-----+
+    fn frame_sizes(&self) -> (u32, usize, usize) {
         // Reserved stack space for spilled locals during execution.
         let soff = self.stack_builder.size();
         // Reserved memory on the stack to spill live locals to during a guard failure.
@@ -1224,11 +2001,22 @@ impl TraceCompiler {
         // After allocating stack space for the trace, we pad the stack pointer up to the next
         // 16-byte alignment boundary. Calls can use this fact when catering for alignment
         // requirements of callees (if necessary). Interim pushes and pops in trace code are
-        // allowed as long as they are short-lived and correctly restore 16-byte alignment.
+        // allowed as long as they are short-lived and correctly restore 16-byte alignment. The
+        // padding goes below the locals so that RBP itself is 16-byte aligned: the stack builder
+        // relies on this to align spilled locals.
         let topalign = SYSV_CALL_STACK_ALIGN
             - (live_off + soff as usize + CALLEE_SAVED_REGS.len() * QWORD_REG_SIZE)
                 % SYSV_CALL_STACK_ALIGN;
-        // Restore registers.
+        (soff, live_off, topalign)
+    }
+
+    /// Emit the code which exits the trace normally, returning null, followed by the `cleanup`
+    /// label, to which guard failure code jumps with a pointer to a stopgap interpreter in RAX.
+    /// Both restore the callee-saved registers and the stack before returning. Returns the offset
+    /// of the normal exit code.
+    fn emit_epilogue(&mut self) -> usize {
+        let (soff, live_off, topalign) = self.frame_sizes();
+        let exit_offset = self.asm.offset().0;
         dynasm!(self.asm
             ; mov rax, 0 // Signifies that there were no guard failures.
             ; ->cleanup:
@@ -1236,19 +2024,37 @@ impl TraceCompiler {
         self.restore_regs(&*CALLEE_SAVED_REGS);
         // Restore the stack and return.
         dynasm!(self.asm
+            ; add rsp, topalign as i32
             ; add rsp, live_off as i32
             ; add rsp, soff as i32
-            ; add rsp, topalign as i32
             ; pop rbp
             ; ret
         );
+        exit_offset
+    }
+
+    /// Emit the trace's epilogue, guard failure code and prologue. Each guard's failure code
+    /// increments the counter in `guard_failures` with the same index. Returns the offsets of the
+    /// code which exits the trace normally and of each guard's failure code.
+    fn ret(
+        &mut self,
+        gl: Vec<(&Guard, HashMap<&Local, Location>, DynamicLabel)>,
+        guard_failures: &[AtomicU64],
+    ) -> (usize, Vec<usize>) {
+        // We generate the code that reserves stack space for spilled locals last, since we don't
+        // know at the beginning of the trace how many locals are going to be spilled. We also
+        // need to reserve space for any live local that remains in a register at the point of a
+        // guard failure.
+        let (soff, live_off, topalign) = self.frame_sizes();
+        let exit_offset = self.emit_epilogue();
 
         // Add guard failure labels. When a guard fails we jump to one of these labels. For each
         // stack frame, we need to allocate memory using `allocate_layout`. We then copy the live
         // variables into this memory and store it, together with other information, inside a
         // Vec<FrameInfo>. Finally, we call `invoke_sinterp` passing in this vector, which
         // initialises a SIR interpreter and runs it.
-        for (guard, mut live_locations, label) in gl {
+        let mut fail_offsets = Vec::new();
+        for ((guard, mut live_locations, label), counter) in gl.into_iter().zip(guard_failures) {
             // Symbol names of the functions called while executing the trace. Needed to recreate
             // the stack frames in the StopgapInterpreter.
             let mut sym_labels = Vec::new();
@@ -1261,10 +2067,17 @@ impl TraceCompiler {
                 sym_labels.push(dynlbl);
             }
 
-            // The beginning of the guard code.
+            // The beginning of the guard code. First record the failure: the trace may be running
+            // in several threads at once, so the increment must be atomic. TEMP_REG is free here.
+            // Note that `patch_jump` relies on these two instructions being at least
+            // `PATCH_JUMP_SIZE` bytes long.
+            fail_offsets.push(self.asm.offset().0);
             dynasm!(self.asm
                 ; => label
+                ; mov Rq(*TEMP_REG), QWORD counter as *const AtomicU64 as i64
+                ; lock add QWORD [Rq(*TEMP_REG)], 1
             );
+            debug_assert!(self.asm.offset().0 - fail_offsets.last().unwrap() >= PATCH_JUMP_SIZE);
 
             // Spill all registers that are currently in use to the stack, so we have enough
             // registers available to generate the stack frames. This also saves us from having to
@@ -1358,29 +2171,70 @@ impl TraceCompiler {
         dynasm!(self.asm
             ; ->reserve:
             ; push rbp
-            ; sub rsp, topalign as i32
             ; mov rbp, rsp
             ; sub rsp, soff as i32
             ; sub rsp, live_off as i32
+            ; sub rsp, topalign as i32
         );
         // Save registers and jump to main.
         self.save_regs(&*CALLEE_SAVED_REGS);
         dynasm!(self.asm
             ; jmp ->main
         );
+        (exit_offset, fail_offsets)
+    }
+
+    /// Find the index of the TIR op at which each local dies.
+    fn last_uses(tt: &TirTrace) -> HashMap<Local, usize> {
+        let mut last_uses = HashMap::new();
+        for i in 0..tt.len() {
+            if let TirOp::Statement(Statement::StorageDead(l)) = unsafe { tt.op(i) } {
+                last_uses.insert(*l, i);
+            }
+        }
+        last_uses
     }
 
-    fn compile(mut tt: TirTrace, debug: bool) -> dynasmrt::ExecutableBuffer {
+    fn compile(
+        mut tt: TirTrace,
+        opts: &CompileOptions,
+        debug: bool,
+    ) -> Result<CompiledTrace, CompileError> {
+        let op_counts = tt.count_ops_by_kind();
         let mut tc: Self = TraceCompiler::new(
             tt.local_decls.clone(),
             tt.addr_map.drain().into_iter().collect(),
         );
+        tc.register_content_map = opts
+            .register_pool
+            .iter()
+            .map(|r| {
+                assert!(REG_POOL.contains(r), "register {} is not in REG_POOL", r);
+                (*r, RegAlloc::Free)
+            })
+            .collect();
+        tc.last_uses = Self::last_uses(&tt);
+        // If the trace loops, keep a count of the iterations in a stack slot. Since everything the
+        // trace computes flows through the interpreter context, which is always in `ICTX_REG`,
+        // each iteration can simply start from scratch.
+        let loop_counter = opts.loop_iterations.map(|_| {
+            let size = u64::try_from(QWORD_REG_SIZE).unwrap();
+            let loc = tc.stack_builder.alloc_named("loop counter", size, size);
+            let ro = loc.unwrap_mem().clone();
+            dynasm!(tc.asm
+                ; mov QWORD [Rq(ro.reg) + ro.off], 0
+                ; ->tloop_back:
+            );
+            ro
+        });
         let mut gl = Vec::new();
+        let mut guard_offsets = Vec::new();
         for i in 0..tt.len() {
             let res = match unsafe { tt.op(i) } {
                 TirOp::Statement(st) => tc.c_statement(st),
                 TirOp::Guard(g) => {
                     let dl = tc.asm.new_dynamic_label();
+                    guard_offsets.push(tc.asm.offset().0);
                     tc.c_guard(g, dl);
                     // As the locations of live variables may change throughout the trace, we need
                     // to save them here for each guard, so when a guard fails we know from which
@@ -1401,11 +2255,34 @@ impl TraceCompiler {
             // compilation and carry on.
             match res {
                 Ok(_) => (),
+                // We know up-front that we can't compile these, so there's nothing to debug.
+                Err(e @ CompileError::Unimplemented(_)) => return Err(e),
                 Err(e) => tc.crash_dump(Some(e)),
             }
         }
-        tc.ret(gl);
+        if let (Some(ro), Some(iters)) = (loop_counter, opts.loop_iterations) {
+            dynasm!(tc.asm
+                ; add QWORD [Rq(ro.reg) + ro.off], 1
+                ; cmp QWORD [Rq(ro.reg) + ro.off], i32::try_from(iters).unwrap()
+                ; jb ->tloop_back
+            );
+        }
+        if let Some(max) = opts.max_stack_bytes {
+            let size = tc.stack_builder.size();
+            if size > max {
+                return Err(CompileError::StackTooLarge { size, max });
+            }
+        }
+        let guard_failures = (0..gl.len())
+            .map(|_| AtomicU64::new(0))
+            .collect::<Box<[_]>>();
+        let (exit_offset, guard_fail_offsets) = tc.ret(gl, &guard_failures);
         let buf = tc.asm.finalize().unwrap();
+        if *PRINT_DISASM {
+            eprintln!("--- Begin trace disassembly ---");
+            disassemble(&*buf);
+            eprintln!("--- End trace disassembly ---");
+        }
         if debug {
             // In debug mode the memory section which contains the compiled trace is marked as
             // writeable, which enables gdb/lldb to set breakpoints within the compiled code.
@@ -1418,6 +2295,215 @@ impl TraceCompiler {
                 libc::mprotect(ptr, len, libc::PROT_EXEC | libc::PROT_WRITE);
             }
         }
-        buf
+        Ok(CompiledTrace {
+            mc: buf,
+            guard_failures,
+            guard_offsets,
+            guard_fail_offsets,
+            exit_offset,
+            op_counts,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        append_to_pending_dump, install_trace_dump_hook, PendingDump, RegAlloc, StackBuilder,
+        TraceCompiler, REG_POOL, TEMP_REG,
+    };
+    use dynasmrt::{dynasm, DynasmApi};
+    use std::{collections::HashMap, convert::TryFrom, env, fs, mem, panic::catch_unwind, process};
+    use ykpack::{FloatTy, SignedIntTy, Ty, TyKind, UnsignedIntTy};
+
+    /// Assemble a function which loads its argument into `TEMP_REG`, runs the code emitted by `f`
+    /// and returns the contents of `TEMP_REG`, then call it with `arg`. Since types normally come
+    /// from SIR, which unit tests don't have, this allows the code generated for operations which
+    /// only work on `TEMP_REG` to be tested directly.
+    fn run_on_temp_reg(f: impl FnOnce(&mut TraceCompiler), arg: u64) -> u64 {
+        let mut tc = TraceCompiler {
+            asm: dynasmrt::x64::Assembler::new().unwrap(),
+            register_content_map: REG_POOL.iter().map(|r| (*r, RegAlloc::Free)).collect(),
+            variable_location_map: HashMap::new(),
+            local_decls: HashMap::new(),
+            last_uses: HashMap::new(),
+            stack_builder: StackBuilder::default(),
+            addr_map: HashMap::new(),
+        };
+        let start = tc.asm.offset();
+        dynasm!(tc.asm
+            ; mov Rq(*TEMP_REG), rdi
+        );
+        f(&mut tc);
+        dynasm!(tc.asm
+            ; mov rax, Rq(*TEMP_REG)
+            ; ret
+        );
+        let buf = tc.asm.finalize().unwrap();
+        let func: extern "sysv64" fn(u64) -> u64 = unsafe { mem::transmute(buf.ptr(start)) };
+        func(arg)
+    }
+
+    fn int_ty(kind: TyKind) -> Ty {
+        let size = match &kind {
+            TyKind::SignedInt(SignedIntTy::I8) | TyKind::UnsignedInt(UnsignedIntTy::U8) => 1,
+            TyKind::SignedInt(SignedIntTy::I16) | TyKind::UnsignedInt(UnsignedIntTy::U16) => 2,
+            TyKind::SignedInt(SignedIntTy::I32) | TyKind::UnsignedInt(UnsignedIntTy::U32) => 4,
+            TyKind::SignedInt(SignedIntTy::I64) | TyKind::UnsignedInt(UnsignedIntTy::U64) => 8,
+            _ => unreachable!(),
+        };
+        Ty {
+            size,
+            align: size,
+            kind,
+        }
+    }
+
+    fn cast_float_to_int(fty: &FloatTy, kind: TyKind, bits: u64) -> u64 {
+        let cty = int_ty(kind);
+        run_on_temp_reg(|tc| tc.c_cast_float_to_int(fty, &cty), bits)
+    }
+
+    fn cast_int_to_float(kind: TyKind, fty: &FloatTy, val: u64) -> u64 {
+        let ty = int_ty(kind);
+        run_on_temp_reg(|tc| tc.c_cast_int_to_float(&ty, fty), val)
+    }
+
+    #[test]
+    fn float_to_int_casts() {
+        let vals = [
+            0.0,
+            -0.0,
+            1.5,
+            -1.5,
+            127.9,
+            128.0,
+            -129.0,
+            255.5,
+            256.0,
+            65536.0,
+            2147483648.0,
+            -2147483649.0,
+            4294967296.0,
+            9223372036854775807.0,
+            -9.3e18,
+            1.5e19,
+            18446744073709551616.0,
+            1e30,
+            -1e30,
+            f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ];
+        for &v in &vals {
+            let (f32b, f64b) = (u64::from((v as f32).to_bits()), v.to_bits());
+            for (fty, bits) in vec![(FloatTy::F32, f32b), (FloatTy::F64, f64b)] {
+                let x = match fty {
+                    FloatTy::F32 => f64::from(v as f32),
+                    FloatTy::F64 => v,
+                };
+                macro_rules! check {
+                    ($kind:expr, $ity:ty) => {
+                        assert_eq!(
+                            cast_float_to_int(&fty, $kind, bits) as $ity,
+                            x as $ity,
+                            "{} ({:?}) as {}",
+                            v,
+                            fty,
+                            stringify!($ity)
+                        );
+                    };
+                }
+                check!(TyKind::SignedInt(SignedIntTy::I8), i8);
+                check!(TyKind::SignedInt(SignedIntTy::I16), i16);
+                check!(TyKind::SignedInt(SignedIntTy::I32), i32);
+                check!(TyKind::SignedInt(SignedIntTy::I64), i64);
+                check!(TyKind::UnsignedInt(UnsignedIntTy::U8), u8);
+                check!(TyKind::UnsignedInt(UnsignedIntTy::U16), u16);
+                check!(TyKind::UnsignedInt(UnsignedIntTy::U32), u32);
+                check!(TyKind::UnsignedInt(UnsignedIntTy::U64), u64);
+            }
+        }
+    }
+
+    #[test]
+    fn int_to_float_casts() {
+        let vals = [
+            0,
+            1,
+            0xff,
+            0xffff_ffff,
+            (1 << 63) - 1,
+            1 << 63,
+            (1 << 63) + 1,
+            0x8000_0000_0000_0400,
+            0x8000_0000_0000_0401,
+            0x8000_0000_0000_0c00,
+            u64::MAX,
+        ];
+        for &v in &vals {
+            let u64_ty = || TyKind::UnsignedInt(UnsignedIntTy::U64);
+            let i64_ty = || TyKind::SignedInt(SignedIntTy::I64);
+            let f64_of = |bits| f64::from_bits(bits);
+            let f32_of = |bits| f32::from_bits(u32::try_from(bits).unwrap());
+            assert_eq!(
+                f64_of(cast_int_to_float(u64_ty(), &FloatTy::F64, v)),
+                v as f64
+            );
+            assert_eq!(
+                f32_of(cast_int_to_float(u64_ty(), &FloatTy::F32, v)),
+                v as f32
+            );
+            assert_eq!(
+                f64_of(cast_int_to_float(i64_ty(), &FloatTy::F64, v)),
+                v as i64 as f64
+            );
+            assert_eq!(
+                f32_of(cast_int_to_float(i64_ty(), &FloatTy::F32, v)),
+                v as i64 as f32
+            );
+            // Narrower integers are extended according to their signedness first.
+            let i8_ty = TyKind::SignedInt(SignedIntTy::I8);
+            assert_eq!(
+                f64_of(cast_int_to_float(i8_ty, &FloatTy::F64, v)),
+                v as i8 as f64
+            );
+            let u32_ty = TyKind::UnsignedInt(UnsignedIntTy::U32);
+            assert_eq!(
+                f64_of(cast_int_to_float(u32_ty, &FloatTy::F64, v)),
+                v as u32 as f64
+            );
+        }
+    }
+
+    #[test]
+    fn dump_on_abort() {
+        let path = env::temp_dir().join(format!("yk_dump_on_abort_{}.txt", process::id()));
+        let _ = fs::remove_file(&path);
+        install_trace_dump_hook();
+
+        // Nothing is written if compilation succeeds.
+        {
+            let _pending = PendingDump::new(path.clone(), String::from("--- TIR trace ---"));
+        }
+        assert!(!path.exists());
+
+        // The hook writes the dump before unwinding starts, so it would also do so if we were
+        // about to abort.
+        let r = catch_unwind(|| {
+            let _pending = PendingDump::new(path.clone(), String::from("--- TIR trace ---\n"));
+            append_to_pending_dump("--- Stack layout ---\n");
+            panic!("deliberate abort for dump test");
+        });
+        assert!(r.is_err());
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents
+            .starts_with("--- TIR trace ---\n--- Stack layout ---\n\n--- Panic message ---\n"));
+        assert!(contents.contains("deliberate abort for dump test"));
+        fs::remove_file(&path).unwrap();
+
+        // The pending dump is cleared once compilation is over, so later panics write nothing.
+        assert!(catch_unwind(|| panic!()).is_err());
+        assert!(!path.exists());
     }
 }
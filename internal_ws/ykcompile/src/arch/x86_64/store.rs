@@ -2,6 +2,7 @@
 
 use super::{IndirectLoc, Location, RegAndOffset, TraceCompiler, TEMP_REG};
 use dynasmrt::DynasmApi;
+use std::convert::TryFrom;
 use ykpack::IRPlace;
 use yktrace::sir::SIR;
 
@@ -22,7 +23,8 @@ impl TraceCompiler {
         //
         // FIXME avoid partial register stalls.
         // FIXME this is massive. Move this (and store() to a new file).
-        // FIXME constants are assumed to fit in a 64-bit register.
+        // FIXME constants are assumed to fit in a 64-bit register (128-bit constants can only be
+        // stored to the stack).
 
         /// Break a 64-bit value down into two 32-bit values. Used in scenarios where the X86_64
         /// ISA doesn't allow 64-bit constant encodings.
@@ -79,6 +81,17 @@ impl TraceCompiler {
                         ),
                         _ => unreachable!(),
                     }
+                } else if size == 16 {
+                    // 128-bit integers are common enough that it's worth avoiding a call to
+                    // memmove. The two halves are moved separately.
+                    debug_assert!(dest_ro.reg != *TEMP_REG);
+                    debug_assert!(src_ro.reg != *TEMP_REG);
+                    dynasm!(self.asm
+                        ; mov Rq(*TEMP_REG), QWORD [Rq(src_ro.reg) + src_ro.off]
+                        ; mov QWORD [Rq(dest_ro.reg) + dest_ro.off], Rq(*TEMP_REG)
+                        ; mov Rq(*TEMP_REG), QWORD [Rq(src_ro.reg) + src_ro.off + 8]
+                        ; mov QWORD [Rq(dest_ro.reg) + dest_ro.off + 8], Rq(*TEMP_REG)
+                    );
                 } else {
                     self.copy_memory(dest_ro, src_ro, size);
                 }
@@ -100,19 +113,15 @@ impl TraceCompiler {
             },
             (Location::Reg(dest_reg), Location::Const { val: c_val, .. }) => {
                 let i64_c = c_val.i64_cast();
-                if i64_c <= i64::from(u32::MAX) {
+                if let Ok(i32_c) = i32::try_from(i64_c) {
+                    // The immediate is sign-extended, so this only works for values that fit in
+                    // an i32.
                     dynasm!(self.asm
-                        ; mov Rq(dest_reg), i64_c as i32
+                        ; mov Rq(dest_reg), i32_c
                     );
                 } else {
-                    // Can't move 64-bit constants in x86_64.
-                    let i64_c = c_val.i64_cast();
-                    let hi_word = (i64_c >> 32) as i32;
-                    let lo_word = (i64_c & 0xffffffff) as i32;
                     dynasm!(self.asm
-                        ; mov Rq(dest_reg), hi_word
-                        ; shl Rq(dest_reg), 32
-                        ; or Rq(dest_reg), lo_word
+                        ; mov Rq(dest_reg), QWORD i64_c
                     );
                 }
             }
@@ -135,6 +144,19 @@ impl TraceCompiler {
                             ; mov DWORD [Rq(ro.reg) + ro.off + 4], hi as i32
                         );
                     }
+                    16 => {
+                        // A 128-bit integer is stored as its low and high 64-bit halves (in that
+                        // order, since we are little-endian).
+                        let c_u128 = c_val.u128_cast();
+                        let (lo_hi, lo_lo) = split_i64(c_u128 as i64);
+                        let (hi_hi, hi_lo) = split_i64((c_u128 >> 64) as i64);
+                        dynasm!(self.asm
+                            ; mov DWORD [Rq(ro.reg) + ro.off], lo_lo as i32
+                            ; mov DWORD [Rq(ro.reg) + ro.off + 4], lo_hi as i32
+                            ; mov DWORD [Rq(ro.reg) + ro.off + 8], hi_lo as i32
+                            ; mov DWORD [Rq(ro.reg) + ro.off + 12], hi_hi as i32
+                        );
+                    }
                     _ => todo!(),
                 }
             }
@@ -328,6 +350,12 @@ impl TraceCompiler {
                             ; mov Rq(*TEMP_REG), QWORD [Rq(src_reg) + *src_off]
                             ; mov QWORD [Rq(dest_ro.reg) + dest_ro.off], Rq(*TEMP_REG)
                         ),
+                        16 => dynasm!(self.asm
+                            ; mov Rq(*TEMP_REG), QWORD [Rq(src_reg) + *src_off]
+                            ; mov QWORD [Rq(dest_ro.reg) + dest_ro.off], Rq(*TEMP_REG)
+                            ; mov Rq(*TEMP_REG), QWORD [Rq(src_reg) + *src_off + 8]
+                            ; mov QWORD [Rq(dest_ro.reg) + dest_ro.off + 8], Rq(*TEMP_REG)
+                        ),
                         _ => {
                             let src_ro = RegAndOffset {
                                 reg: *src_reg,
@@ -364,10 +392,16 @@ impl TraceCompiler {
                             ; mov Rq(*TEMP_REG), QWORD [Rq(src_ro.reg) + src_ro.off]
                             ; mov QWORD [Rq(dest_reg) + *dest_off], Rq(*TEMP_REG)
                         ),
+                        16 => dynasm!(self.asm
+                            ; mov Rq(*TEMP_REG), QWORD [Rq(src_ro.reg) + src_ro.off]
+                            ; mov QWORD [Rq(dest_reg) + *dest_off], Rq(*TEMP_REG)
+                            ; mov Rq(*TEMP_REG), QWORD [Rq(src_ro.reg) + src_ro.off + 8]
+                            ; mov QWORD [Rq(dest_reg) + *dest_off + 8], Rq(*TEMP_REG)
+                        ),
                         _ => {
                             let dest_ro = RegAndOffset {
                                 reg: *dest_reg,
-                                off: 0,
+                                off: *dest_off,
                             };
                             self.copy_memory(&dest_ro, src_ro, size);
                         }
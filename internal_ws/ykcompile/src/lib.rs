@@ -9,8 +9,16 @@ extern crate lazy_static;
 #[cfg(test)]
 extern crate test;
 
-use libc::{c_void, dlsym, RTLD_DEFAULT};
-use std::{ffi::CString, fmt, mem};
+use libc::{c_int, c_void, dl_iterate_phdr, dl_phdr_info, dlsym, RTLD_DEFAULT};
+use object::{Object, ObjectSymbol};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    ffi::CString,
+    fmt, fs, mem,
+    sync::atomic::{AtomicU64, Ordering},
+};
 use ykpack::{Constant, Local, OffT, TypeId};
 use yksg::StopgapInterpreter;
 
@@ -19,18 +27,80 @@ mod stack_builder;
 
 // FIXME hard-wired use of the x86_64 backend.
 // This should be made into a properly abstracted API.
-pub use arch::x86_64::{compile_trace, TraceCompiler, REG_POOL};
+pub use arch::x86_64::{
+    compile_trace, compile_trace_with_options, install_trace_dump_hook, TraceCompiler, REG_POOL,
+};
 
 #[derive(Debug, Hash, Eq, PartialEq)]
 pub enum CompileError {
     /// The binary symbol could not be found.
     UnknownSymbol(String),
+    /// The trace needs more stack space for its locals than `CompileOptions::max_stack_bytes`.
+    StackTooLarge { size: u32, max: u32 },
+    /// The trace contains a statement which ykrustc couldn't lower to SIR. The string describes
+    /// the original MIR statement.
+    Unimplemented(String),
+    /// `CompileOptions::loop_iterations` is out of range.
+    InvalidLoopIterations(u32),
 }
 
 impl fmt::Display for CompileError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::UnknownSymbol(s) => write!(f, "Unknown symbol: {}", s),
+            Self::StackTooLarge { size, max } => write!(
+                f,
+                "Trace needs {} bytes of stack, but at most {} are allowed",
+                size, max
+            ),
+            Self::Unimplemented(s) => write!(f, "Unimplemented statement: {}", s),
+            Self::InvalidLoopIterations(n) => write!(
+                f,
+                "Invalid number of loop iterations: {} (must be between 1 and {})",
+                n,
+                i32::MAX
+            ),
+        }
+    }
+}
+
+/// The reasons that `CompiledTrace::patch_guard` can fail.
+#[derive(Debug)]
+pub enum PatchError {
+    /// The trace has no guard with the given index.
+    InvalidGuard(usize),
+    /// The memory containing the trace could not be made writeable.
+    Protect(std::io::Error),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidGuard(g) => write!(f, "Trace has no guard {}", g),
+            Self::Protect(e) => write!(f, "Can't make trace writeable: {}", e),
+        }
+    }
+}
+
+/// Options controlling the compilation of a trace.
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    /// The registers that the register allocator may use. This must be a subset of `REG_POOL`.
+    pub register_pool: Vec<u8>,
+    /// If set, compilation fails if the trace's locals need more than this many bytes of stack.
+    pub max_stack_bytes: Option<u32>,
+    /// If set, the end of the trace jumps back to its start, so that the trace runs (at most) this
+    /// many times before returning. Otherwise the trace runs once. Compilation fails if this is 0
+    /// or greater than `i32::MAX`.
+    pub loop_iterations: Option<u32>,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            register_pool: REG_POOL.to_vec(),
+            max_stack_bytes: None,
+            loop_iterations: None,
         }
     }
 }
@@ -136,13 +206,26 @@ enum RegAlloc {
 pub struct CompiledTrace {
     /// A compiled trace.
     mc: dynasmrt::ExecutableBuffer,
+    /// The number of times each of the trace's guards has failed, in the order that the guards
+    /// appear in the trace. These are incremented directly by the trace's guard failure code.
+    guard_failures: Box<[AtomicU64]>,
+    /// The offset, in bytes from the start of `mc`, of the first instruction of each guard.
+    guard_offsets: Vec<usize>,
+    /// The offset, in bytes from the start of `mc`, of each guard's failure code.
+    guard_fail_offsets: Vec<usize>,
+    /// The offset, in bytes from the start of `mc`, of the code which returns from the trace as
+    /// if no guard had failed.
+    exit_offset: usize,
+    /// The number of ops of each kind in the TIR trace this trace was compiled from.
+    op_counts: HashMap<&'static str, usize>,
 }
 
 impl CompiledTrace {
     /// Execute the trace by calling (not jumping to) the first instruction's address. Returns a
-    /// pointer to an initialised `StopgapInterpreter` if there was a guard failure, or a null
-    /// pointer otherwise. Note that the interpreter holds a `*mut` pointer to `args`, so we need
-    /// to make sure that `args` is still alive when we call the interpreters `interpret` function.
+    /// pointer to an initialised `StopgapInterpreter` if there was a guard failure (in any
+    /// iteration, if the trace loops), or a null pointer otherwise. Note that the interpreter
+    /// holds a `*mut` pointer to `args`, so we need to make sure that `args` is still alive when
+    /// we call the interpreters `interpret` function.
     pub unsafe fn execute<TT>(&self, args: &mut TT) -> *mut StopgapInterpreter {
         let func: extern "sysv64" fn(&mut TT) -> *mut StopgapInterpreter =
             mem::transmute(self.mc.ptr(dynasmrt::AssemblyOffset(0)));
@@ -164,21 +247,164 @@ impl CompiledTrace {
     pub fn ptr(&self) -> *const u8 {
         self.mc.ptr(dynasmrt::AssemblyOffset(0))
     }
+
+    /// Return the size, in bytes, of the machine code of the trace.
+    pub fn code_size(&self) -> usize {
+        self.mc.len()
+    }
+
+    /// Return the number of times each of the trace's guards has failed, in the order that the
+    /// guards appear in the trace.
+    pub fn guard_failures(&self) -> Vec<u64> {
+        self.guard_failures
+            .iter()
+            .map(|c| c.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Return the number of ops of each kind (as named by `TirOp::kind_name`) in the TIR trace
+    /// this trace was compiled from.
+    pub fn op_counts(&self) -> &HashMap<&'static str, usize> {
+        &self.op_counts
+    }
+
+    /// Return the offset, in bytes from the start of the trace, of the first instruction of the
+    /// `guard`th guard (i.e. the instruction which compares the guarded value), or `None` if the
+    /// trace has no such guard.
+    pub fn find_guard_offset(&self, guard: usize) -> Option<usize> {
+        self.guard_offsets.get(guard).copied()
+    }
+
+    /// Return a pointer to the code which returns from the trace as if no guard had failed.
+    /// Jumping here from within the trace (e.g. from a guard patched with `patch_guard`) cleanly
+    /// exits the trace.
+    pub fn exit_ptr(&self) -> *const u8 {
+        self.mc.ptr(dynasmrt::AssemblyOffset(self.exit_offset))
+    }
+
+    /// Patch the failure code of the `guard`th guard so that, rather than handing over to the
+    /// stopgap interpreter, a failing guard jumps to `target` (e.g. a side trace). The code at
+    /// `target` is entered with the trace's stack frame and registers as they were when the guard
+    /// failed. Patched guards no longer count their failures.
+    ///
+    /// # Safety
+    ///
+    /// No thread may be running the guard's failure code while it is patched, and `target` must
+    /// be code which can cope with the trace's state.
+    pub unsafe fn patch_guard(&self, guard: usize, target: *const u8) -> Result<(), PatchError> {
+        let off = *self
+            .guard_fail_offsets
+            .get(guard)
+            .ok_or(PatchError::InvalidGuard(guard))?;
+        let addr = self.mc.ptr(dynasmrt::AssemblyOffset(off)) as *mut u8;
+        arch::x86_64::patch_jump(addr, target).map_err(PatchError::Protect)
+    }
+
+    /// Disassemble the trace's machine code with the help of `rasm2`.
+    pub fn disassemble(&self) -> Result<String, Box<dyn Error>> {
+        self.disassemble_range(0, self.mc.len())
+    }
+
+    /// Disassemble the machine code between byte offsets `start` (inclusive) and `end`
+    /// (exclusive). This is useful for looking at one part of the trace (e.g. a single guard, as
+    /// found with `find_guard_offset`) without wading through the whole thing.
+    pub fn disassemble_range(&self, start: usize, end: usize) -> Result<String, Box<dyn Error>> {
+        if start > end || end > self.mc.len() {
+            return Err(format!(
+                "range {}..{} is out of bounds for a trace of {} bytes",
+                start,
+                end,
+                self.mc.len()
+            )
+            .into());
+        }
+        arch::x86_64::disassemble_to_string(&self.mc[start..end])
+    }
+}
+
+lazy_static! {
+    /// Maps the names of the symbols defined in the executable's symbol tables to their run-time
+    /// addresses. This is only built if `dlsym` fails to find a symbol.
+    static ref EXE_SYMBOLS: HashMap<String, usize> = load_exe_symbols();
 }
 
 /// Returns a pointer to the static symbol `sym`, or an error if it cannot be found.
 ///
-/// In general, there is no guarantee that a symbol will be found, especially when dealing with
-/// binary executables where there's no public API to speak of. In such cases the Rust compiler is
-/// free to (and often does) throw away symbols. Note however that symbols marked `#[no_mangle] are
-/// always exported.
+/// Symbols are first looked up with `dlsym`, which only finds exported symbols. If that fails, we
+/// fall back to searching the executable's symbol table, which also contains symbols that weren't
+/// exported (so long as the executable hasn't been stripped). In general, there is still no
+/// guarantee that a symbol will be found: the Rust compiler is free to (and often does) throw
+/// away symbols. Note however that symbols marked `#[no_mangle]` are never thrown away.
 pub fn find_symbol(sym: &str) -> Result<*mut c_void, CompileError> {
     let sym_arg = CString::new(sym).unwrap();
-    let addr = unsafe { dlsym(RTLD_DEFAULT, sym_arg.into_raw()) };
+    let addr = unsafe { dlsym(RTLD_DEFAULT, sym_arg.as_ptr()) };
 
     if !addr.is_null() {
         Ok(addr)
+    } else if let Some(addr) = EXE_SYMBOLS.get(sym) {
+        Ok(*addr as *mut c_void)
     } else {
         Err(CompileError::UnknownSymbol(sym.to_owned()))
     }
 }
+
+/// Reads the defined symbols from the executable's symbol tables, relocating their addresses by
+/// the executable's load address. If the executable can't be read, the map is empty.
+fn load_exe_symbols() -> HashMap<String, usize> {
+    let mut syms = HashMap::new();
+    let mmap = match env::current_exe()
+        .and_then(fs::File::open)
+        .and_then(|f| unsafe { memmap2::Mmap::map(&f) })
+    {
+        Ok(mmap) => mmap,
+        Err(_) => return syms,
+    };
+    let object = match object::File::parse(&*mmap) {
+        Ok(o) => o,
+        Err(_) => return syms,
+    };
+    let bias = exe_load_bias();
+    for sym in object.symbols() {
+        if !sym.is_definition() {
+            continue;
+        }
+        if let Ok(name) = sym.name() {
+            syms.entry(name.to_owned())
+                .or_insert(bias + sym.address() as usize);
+        }
+    }
+    syms
+}
+
+/// Returns the difference between the addresses in the executable's symbol table and the
+/// addresses the symbols were loaded at (which is non-zero for position independent executables).
+fn exe_load_bias() -> usize {
+    // The first object `dl_iterate_phdr` reports is always the executable itself.
+    extern "C" fn first_addr(info: *mut dl_phdr_info, _size: usize, data: *mut c_void) -> c_int {
+        unsafe { *(data as *mut usize) = (*info).dlpi_addr as usize };
+        1
+    }
+    let mut bias = 0usize;
+    unsafe { dl_iterate_phdr(Some(first_addr), &mut bias as *mut usize as *mut c_void) };
+    bias
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find_symbol;
+    use libc::c_void;
+
+    // Test binaries aren't linked with `--export-dynamic`, so this can't be found with `dlsym`.
+    #[no_mangle]
+    #[inline(never)]
+    extern "C" fn ykcompile_internal_symbol() {}
+
+    #[test]
+    fn find_internal_symbol() {
+        assert_eq!(
+            find_symbol("ykcompile_internal_symbol").unwrap(),
+            ykcompile_internal_symbol as *mut c_void
+        );
+        assert!(find_symbol("__xxxyyyzzz__").is_err());
+    }
+}
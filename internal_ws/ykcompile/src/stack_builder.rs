@@ -4,16 +4,31 @@
 //! potentially inserting more alignment padding than would be necessary if we deferred and
 //! reordered allocations.
 //!
-//! The stack is assumed to be appropriately aligned before the builder is used.
+//! Allocations are made relative to RBP, which is assumed to be 16-byte aligned (the trace prologue
+//! ensures this), so any alignment up to 16 bytes is honoured.
 
 use crate::Location;
 use dynasmrt::{x64::Rq::RBP, Register};
-use std::convert::{TryFrom, TryInto};
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt::Write,
+};
 
 #[derive(Default, Debug)]
 pub(crate) struct StackBuilder {
     /// Keeps track of how many bytes have been allocated.
     stack_top: u64,
+    /// The allocations made with `alloc_named`, in the order they were made.
+    named_slots: Vec<NamedSlot>,
+}
+
+/// A named stack allocation, recorded so that the stack layout can be printed when debugging.
+#[derive(Debug)]
+struct NamedSlot {
+    name: String,
+    /// The allocation's distance below RBP.
+    off: u64,
+    size: u64,
 }
 
 impl StackBuilder {
@@ -25,6 +40,40 @@ impl StackBuilder {
         Location::new_mem(RBP.code(), -i32::try_from(self.stack_top).unwrap())
     }
 
+    /// Like `alloc`, but records the allocation under `name` so that it shows up in `layout`.
+    pub(crate) fn alloc_named(
+        &mut self,
+        name: impl Into<String>,
+        size: u64,
+        align: u64,
+    ) -> Location {
+        let loc = self.alloc(size, align);
+        self.named_slots.push(NamedSlot {
+            name: name.into(),
+            off: self.stack_top,
+            size,
+        });
+        loc
+    }
+
+    /// Describe the allocations made with `alloc_named`, one per line, in the order they were
+    /// made.
+    pub(crate) fn layout(&self) -> String {
+        let mut s = String::new();
+        for slot in &self.named_slots {
+            writeln!(
+                s,
+                "  [rbp-{}..rbp-{}] {} ({} bytes)",
+                slot.off,
+                slot.off - slot.size,
+                slot.name,
+                slot.size
+            )
+            .unwrap();
+        }
+        s
+    }
+
     /// Aligns `offset` to `align` bytes.
     fn align(&mut self, align: u64) {
         let mask = align - 1;
@@ -40,6 +89,7 @@ impl StackBuilder {
 #[cfg(test)]
 mod tests {
     use super::StackBuilder;
+    use std::convert::TryFrom;
 
     #[test]
     fn stackbuilder() {
@@ -51,4 +101,26 @@ mod tests {
         assert_eq!(sb.alloc(1, 1).unwrap_mem().off, -25);
         assert_eq!(sb.alloc(4, 2).unwrap_mem().off, -30);
     }
+
+    #[test]
+    fn stackbuilder_alignment() {
+        let mut sb = StackBuilder::default();
+        for &(size, align) in &[(1, 1), (16, 16), (4, 4), (1, 1), (8, 8), (16, 16), (4, 4)] {
+            let off = sb.alloc(size, align).unwrap_mem().off;
+            assert_eq!(off % i32::try_from(align).unwrap(), 0);
+        }
+        assert_eq!(sb.size(), 68);
+    }
+
+    #[test]
+    fn stackbuilder_layout() {
+        let mut sb = StackBuilder::default();
+        assert_eq!(sb.alloc_named("$1", 1, 1).unwrap_mem().off, -1);
+        sb.alloc(2, 2);
+        assert_eq!(sb.alloc_named("$2", 8, 8).unwrap_mem().off, -16);
+        assert_eq!(
+            sb.layout(),
+            "  [rbp-1..rbp-0] $1 (1 bytes)\n  [rbp-16..rbp-8] $2 (8 bytes)\n"
+        );
+    }
 }
@@ -3,9 +3,14 @@
 //! Offers a simple iterator interface to serialised packs.
 
 use crate::Pack;
+use bincode::Options;
 use fallible_iterator::FallibleIterator;
 use std::io::Read;
 
+/// The maximum number of bytes that a single pack may occupy. Without a limit, a corrupt length
+/// prefix causes the decoder to try and allocate a huge buffer, which panics or aborts.
+const MAX_PACK_SIZE: u64 = 1 << 30;
+
 pub struct Decoder<'a> {
     from: &'a mut dyn Read,
 }
@@ -22,6 +27,11 @@ impl<'a> FallibleIterator for Decoder<'a> {
     type Error = bincode::Error;
 
     fn next(&mut self) -> Result<Option<Self::Item>, Self::Error> {
-        bincode::deserialize_from(&mut *self.from)
+        // These options are the same as those used by `bincode::serialize_into`, plus a limit.
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(MAX_PACK_SIZE)
+            .deserialize_from(&mut *self.from)
     }
 }
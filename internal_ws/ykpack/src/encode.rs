@@ -19,6 +19,11 @@ impl<'a> Encoder<'a> {
         bincode::serialize_into(&mut *self.buf, &Some(md))
     }
 
+    /// Serialises the sentinel marking the end of the packs.
+    pub fn done(&mut self) -> Result<(), bincode::Error> {
+        bincode::serialize_into(&mut *self.buf, &None::<Pack>)
+    }
+
     /// Return the number of bytes encoded so far.
     pub fn tell(&mut self) -> usize {
         self.buf.len()
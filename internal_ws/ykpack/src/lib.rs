@@ -21,6 +21,11 @@
 //!  The version field is automatically written and checked by the `Encoder` and `Decoder`
 //!  respectively.
 
+#![cfg_attr(test, feature(test))]
+
+#[cfg(test)]
+extern crate test;
+
 #[cfg(feature = "write_utils")]
 pub mod build;
 mod decode;
@@ -44,9 +49,16 @@ pub const YKLABELS_SECTION: &str = ".yklabels";
 
 #[cfg(test)]
 mod tests {
-    use super::{BasicBlock, Body, BodyFlags, Decoder, Encoder, Pack, Statement, Terminator};
+    use super::{
+        BasicBlock, BinOp, Body, BodyFlags, CallOperand, CguHash, Constant, ConstantFloat,
+        ConstantInt, Decoder, Encoder, Fields, FloatTy, IRPlace, Local, LocalDecl, Pack, Ptr,
+        SignedInt, SignedIntTy, SirHeader, Statement, StructTy, Terminator, TupleTy, Ty, TyIndex,
+        TyKind, TypeId, UnsignedInt, UnsignedIntTy,
+    };
     use fallible_iterator::{self, FallibleIterator};
+    use proptest::{collection::vec, option, prelude::*, sample::select};
     use std::io::Cursor;
+    use test::{black_box, Bencher};
 
     // Makes some sample stuff to round trip test.
     fn get_sample_packs() -> Vec<Pack> {
@@ -115,4 +127,384 @@ mod tests {
         // We've consumed everything, so attempting to decode another pack should fail.
         assert!(itr.next().is_err());
     }
+
+    /// Serialise `packs` and check that deserialising them gives back the same packs.
+    fn round_trip(packs: Vec<Pack>) {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::from(&mut buf);
+        for p in &packs {
+            enc.serialise(p.clone()).unwrap();
+        }
+
+        let mut curs = Cursor::new(&mut buf);
+        let mut dec = Decoder::from(&mut curs);
+        for p in packs {
+            assert_eq!(dec.next().unwrap(), Some(p));
+        }
+        assert!(dec.next().is_err());
+    }
+
+    /// Makes `n` bodies, each with 10 blocks of 8 statements, for benchmarking.
+    fn bench_packs(n: usize) -> Vec<Pack> {
+        let ty = TypeId {
+            cgu: CguHash(0),
+            idx: TyIndex(0),
+        };
+        let val = |l| IRPlace::Val {
+            local: Local(l),
+            off: 0,
+            ty,
+        };
+        let stmts = (0..8)
+            .map(|l| Statement::BinaryOp {
+                dest: val(l),
+                op: BinOp::Add,
+                opnd1: val(l + 1),
+                opnd2: val(l + 2),
+                checked: true,
+            })
+            .collect::<Vec<_>>();
+        (0..n)
+            .map(|i| {
+                Pack::Body(Body {
+                    symbol_name: format!("symbol{}", i),
+                    blocks: (0..10)
+                        .map(|bb| BasicBlock::new(stmts.clone(), Terminator::Goto(bb + 1)))
+                        .collect(),
+                    flags: BodyFlags::empty(),
+                    local_decls: vec![LocalDecl::new(ty, false); 10],
+                    num_args: 0,
+                    layout: (0, 0),
+                    offsets: Vec::new(),
+                })
+            })
+            .collect()
+    }
+
+    #[bench]
+    fn bench_encode_100_bodies(b: &mut Bencher) {
+        let packs = bench_packs(100);
+        b.iter(|| {
+            let mut buf = Vec::new();
+            let mut enc = Encoder::from(&mut buf);
+            for p in &packs {
+                enc.serialise(p.clone()).unwrap();
+            }
+            enc.done().unwrap();
+            black_box(buf);
+        });
+    }
+
+    #[bench]
+    fn bench_decode_100_bodies(b: &mut Bencher) {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::from(&mut buf);
+        for p in bench_packs(100) {
+            enc.serialise(p).unwrap();
+        }
+        enc.done().unwrap();
+        b.iter(|| {
+            let mut curs = Cursor::new(&buf);
+            let dec = Decoder::from(&mut curs);
+            black_box(dec.collect::<Vec<_>>().unwrap());
+        });
+    }
+
+    // A length prefix far larger than the input used to make the decoder panic trying to allocate
+    // a huge buffer. This input is in the `fuzz_ykpack` fuzz target's seed corpus.
+    #[test]
+    fn huge_length() {
+        let data = [1, 1, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        let mut curs = Cursor::new(&data);
+        assert!(Decoder::from(&mut curs).next().is_err());
+    }
+
+    fn type_id() -> impl Strategy<Value = TypeId> {
+        (any::<u64>(), any::<u32>()).prop_map(|(cgu, idx)| TypeId {
+            cgu: CguHash(cgu),
+            idx: TyIndex(idx),
+        })
+    }
+
+    fn local() -> impl Strategy<Value = Local> {
+        any::<u32>().prop_map(Local)
+    }
+
+    fn constant() -> impl Strategy<Value = Constant> {
+        prop_oneof![
+            any::<usize>().prop_map(|v| ConstantInt::UnsignedInt(UnsignedInt::Usize(v))),
+            any::<u8>().prop_map(|v| ConstantInt::UnsignedInt(UnsignedInt::U8(v))),
+            any::<u16>().prop_map(|v| ConstantInt::UnsignedInt(UnsignedInt::U16(v))),
+            any::<u32>().prop_map(|v| ConstantInt::UnsignedInt(UnsignedInt::U32(v))),
+            any::<u64>().prop_map(|v| ConstantInt::UnsignedInt(UnsignedInt::U64(v))),
+            any::<u128>().prop_map(|v| ConstantInt::UnsignedInt(UnsignedInt::U128(v))),
+            any::<isize>().prop_map(|v| ConstantInt::SignedInt(SignedInt::Isize(v))),
+            any::<i8>().prop_map(|v| ConstantInt::SignedInt(SignedInt::I8(v))),
+            any::<i16>().prop_map(|v| ConstantInt::SignedInt(SignedInt::I16(v))),
+            any::<i32>().prop_map(|v| ConstantInt::SignedInt(SignedInt::I32(v))),
+            any::<i64>().prop_map(|v| ConstantInt::SignedInt(SignedInt::I64(v))),
+            any::<i128>().prop_map(|v| ConstantInt::SignedInt(SignedInt::I128(v))),
+        ]
+        .prop_map(Constant::Int)
+        .boxed()
+        .prop_union(
+            prop_oneof![
+                any::<u32>().prop_map(|v| Constant::Float(ConstantFloat::F32(v))),
+                any::<u64>().prop_map(|v| Constant::Float(ConstantFloat::F64(v))),
+                any::<bool>().prop_map(Constant::Bool),
+                type_id().prop_map(Constant::Tuple),
+                ".*".prop_map(Constant::Unimplemented),
+            ]
+            .boxed(),
+        )
+    }
+
+    fn place() -> impl Strategy<Value = IRPlace> {
+        prop_oneof![
+            (local(), any::<i32>(), type_id()).prop_map(|(local, off, ty)| IRPlace::Val {
+                local,
+                off,
+                ty
+            }),
+            (local(), any::<i32>(), any::<i32>(), type_id()).prop_map(
+                |(local, ptr_off, off, ty)| IRPlace::Indirect {
+                    ptr: Ptr {
+                        local,
+                        off: ptr_off
+                    },
+                    off,
+                    ty
+                }
+            ),
+            (constant(), type_id()).prop_map(|(val, ty)| IRPlace::Const { val, ty }),
+            ".*".prop_map(IRPlace::Unimplemented),
+        ]
+    }
+
+    fn binop() -> impl Strategy<Value = BinOp> {
+        select(vec![
+            BinOp::Add,
+            BinOp::Sub,
+            BinOp::Mul,
+            BinOp::Div,
+            BinOp::Rem,
+            BinOp::BitXor,
+            BinOp::BitAnd,
+            BinOp::BitOr,
+            BinOp::Shl,
+            BinOp::Shr,
+            BinOp::Eq,
+            BinOp::Lt,
+            BinOp::Le,
+            BinOp::Ne,
+            BinOp::Ge,
+            BinOp::Gt,
+            BinOp::Offset,
+        ])
+    }
+
+    fn call_operand() -> impl Strategy<Value = CallOperand> {
+        prop_oneof![".*".prop_map(CallOperand::Fn), Just(CallOperand::Unknown)]
+    }
+
+    fn statement() -> impl Strategy<Value = Statement> {
+        prop_oneof![
+            Just(Statement::Nop),
+            (place(), place()).prop_map(|(d, s)| Statement::Store(d, s)),
+            (place(), binop(), place(), place(), any::<bool>()).prop_map(
+                |(dest, op, opnd1, opnd2, checked)| Statement::BinaryOp {
+                    dest,
+                    op,
+                    opnd1,
+                    opnd2,
+                    checked
+                }
+            ),
+            (place(), place()).prop_map(|(d, s)| Statement::MkRef(d, s)),
+            (place(), place(), place(), any::<u32>()).prop_map(|(dest, base, idx, scale)| {
+                Statement::DynOffs {
+                    dest,
+                    base,
+                    idx,
+                    scale,
+                }
+            }),
+            local().prop_map(Statement::StorageLive),
+            local().prop_map(Statement::StorageDead),
+            (call_operand(), vec(place(), 0..4), option::of(place()))
+                .prop_map(|(op, args, dest)| Statement::Call(op, args, dest)),
+            (place(), place()).prop_map(|(d, s)| Statement::Cast(d, s)),
+            ".*".prop_map(Statement::Debug),
+            ".*".prop_map(Statement::Unimplemented),
+        ]
+    }
+
+    fn terminator() -> impl Strategy<Value = Terminator> {
+        prop_oneof![
+            any::<u32>().prop_map(Terminator::Goto),
+            (
+                place(),
+                vec(any::<u128>(), 0..8),
+                vec(any::<u32>(), 0..8),
+                any::<u32>()
+            )
+                .prop_map(|(discr, values, target_bbs, otherwise_bb)| {
+                    Terminator::SwitchInt {
+                        discr,
+                        values,
+                        target_bbs,
+                        otherwise_bb,
+                    }
+                }),
+            Just(Terminator::Return),
+            Just(Terminator::Unreachable),
+            (place(), any::<u32>()).prop_map(|(location, target_bb)| Terminator::Drop {
+                location,
+                target_bb
+            }),
+            (
+                call_operand(),
+                vec(place(), 0..4),
+                option::of((place(), any::<u32>()))
+            )
+                .prop_map(|(operand, args, destination)| Terminator::Call {
+                    operand,
+                    args,
+                    destination
+                }),
+            (".*", any::<u32>())
+                .prop_map(|(msg, destination)| Terminator::TraceDebugCall { msg, destination }),
+            (place(), any::<bool>(), any::<u32>()).prop_map(|(cond, expected, target_bb)| {
+                Terminator::Assert {
+                    cond,
+                    expected,
+                    target_bb,
+                }
+            }),
+            ".*".prop_map(Terminator::Unimplemented),
+        ]
+    }
+
+    fn body() -> impl Strategy<Value = Body> {
+        (
+            ".*",
+            vec(
+                (vec(statement(), 0..8), terminator())
+                    .prop_map(|(stmts, term)| BasicBlock::new(stmts, term)),
+                0..=64,
+            ),
+            any::<u8>().prop_map(BodyFlags::from_bits_truncate),
+            vec(
+                (type_id(), any::<bool>()).prop_map(|(ty, r)| LocalDecl::new(ty, r)),
+                0..16,
+            ),
+            any::<usize>(),
+            (any::<usize>(), any::<usize>()),
+            vec(any::<usize>(), 0..16),
+        )
+            .prop_map(
+                |(symbol_name, blocks, flags, local_decls, num_args, layout, offsets)| Body {
+                    symbol_name,
+                    blocks,
+                    flags,
+                    local_decls,
+                    num_args,
+                    layout,
+                    offsets,
+                },
+            )
+    }
+
+    fn fields() -> impl Strategy<Value = Fields> {
+        (vec(any::<i32>(), 0..8), vec(type_id(), 0..8))
+            .prop_map(|(offsets, tys)| Fields { offsets, tys })
+    }
+
+    fn ty() -> impl Strategy<Value = Ty> {
+        let kind = prop_oneof![
+            select(vec![
+                SignedIntTy::Isize,
+                SignedIntTy::I8,
+                SignedIntTy::I16,
+                SignedIntTy::I32,
+                SignedIntTy::I64,
+                SignedIntTy::I128,
+            ])
+            .prop_map(TyKind::SignedInt),
+            select(vec![
+                UnsignedIntTy::Usize,
+                UnsignedIntTy::U8,
+                UnsignedIntTy::U16,
+                UnsignedIntTy::U32,
+                UnsignedIntTy::U64,
+                UnsignedIntTy::U128,
+            ])
+            .prop_map(TyKind::UnsignedInt),
+            select(vec![FloatTy::F32, FloatTy::F64]).prop_map(TyKind::Float),
+            fields().prop_map(|fields| TyKind::Struct(StructTy { fields })),
+            fields().prop_map(|fields| TyKind::Tuple(TupleTy { fields })),
+            (type_id(), any::<usize>()).prop_map(|(elem_ty, len)| TyKind::Array { elem_ty, len }),
+            type_id().prop_map(TyKind::Slice),
+            type_id().prop_map(TyKind::Ref),
+            Just(TyKind::Bool),
+            Just(TyKind::Char),
+            ".*".prop_map(TyKind::Unimplemented),
+        ];
+        (any::<usize>(), any::<usize>(), kind).prop_map(|(size, align, kind)| Ty {
+            size,
+            align,
+            kind,
+        })
+    }
+
+    fn header() -> impl Strategy<Value = SirHeader> {
+        (
+            any::<u64>(),
+            vec(any::<usize>(), 0..16),
+            vec((".*", any::<usize>()), 0..16),
+        )
+            .prop_map(|(cgu_hash, types, bodies)| SirHeader {
+                cgu_hash: CguHash(cgu_hash),
+                types,
+                bodies: bodies.into_iter().collect(),
+            })
+    }
+
+    fn pack() -> impl Strategy<Value = Pack> {
+        prop_oneof![
+            header().prop_map(Pack::Header),
+            body().prop_map(Pack::Body),
+            ty().prop_map(Pack::Type),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(1000))]
+
+        #[test]
+        fn round_trip_packs(packs in vec(pack(), 1..3)) {
+            round_trip(packs);
+        }
+    }
+
+    // A `SwitchInt` with no targets other than `otherwise_bb` is valid, and must survive
+    // serialisation.
+    #[test]
+    fn round_trip_switchint_no_targets() {
+        let term = Terminator::SwitchInt {
+            discr: IRPlace::Unimplemented(String::new()),
+            values: Vec::new(),
+            target_bbs: Vec::new(),
+            otherwise_bb: 3,
+        };
+        round_trip(vec![Pack::Body(Body {
+            symbol_name: String::new(),
+            blocks: vec![BasicBlock::new(Vec::new(), term)],
+            flags: BodyFlags::empty(),
+            local_decls: Vec::new(),
+            num_args: 0,
+            layout: (0, 0),
+            offsets: Vec::new(),
+        })]);
+    }
 }
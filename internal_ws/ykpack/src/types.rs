@@ -88,6 +88,9 @@ pub enum TyKind {
     Char,
     /// Anything that we've not yet defined a lowering for.
     Unimplemented(String),
+    /// Floating point numbers. Appended last so as not to renumber the variants above in
+    /// serialised SIR.
+    Float(FloatTy),
 }
 
 impl Display for Ty {
@@ -95,6 +98,7 @@ impl Display for Ty {
         match &self.kind {
             TyKind::SignedInt(si) => write!(f, "{}", si),
             TyKind::UnsignedInt(ui) => write!(f, "{}", ui),
+            TyKind::Float(fty) => write!(f, "{}", fty),
             TyKind::Struct(sty) => write!(f, "{}", sty),
             TyKind::Tuple(tty) => write!(f, "{}", tty),
             TyKind::Array { elem_ty, len, .. } => write!(f, "[{}; {}]", elem_ty, len),
@@ -120,8 +124,33 @@ impl Ty {
         matches!(self.kind, TyKind::SignedInt(..))
     }
 
+    pub fn is_unsigned_int(&self) -> bool {
+        matches!(self.kind, TyKind::UnsignedInt(..))
+    }
+
     pub fn is_int(&self) -> bool {
-        matches!(self.kind, TyKind::SignedInt(..)) || matches!(self.kind, TyKind::UnsignedInt(..))
+        self.is_signed_int() || self.is_unsigned_int()
+    }
+
+    /// Returns the width in bits of an integer type, or `None` if `self` isn't an integer.
+    pub fn int_bits(&self) -> Option<u32> {
+        if self.is_int() {
+            Some(u32::try_from(self.size * 8).unwrap())
+        } else {
+            None
+        }
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self.kind, TyKind::Float(..))
+    }
+
+    pub fn is_ref(&self) -> bool {
+        matches!(self.kind, TyKind::Ref(..))
+    }
+
+    pub fn is_bool(&self) -> bool {
+        self.kind == TyKind::Bool
     }
 
     pub fn is_unit(&self) -> bool {
@@ -192,6 +221,33 @@ impl Display for UnsignedIntTy {
     }
 }
 
+/// Describes the various floating point types.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
+pub enum FloatTy {
+    F32,
+    F64,
+}
+
+impl FloatTy {
+    /// Returns the size of the floating point type in bytes.
+    pub fn size(&self) -> u64 {
+        match self {
+            Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+}
+
+impl Display for FloatTy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::F32 => "f32",
+            Self::F64 => "f64",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
 pub struct Fields {
     /// Field offsets.
@@ -200,6 +256,31 @@ pub struct Fields {
     pub tys: Vec<TypeId>,
 }
 
+impl Fields {
+    /// Returns the offset of field `idx`, or `None` if there is no such field.
+    pub fn offset(&self, idx: usize) -> Option<OffT> {
+        self.offsets.get(idx).copied()
+    }
+
+    /// Returns true if every field lies within the first `size` bytes and no two fields overlap.
+    /// Note that fields need not be laid out in the order they are declared. `size_of` returns the
+    /// size of a type.
+    pub fn well_formed(&self, size: usize, size_of: impl Fn(&TypeId) -> usize) -> bool {
+        if self.offsets.len() != self.tys.len() {
+            return false;
+        }
+        let mut spans = Vec::new();
+        for (off, ty) in self.offsets.iter().zip(&self.tys) {
+            match usize::try_from(*off) {
+                Ok(off) => spans.push((off, off + size_of(ty))),
+                Err(_) => return false,
+            }
+        }
+        spans.sort_unstable();
+        spans.windows(2).all(|w| w[0].1 <= w[1].0) && spans.iter().all(|(_, end)| *end <= size)
+    }
+}
+
 impl Display for Fields {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -243,6 +324,14 @@ pub struct StructTy {
     pub fields: Fields,
 }
 
+impl StructTy {
+    /// Returns the offset of field `idx` from the start of the struct, or `None` if there is no
+    /// such field.
+    pub fn field_offset(&self, idx: usize) -> Option<OffT> {
+        self.fields.offset(idx)
+    }
+}
+
 impl Display for StructTy {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "StructTy {{ {} }}", self.fields)
@@ -307,6 +396,38 @@ pub struct Body {
     pub offsets: Vec<usize>,
 }
 
+impl Body {
+    /// Returns the body's control flow graph as an adjacency list: element `i` holds the indices
+    /// of the successors of block `i`, without duplicates.
+    pub fn cfg(&self) -> Vec<Vec<usize>> {
+        self.blocks
+            .iter()
+            .map(|b| {
+                let mut succs = Vec::new();
+                for s in b.term.successors() {
+                    let s = usize::try_from(s).unwrap();
+                    if !succs.contains(&s) {
+                        succs.push(s);
+                    }
+                }
+                succs
+            })
+            .collect()
+    }
+
+    /// Returns the transpose of `cfg`: element `i` holds the indices of the predecessors of block
+    /// `i`, in ascending order.
+    pub fn cfg_predecessors(&self) -> Vec<Vec<usize>> {
+        let mut preds = vec![Vec::new(); self.blocks.len()];
+        for (bb, succs) in self.cfg().into_iter().enumerate() {
+            for s in succs {
+                preds[s].push(bb);
+            }
+        }
+        preds
+    }
+}
+
 impl Display for Body {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "symbol: {}", self.symbol_name)?;
@@ -339,6 +460,31 @@ impl BasicBlock {
     pub fn new(stmts: Vec<Statement>, term: Terminator) -> Self {
         Self { stmts, term }
     }
+
+    /// Returns the first statement of the block, or `None` if it has no statements.
+    pub fn first_stmt(&self) -> Option<&Statement> {
+        self.stmts.first()
+    }
+
+    /// Returns the last statement of the block, or `None` if it has no statements.
+    pub fn last_stmt(&self) -> Option<&Statement> {
+        self.stmts.last()
+    }
+
+    /// Returns the number of statements in the block (not counting the terminator).
+    pub fn stmts_len(&self) -> usize {
+        self.stmts.len()
+    }
+
+    /// Returns true if the block has no statements (it always has a terminator).
+    pub fn is_empty(&self) -> bool {
+        self.stmts.is_empty()
+    }
+
+    /// Returns an iterator over the block's statements.
+    pub fn iter_stmts(&self) -> impl Iterator<Item = &Statement> {
+        self.stmts.iter()
+    }
 }
 
 impl Display for BasicBlock {
@@ -499,6 +645,44 @@ pub enum Statement {
     Unimplemented(String),
 }
 
+impl Statement {
+    /// Returns the place that the statement writes to, if any.
+    pub fn dest(&self) -> Option<&IRPlace> {
+        match self {
+            Statement::Store(dest, _)
+            | Statement::BinaryOp { dest, .. }
+            | Statement::MkRef(dest, _)
+            | Statement::DynOffs { dest, .. }
+            | Statement::Cast(dest, _)
+            | Statement::Call(_, _, Some(dest)) => Some(dest),
+            Statement::Call(_, _, None)
+            | Statement::Nop
+            | Statement::StorageLive(_)
+            | Statement::StorageDead(_)
+            | Statement::Debug(_)
+            | Statement::Unimplemented(_) => None,
+        }
+    }
+
+    /// Returns the places that the statement reads from. Note that the destination is not
+    /// included, even if it is an indirect place (whose pointer is read).
+    pub fn operands(&self) -> Vec<&IRPlace> {
+        match self {
+            Statement::Store(_, src) | Statement::MkRef(_, src) | Statement::Cast(_, src) => {
+                vec![src]
+            }
+            Statement::BinaryOp { opnd1, opnd2, .. } => vec![opnd1, opnd2],
+            Statement::DynOffs { base, idx, .. } => vec![base, idx],
+            Statement::Call(_, args, _) => args.iter().collect(),
+            Statement::Nop
+            | Statement::StorageLive(_)
+            | Statement::StorageDead(_)
+            | Statement::Debug(_)
+            | Statement::Unimplemented(_) => Vec::new(),
+        }
+    }
+}
+
 impl Display for Statement {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -550,23 +734,36 @@ pub enum Constant {
     Bool(bool),
     Tuple(TypeId), // FIXME assumed to be unit for now. Needs a value in here.
     Unimplemented(String),
+    // Appended last so as not to renumber the variants above in serialised SIR.
+    Float(ConstantFloat),
 }
 
 impl Constant {
     pub fn i64_cast(&self) -> i64 {
         match self {
             Self::Int(ci) => ci.i64_cast(),
+            Self::Float(cf) => cf.to_bits() as i64,
             Self::Bool(b) => *b as i64,
             Self::Tuple(..) => unreachable!(),
             Self::Unimplemented(_) => unreachable!(),
         }
     }
+
+    /// Like `i64_cast`, but without losing the upper bits of 128-bit integers. Signed values are
+    /// not sign-extended.
+    pub fn u128_cast(&self) -> u128 {
+        match self {
+            Self::Int(ci) => ci.to_bits(),
+            _ => self.i64_cast() as u64 as u128,
+        }
+    }
 }
 
 impl Display for Constant {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Constant::Int(i) => write!(f, "{}", i),
+            Constant::Float(fl) => write!(f, "{}", fl),
             Constant::Bool(b) => write!(f, "{}", b),
             Constant::Tuple(..) => write!(f, "()"), // FIXME assumed unit.
             Constant::Unimplemented(s) => write!(f, "unimplemented constant: {:?}", s),
@@ -574,7 +771,43 @@ impl Display for Constant {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+/// A floating point constant. Floats aren't `Eq`, so we store the bit pattern of the value instead.
+/// This means that a NaN constant is equal to itself.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
+pub enum ConstantFloat {
+    F32(u32),
+    F64(u64),
+}
+
+impl ConstantFloat {
+    pub fn from_f32(v: f32) -> Self {
+        ConstantFloat::F32(v.to_bits())
+    }
+
+    pub fn from_f64(v: f64) -> Self {
+        ConstantFloat::F64(v.to_bits())
+    }
+
+    /// Returns the constant's bit pattern zero-extended to a u64.
+    pub fn to_bits(&self) -> u64 {
+        match self {
+            ConstantFloat::F32(b) => u64::from(*b),
+            ConstantFloat::F64(b) => *b,
+        }
+    }
+}
+
+impl Display for ConstantFloat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConstantFloat::F32(b) => write!(f, "{}f32", f32::from_bits(*b)),
+            ConstantFloat::F64(b) => write!(f, "{}f64", f64::from_bits(*b)),
+        }
+    }
+}
+
+/// Integers of the same type are ordered numerically, and integers of different types by type.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
 pub enum ConstantInt {
     UnsignedInt(UnsignedInt),
     SignedInt(SignedInt),
@@ -640,6 +873,128 @@ impl ConstantInt {
     const_int_from_bits!(i64_from_bits, i64, SignedInt, I64);
     const_int_from_bits!(i128_from_bits, i128, SignedInt, I128);
     const_int_from_bits!(isize_from_bits, isize, SignedInt, Isize);
+
+    /// Constructs a ConstantInt of the integer type `ty` from bits in u128 form. Returns `None` if
+    /// `ty` isn't an integer type.
+    pub fn from_bits(ty: &Ty, bits: u128) -> Option<Self> {
+        Some(match &ty.kind {
+            TyKind::UnsignedInt(ui) => match ui {
+                UnsignedIntTy::Usize => Self::usize_from_bits(bits),
+                UnsignedIntTy::U8 => Self::u8_from_bits(bits),
+                UnsignedIntTy::U16 => Self::u16_from_bits(bits),
+                UnsignedIntTy::U32 => Self::u32_from_bits(bits),
+                UnsignedIntTy::U64 => Self::u64_from_bits(bits),
+                UnsignedIntTy::U128 => Self::u128_from_bits(bits),
+            },
+            TyKind::SignedInt(si) => match si {
+                SignedIntTy::Isize => Self::isize_from_bits(bits),
+                SignedIntTy::I8 => Self::i8_from_bits(bits),
+                SignedIntTy::I16 => Self::i16_from_bits(bits),
+                SignedIntTy::I32 => Self::i32_from_bits(bits),
+                SignedIntTy::I64 => Self::i64_from_bits(bits),
+                SignedIntTy::I128 => Self::i128_from_bits(bits),
+            },
+            _ => return None,
+        })
+    }
+
+    /// Returns the constant's bit pattern zero-extended to a u128. This is the inverse of the
+    /// `*_from_bits` constructors and matches the encoding of `SwitchInt` values.
+    pub fn to_bits(&self) -> u128 {
+        match self {
+            ConstantInt::UnsignedInt(ui) => match ui {
+                UnsignedInt::U8(i) => u128::from(*i),
+                UnsignedInt::U16(i) => u128::from(*i),
+                UnsignedInt::U32(i) => u128::from(*i),
+                UnsignedInt::U64(i) => u128::from(*i),
+                UnsignedInt::Usize(i) => *i as u128,
+                UnsignedInt::U128(i) => *i,
+            },
+            ConstantInt::SignedInt(si) => match si {
+                SignedInt::I8(i) => u128::from(*i as u8),
+                SignedInt::I16(i) => u128::from(*i as u16),
+                SignedInt::I32(i) => u128::from(*i as u32),
+                SignedInt::I64(i) => u128::from(*i as u64),
+                SignedInt::Isize(i) => *i as usize as u128,
+                SignedInt::I128(i) => *i as u128,
+            },
+        }
+    }
+}
+
+/// Generate code which statically evaluates a binary operation on two integers of the same Rust
+/// type, returning an `Option<Constant>`. Arithmetic wraps, as is the case for unchecked SIR
+/// binary operations.
+macro_rules! fold_int_binop {
+    ($op: expr, $a: expr, $b: expr, $yk_t: ident, $yk_variant: ident) => {{
+        let (a, b) = ($a, $b);
+        let int = |v| Constant::Int(ConstantInt::$yk_t($yk_t::$yk_variant(v)));
+        match $op {
+            BinOp::Add => Some(int(a.wrapping_add(b))),
+            BinOp::Sub => Some(int(a.wrapping_sub(b))),
+            BinOp::Mul => Some(int(a.wrapping_mul(b))),
+            BinOp::Div => a.checked_div(b).map(int),
+            BinOp::Rem => a.checked_rem(b).map(int),
+            BinOp::BitXor => Some(int(a ^ b)),
+            BinOp::BitAnd => Some(int(a & b)),
+            BinOp::BitOr => Some(int(a | b)),
+            BinOp::Eq => Some(Constant::Bool(a == b)),
+            BinOp::Lt => Some(Constant::Bool(a < b)),
+            BinOp::Le => Some(Constant::Bool(a <= b)),
+            BinOp::Ne => Some(Constant::Bool(a != b)),
+            BinOp::Ge => Some(Constant::Bool(a >= b)),
+            BinOp::Gt => Some(Constant::Bool(a > b)),
+            // The operands of these needn't have the same type.
+            BinOp::Shl | BinOp::Shr | BinOp::Offset => None,
+        }
+    }};
+}
+
+impl ConstantInt {
+    /// Statically evaluate `self op other`. Returns `None` if the operation can't be evaluated,
+    /// e.g. because the operands have different types or because of a division by zero.
+    pub fn eval_binop(&self, op: BinOp, other: &Self) -> Option<Constant> {
+        use ConstantInt::{SignedInt as S, UnsignedInt as U};
+        match (self, other) {
+            (U(UnsignedInt::Usize(a)), U(UnsignedInt::Usize(b))) => {
+                fold_int_binop!(op, *a, *b, UnsignedInt, Usize)
+            }
+            (U(UnsignedInt::U8(a)), U(UnsignedInt::U8(b))) => {
+                fold_int_binop!(op, *a, *b, UnsignedInt, U8)
+            }
+            (U(UnsignedInt::U16(a)), U(UnsignedInt::U16(b))) => {
+                fold_int_binop!(op, *a, *b, UnsignedInt, U16)
+            }
+            (U(UnsignedInt::U32(a)), U(UnsignedInt::U32(b))) => {
+                fold_int_binop!(op, *a, *b, UnsignedInt, U32)
+            }
+            (U(UnsignedInt::U64(a)), U(UnsignedInt::U64(b))) => {
+                fold_int_binop!(op, *a, *b, UnsignedInt, U64)
+            }
+            (U(UnsignedInt::U128(a)), U(UnsignedInt::U128(b))) => {
+                fold_int_binop!(op, *a, *b, UnsignedInt, U128)
+            }
+            (S(SignedInt::Isize(a)), S(SignedInt::Isize(b))) => {
+                fold_int_binop!(op, *a, *b, SignedInt, Isize)
+            }
+            (S(SignedInt::I8(a)), S(SignedInt::I8(b))) => {
+                fold_int_binop!(op, *a, *b, SignedInt, I8)
+            }
+            (S(SignedInt::I16(a)), S(SignedInt::I16(b))) => {
+                fold_int_binop!(op, *a, *b, SignedInt, I16)
+            }
+            (S(SignedInt::I32(a)), S(SignedInt::I32(b))) => {
+                fold_int_binop!(op, *a, *b, SignedInt, I32)
+            }
+            (S(SignedInt::I64(a)), S(SignedInt::I64(b))) => {
+                fold_int_binop!(op, *a, *b, SignedInt, I64)
+            }
+            (S(SignedInt::I128(a)), S(SignedInt::I128(b))) => {
+                fold_int_binop!(op, *a, *b, SignedInt, I128)
+            }
+            _ => None,
+        }
+    }
 }
 
 impl Display for ConstantInt {
@@ -651,7 +1006,7 @@ impl Display for ConstantInt {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
 pub enum UnsignedInt {
     Usize(usize),
     U8(u8),
@@ -674,7 +1029,7 @@ impl Display for UnsignedInt {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
 pub enum SignedInt {
     Isize(isize),
     I8(i8),
@@ -763,6 +1118,34 @@ pub enum Terminator {
     Unimplemented(String), // FIXME will eventually disappear.
 }
 
+impl Terminator {
+    /// Returns the blocks which control may flow to after this terminator, in the order they
+    /// appear in the terminator.
+    pub fn successors(&self) -> Vec<BasicBlockIndex> {
+        match self {
+            Terminator::Goto(bb)
+            | Terminator::Drop { target_bb: bb, .. }
+            | Terminator::TraceDebugCall {
+                destination: bb, ..
+            }
+            | Terminator::Assert { target_bb: bb, .. } => vec![*bb],
+            Terminator::SwitchInt {
+                target_bbs,
+                otherwise_bb,
+                ..
+            } => {
+                let mut bbs = target_bbs.clone();
+                bbs.push(*otherwise_bb);
+                bbs
+            }
+            Terminator::Call { destination, .. } => destination.iter().map(|(_, bb)| *bb).collect(),
+            Terminator::Return | Terminator::Unreachable | Terminator::Unimplemented(_) => {
+                Vec::new()
+            }
+        }
+    }
+}
+
 impl Display for Terminator {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -924,7 +1307,12 @@ pub struct SirLabel {
 
 #[cfg(test)]
 mod tests {
-    use super::{ConstantInt, SignedInt, UnsignedInt};
+    use super::{
+        BasicBlock, BinOp, Body, BodyFlags, CallOperand, CguHash, Constant, ConstantFloat,
+        ConstantInt, Fields, FloatTy, IRPlace, Local, SignedInt, SignedIntTy, Statement, StructTy,
+        Terminator, TupleTy, Ty, TyIndex, TyKind, TypeId, UnsignedInt, UnsignedIntTy,
+    };
+    use std::collections::HashSet;
 
     #[test]
     fn const_u8_from_bits() {
@@ -960,4 +1348,335 @@ mod tests {
         let cst = ConstantInt::u128_from_bits(v as u128);
         assert_eq!(cst, ConstantInt::UnsignedInt(UnsignedInt::U128(v)));
     }
+
+    #[test]
+    fn const_to_bits() {
+        assert_eq!(ConstantInt::u8_from_bits(233).to_bits(), 233);
+        assert_eq!(ConstantInt::i8_from_bits(-1i8 as u128).to_bits(), 0xff);
+        assert_eq!(
+            ConstantInt::i32_from_bits(-42i32 as u128).to_bits(),
+            -42i32 as u32 as u128
+        );
+        assert_eq!(
+            ConstantInt::i128_from_bits(-7i128 as u128).to_bits(),
+            -7i128 as u128
+        );
+    }
+
+    #[test]
+    fn float_ty() {
+        let ty = Ty {
+            size: 8,
+            align: 8,
+            kind: TyKind::Float(FloatTy::F64),
+        };
+        assert!(ty.is_float());
+        assert!(!ty.is_int());
+        assert_eq!(format!("{}", ty), "f64");
+    }
+
+    #[test]
+    fn const_eval_binop() {
+        let u8c = |v: u8| ConstantInt::u8_from_bits(u128::from(v));
+        let i32c = |v: i32| ConstantInt::i32_from_bits(v as u128);
+        let eval = |a: &ConstantInt, op, b: &ConstantInt| a.eval_binop(op, b);
+
+        assert_eq!(
+            eval(&u8c(250), BinOp::Add, &u8c(10)),
+            Some(Constant::Int(u8c(4)))
+        );
+        assert_eq!(
+            eval(&u8c(3), BinOp::Sub, &u8c(5)),
+            Some(Constant::Int(u8c(254)))
+        );
+        assert_eq!(
+            eval(&i32c(-6), BinOp::Mul, &i32c(7)),
+            Some(Constant::Int(i32c(-42)))
+        );
+        assert_eq!(
+            eval(&i32c(-7), BinOp::Div, &i32c(2)),
+            Some(Constant::Int(i32c(-3)))
+        );
+        assert_eq!(
+            eval(&i32c(-7), BinOp::Rem, &i32c(2)),
+            Some(Constant::Int(i32c(-1)))
+        );
+        assert_eq!(
+            eval(&u8c(0b1100), BinOp::BitXor, &u8c(0b1010)),
+            Some(Constant::Int(u8c(0b0110)))
+        );
+        assert_eq!(
+            eval(&u8c(0b1100), BinOp::BitAnd, &u8c(0b1010)),
+            Some(Constant::Int(u8c(0b1000)))
+        );
+        assert_eq!(
+            eval(&u8c(0b1100), BinOp::BitOr, &u8c(0b1010)),
+            Some(Constant::Int(u8c(0b1110)))
+        );
+        assert_eq!(
+            eval(&i32c(-1), BinOp::Lt, &i32c(1)),
+            Some(Constant::Bool(true))
+        );
+        assert_eq!(
+            eval(&u8c(255), BinOp::Le, &u8c(1)),
+            Some(Constant::Bool(false))
+        );
+        assert_eq!(
+            eval(&u8c(2), BinOp::Gt, &u8c(1)),
+            Some(Constant::Bool(true))
+        );
+        assert_eq!(
+            eval(&u8c(2), BinOp::Ge, &u8c(2)),
+            Some(Constant::Bool(true))
+        );
+        assert_eq!(
+            eval(&u8c(2), BinOp::Eq, &u8c(2)),
+            Some(Constant::Bool(true))
+        );
+        assert_eq!(
+            eval(&u8c(2), BinOp::Ne, &u8c(2)),
+            Some(Constant::Bool(false))
+        );
+
+        // Things we can't (or won't) fold.
+        assert_eq!(eval(&u8c(1), BinOp::Div, &u8c(0)), None);
+        assert_eq!(eval(&i32c(i32::MIN), BinOp::Div, &i32c(-1)), None);
+        assert_eq!(eval(&u8c(1), BinOp::Shl, &u8c(1)), None);
+        assert_eq!(eval(&u8c(1), BinOp::Add, &i32c(1)), None);
+    }
+
+    #[test]
+    fn const_float() {
+        let c = ConstantFloat::from_f64(1.5);
+        assert_eq!(c.to_bits(), 1.5f64.to_bits());
+        assert_eq!(format!("{}", c), "1.5f64");
+        assert_eq!(format!("{}", ConstantFloat::from_f32(-0.25)), "-0.25f32");
+        assert_eq!(Constant::Float(c).i64_cast(), 1.5f64.to_bits() as i64);
+
+        // Constants are compared by bit pattern, so NaNs are equal to themselves and a set of
+        // constants holds only one copy of a NaN.
+        let nan = ConstantFloat::from_f64(f64::NAN);
+        assert_eq!(nan, ConstantFloat::from_f64(f64::NAN));
+        let mut set = HashSet::new();
+        set.insert(nan.clone());
+        set.insert(nan);
+        assert_eq!(set.len(), 1);
+        assert_ne!(ConstantFloat::from_f32(0.0), ConstantFloat::from_f32(-0.0));
+    }
+
+    fn body(terms: Vec<Terminator>) -> Body {
+        Body {
+            symbol_name: String::from("f"),
+            blocks: terms
+                .into_iter()
+                .map(|t| BasicBlock::new(Vec::new(), t))
+                .collect(),
+            flags: BodyFlags::empty(),
+            local_decls: Vec::new(),
+            num_args: 0,
+            layout: (0, 0),
+            offsets: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn cfg_loop() {
+        let b = body(vec![
+            Terminator::Goto(1),
+            Terminator::Call {
+                operand: CallOperand::Unknown,
+                args: Vec::new(),
+                destination: None,
+            },
+            Terminator::Goto(2),
+        ]);
+        assert_eq!(b.cfg(), vec![vec![1], vec![], vec![2]]);
+        assert_eq!(b.cfg_predecessors(), vec![vec![], vec![0], vec![2]]);
+    }
+
+    #[test]
+    fn cfg_switch() {
+        let discr = IRPlace::Unimplemented(String::new());
+        let b = body(vec![
+            Terminator::SwitchInt {
+                discr,
+                values: vec![0, 1, 2],
+                target_bbs: vec![1, 2, 1],
+                otherwise_bb: 3,
+            },
+            Terminator::Goto(3),
+            Terminator::Return,
+            Terminator::Goto(0),
+        ]);
+        assert_eq!(b.cfg(), vec![vec![1, 2, 3], vec![3], vec![], vec![0]]);
+        assert_eq!(
+            b.cfg_predecessors(),
+            vec![vec![3], vec![0], vec![0], vec![0, 1]]
+        );
+    }
+
+    #[test]
+    fn const_int_ordering() {
+        let mut cs = vec![
+            ConstantInt::i64_from_bits(5),
+            ConstantInt::u8_from_bits(200),
+            ConstantInt::i64_from_bits(-3i64 as u128),
+            ConstantInt::u8_from_bits(7),
+            ConstantInt::u64_from_bits(1),
+        ];
+        cs.sort();
+        assert_eq!(
+            cs,
+            vec![
+                ConstantInt::u8_from_bits(7),
+                ConstantInt::u8_from_bits(200),
+                ConstantInt::u64_from_bits(1),
+                ConstantInt::i64_from_bits(-3i64 as u128),
+                ConstantInt::i64_from_bits(5),
+            ]
+        );
+        for c in &cs {
+            assert!(cs.binary_search(c).is_ok());
+        }
+        assert!(cs.binary_search(&ConstantInt::u8_from_bits(8)).is_err());
+    }
+
+    #[test]
+    fn float_sizes() {
+        assert_eq!(FloatTy::F32.size(), 4);
+        assert_eq!(FloatTy::F64.size(), 8);
+    }
+
+    #[test]
+    fn ty_predicates() {
+        let tyid = TypeId {
+            cgu: CguHash(0),
+            idx: TyIndex(0),
+        };
+        let ty = |size, kind| Ty {
+            size,
+            align: 1,
+            kind,
+        };
+        let no_fields = || Fields {
+            offsets: Vec::new(),
+            tys: Vec::new(),
+        };
+        let preds: [(&str, fn(&Ty) -> bool); 6] = [
+            ("signed", Ty::is_signed_int),
+            ("unsigned", Ty::is_unsigned_int),
+            ("float", Ty::is_float),
+            ("ref", Ty::is_ref),
+            ("bool", Ty::is_bool),
+            ("unit", Ty::is_unit),
+        ];
+        let tys = vec![
+            (
+                ty(2, TyKind::SignedInt(SignedIntTy::I16)),
+                Some("signed"),
+                Some(16),
+            ),
+            (
+                ty(8, TyKind::UnsignedInt(UnsignedIntTy::U64)),
+                Some("unsigned"),
+                Some(64),
+            ),
+            (ty(4, TyKind::Float(FloatTy::F32)), Some("float"), None),
+            (ty(8, TyKind::Ref(tyid)), Some("ref"), None),
+            (ty(1, TyKind::Bool), Some("bool"), None),
+            (
+                ty(
+                    0,
+                    TyKind::Tuple(TupleTy {
+                        fields: no_fields(),
+                    }),
+                ),
+                Some("unit"),
+                None,
+            ),
+            (ty(4, TyKind::Char), None, None),
+            (ty(16, TyKind::Slice(tyid)), None, None),
+            (
+                ty(
+                    4,
+                    TyKind::Array {
+                        elem_ty: tyid,
+                        len: 4,
+                    },
+                ),
+                None,
+                None,
+            ),
+            (
+                ty(
+                    0,
+                    TyKind::Struct(StructTy {
+                        fields: no_fields(),
+                    }),
+                ),
+                None,
+                None,
+            ),
+            (ty(0, TyKind::Unimplemented(String::new())), None, None),
+        ];
+        for (t, kind, bits) in tys {
+            for (name, pred) in &preds {
+                assert_eq!(pred(&t), kind == Some(*name), "{} {}", name, t);
+            }
+            assert_eq!(t.is_int(), bits.is_some());
+            assert_eq!(t.int_bits(), bits);
+        }
+    }
+
+    #[test]
+    fn block_stmts() {
+        let stmts = vec![
+            Statement::StorageLive(Local(1)),
+            Statement::Nop,
+            Statement::StorageDead(Local(1)),
+        ];
+        let bb = BasicBlock::new(stmts.clone(), Terminator::Return);
+        assert_eq!(bb.first_stmt(), Some(&stmts[0]));
+        assert_eq!(bb.last_stmt(), Some(&stmts[2]));
+        assert_eq!(bb.stmts_len(), 3);
+        assert!(!bb.is_empty());
+        assert!(bb.iter_stmts().eq(stmts.iter()));
+
+        let bb = BasicBlock::new(Vec::new(), Terminator::Return);
+        assert_eq!(bb.first_stmt(), None);
+        assert_eq!(bb.last_stmt(), None);
+        assert_eq!(bb.stmts_len(), 0);
+        assert!(bb.is_empty());
+        assert_eq!(bb.iter_stmts().count(), 0);
+    }
+
+    #[test]
+    fn struct_fields() {
+        let tyid = |idx| TypeId {
+            cgu: CguHash(0),
+            idx: TyIndex(idx),
+        };
+        // Type index `i` has size `i`.
+        let size_of = |t: &TypeId| t.idx.0 as usize;
+
+        // struct { a: 4 bytes, b: 2 bytes }, laid out with b first.
+        let st = StructTy {
+            fields: Fields {
+                offsets: vec![4, 0],
+                tys: vec![tyid(4), tyid(2)],
+            },
+        };
+        assert_eq!(st.field_offset(0), Some(4));
+        assert_eq!(st.field_offset(1), Some(0));
+        assert_eq!(st.field_offset(2), None);
+        assert!(st.fields.well_formed(8, size_of));
+        assert!(!st.fields.well_formed(7, size_of));
+
+        // Overlapping fields.
+        let fields = Fields {
+            offsets: vec![0, 2],
+            tys: vec![tyid(4), tyid(2)],
+        };
+        assert!(!fields.well_formed(8, size_of));
+    }
 }
@@ -3,8 +3,10 @@
 //! For more information, see this section in the documentation:
 //! https://softdevteam.github.io/ykdocs/tech/yk_structure.html
 
+#[cfg(feature = "yk_testing")]
 mod test_api;
 
+use std::convert::TryFrom;
 use std::ffi::{c_void, CString};
 use std::os::raw::c_char;
 use ykcompile::CompiledTrace;
@@ -38,6 +40,21 @@ unsafe extern "C" fn __ykshim_start_tracing(tracing_kind: u8) -> *mut ThreadTrac
     Box::into_raw(Box::new(tracer))
 }
 
+/// Like `__ykshim_start_tracing`, but limits the trace to `max_blocks` blocks.
+#[no_mangle]
+unsafe extern "C" fn __ykshim_start_tracing_with_budget(
+    tracing_kind: u8,
+    max_blocks: usize,
+) -> *mut ThreadTracer {
+    let tracing_kind = match tracing_kind {
+        0 => yktrace::TracingKind::SoftwareTracing,
+        1 => yktrace::TracingKind::HardwareTracing,
+        _ => return std::ptr::null_mut(),
+    };
+    let tracer = yktrace::start_tracing_with_budget(tracing_kind, max_blocks);
+    Box::into_raw(Box::new(tracer))
+}
+
 /// Stops tracing, consuming the ThreadTracer and returning an opaque pointer to a SirTrace. If an
 /// error occurs then the returned pointer will be NULL and `error_msg` will contain details of the
 /// error.
@@ -57,6 +74,25 @@ unsafe extern "C" fn __ykshim_stop_tracing(
     }
 }
 
+/// Returns whether the current thread is being traced.
+#[no_mangle]
+extern "C" fn __ykshim_is_tracing() -> bool {
+    yktrace::is_tracing()
+}
+
+/// Returns the number of tracers active on the current thread.
+#[no_mangle]
+extern "C" fn __ykshim_tracing_depth() -> usize {
+    yktrace::tracing_depth()
+}
+
+/// Installs the panic hook which writes out the trace being compiled if `YKD_DUMP_TRACE_ON_ABORT`
+/// is set.
+#[no_mangle]
+extern "C" fn __ykshim_install_trace_dump_hook() {
+    ykcompile::install_trace_dump_hook()
+}
+
 /// Compiles a SIR trace into an opaque pointer to a native code trace. If an error occurs, the
 /// returned pointer will be null, and `error_msg` will contain details of the error.
 #[no_mangle]
@@ -72,8 +108,13 @@ unsafe extern "C" fn __ykshim_compile_trace(
             return std::ptr::null_mut();
         }
     };
-    let compiled_trace = ykcompile::compile_trace(tt);
-    Box::into_raw(Box::new(compiled_trace))
+    match ykcompile::compile_trace(tt) {
+        Ok(compiled_trace) => Box::into_raw(Box::new(compiled_trace)),
+        Err(err) => {
+            *error_msg = CString::new(err.to_string()).unwrap().into_raw();
+            std::ptr::null_mut()
+        }
+    }
 }
 
 /// Gets a callable function pointer from a compiled trace.
@@ -85,12 +126,135 @@ unsafe extern "C" fn __ykshim_compiled_trace_get_ptr(
     compiled_trace.ptr() as *const c_void
 }
 
+/// Gets the size, in bytes, of a compiled trace's machine code.
+#[no_mangle]
+unsafe extern "C" fn __ykshim_compiled_trace_code_size(
+    compiled_trace: *const CompiledTrace,
+) -> usize {
+    let compiled_trace = &*(compiled_trace as *mut CompiledTrace);
+    compiled_trace.code_size()
+}
+
+/// Gets the number of ops of each kind in the TIR trace a compiled trace was built from, as a
+/// string with one `<kind> <count>` line per kind. The string must be freed by the caller.
+#[no_mangle]
+unsafe extern "C" fn __ykshim_compiled_trace_op_counts(
+    compiled_trace: *const CompiledTrace,
+) -> *mut c_char {
+    let compiled_trace = &*(compiled_trace as *mut CompiledTrace);
+    let counts = compiled_trace
+        .op_counts()
+        .iter()
+        .map(|(kind, count)| format!("{} {}\n", kind, count))
+        .collect::<String>();
+    CString::new(counts).unwrap().into_raw()
+}
+
+/// Gets the number of guards in a compiled trace.
+#[no_mangle]
+unsafe extern "C" fn __ykshim_compiled_trace_guard_count(
+    compiled_trace: *const CompiledTrace,
+) -> usize {
+    let compiled_trace = &*(compiled_trace as *mut CompiledTrace);
+    compiled_trace.guard_failures().len()
+}
+
+/// Writes the number of times each of a compiled trace's guards has failed into `counts`, which
+/// must have room for (at least) `__ykshim_compiled_trace_guard_count` elements.
+#[no_mangle]
+unsafe extern "C" fn __ykshim_compiled_trace_guard_failures(
+    compiled_trace: *const CompiledTrace,
+    counts: *mut u64,
+) {
+    let compiled_trace = &*(compiled_trace as *mut CompiledTrace);
+    let failures = compiled_trace.guard_failures();
+    std::ptr::copy_nonoverlapping(failures.as_ptr(), counts, failures.len());
+}
+
+/// Returns the offset, in bytes, of the first instruction of the `guard`th guard in a compiled
+/// trace, or -1 if there is no such guard.
+#[no_mangle]
+unsafe extern "C" fn __ykshim_compiled_trace_guard_offset(
+    compiled_trace: *const CompiledTrace,
+    guard: usize,
+) -> isize {
+    let compiled_trace = &*(compiled_trace as *mut CompiledTrace);
+    match compiled_trace.find_guard_offset(guard) {
+        Some(off) => isize::try_from(off).unwrap(),
+        None => -1,
+    }
+}
+
+/// Disassembles the machine code between byte offsets `start` and `end` of a compiled trace. If an
+/// error occurs, the returned pointer will be null, and `error_msg` will contain details of the
+/// error.
+#[no_mangle]
+unsafe extern "C" fn __ykshim_compiled_trace_disassemble_range(
+    compiled_trace: *const CompiledTrace,
+    start: usize,
+    end: usize,
+    error_msg: *mut *mut c_char,
+) -> *mut c_char {
+    let compiled_trace = &*(compiled_trace as *mut CompiledTrace);
+    match compiled_trace.disassemble_range(start, end) {
+        Ok(asm) => CString::new(asm).unwrap().into_raw(),
+        Err(err) => {
+            *error_msg = CString::new(err.to_string()).unwrap().into_raw();
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Gets a pointer to the code which returns from a compiled trace as if no guard had failed.
+#[no_mangle]
+unsafe extern "C" fn __ykshim_compiled_trace_exit_ptr(
+    compiled_trace: *const CompiledTrace,
+) -> *const c_void {
+    let compiled_trace = &*(compiled_trace as *mut CompiledTrace);
+    compiled_trace.exit_ptr() as *const c_void
+}
+
+/// Patches the `guard`th guard of a compiled trace to jump to `target` when it fails. Returns
+/// false, with `error_msg` containing details of the error, if the guard can't be patched.
+#[no_mangle]
+unsafe extern "C" fn __ykshim_compiled_trace_patch_guard(
+    compiled_trace: *const CompiledTrace,
+    guard: usize,
+    target: *const c_void,
+    error_msg: *mut *mut c_char,
+) -> bool {
+    let compiled_trace = &*(compiled_trace as *mut CompiledTrace);
+    match compiled_trace.patch_guard(guard, target as *const u8) {
+        Ok(()) => true,
+        Err(err) => {
+            *error_msg = CString::new(err.to_string()).unwrap().into_raw();
+            false
+        }
+    }
+}
+
 /// Drop a compiled trace.
 #[no_mangle]
 unsafe extern "C" fn __ykshim_compiled_trace_drop(compiled_trace: *mut CompiledTrace) {
     Box::from_raw(compiled_trace);
 }
 
+/// Returns a hash of the sequence of blocks in a SIR trace.
+#[no_mangle]
+unsafe extern "C" fn __ykshim_sirtrace_block_hash(trace: *const SirTrace) -> u64 {
+    (&*trace).block_hash()
+}
+
+/// Returns the sequence of blocks in a SIR trace, one `<symbol name> <block index>` per line.
+#[no_mangle]
+unsafe extern "C" fn __ykshim_sirtrace_blocks(trace: *const SirTrace) -> *mut c_char {
+    let blocks = (&*trace)
+        .iter()
+        .map(|loc| format!("{} {}\n", loc.symbol_name, loc.bb_idx))
+        .collect::<String>();
+    CString::new(blocks).unwrap().into_raw()
+}
+
 /// Drop a SIR trace.
 #[no_mangle]
 unsafe extern "C" fn __ykshim_sirtrace_drop(trace: *mut SirTrace) {
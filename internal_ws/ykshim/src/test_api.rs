@@ -7,9 +7,9 @@ use std::convert::TryFrom;
 use std::default::Default;
 use std::ffi::{c_void, CStr, CString};
 use std::os::raw::c_char;
-use std::ptr;
+use std::{ptr, slice};
 
-use ykcompile::{find_symbol, CompiledTrace, TraceCompiler, REG_POOL};
+use ykcompile::{find_symbol, CompileOptions, CompiledTrace, TraceCompiler, REG_POOL};
 use ykpack::{self, Local, LocalDecl, TypeId};
 use yksg::StopgapInterpreter;
 use yktrace::sir::{self, SirTrace, SIR};
@@ -98,6 +98,24 @@ unsafe extern "C" fn __ykshimtest_tracecompiler_local_to_location_str(
     CString::new(rstr.as_str()).unwrap().into_raw()
 }
 
+/// Tell a TraceCompiler's register allocator the index of the TIR op at which a local dies.
+#[no_mangle]
+unsafe extern "C" fn __ykshimtest_tracecompiler_set_last_use(
+    tc: *mut TraceCompiler,
+    local: Local,
+    idx: usize,
+) {
+    let tc = &mut *tc;
+    tc.last_uses.insert(local, idx);
+}
+
+/// Inform a TraceCompiler's register allocator that a local variable is live.
+#[no_mangle]
+unsafe extern "C" fn __ykshimtest_tracecompiler_local_live(tc: *mut TraceCompiler, local: Local) {
+    let tc = &mut *tc;
+    tc.local_live(&local);
+}
+
 /// Inform a TraceCompiler's register allocator that a local variable is dead.
 #[no_mangle]
 unsafe extern "C" fn __ykshimtest_tracecompiler_local_dead(tc: *mut TraceCompiler, local: Local) {
@@ -126,11 +144,50 @@ unsafe extern "C" fn __ykshimtest_reg_pool_size() -> usize {
     REG_POOL.len()
 }
 
+/// Consumes and compiles the given TIR trace to native code using the given options, returning an
+/// opaque pointer to the compiled trace. If `reg_pool` is null, the default register pool is used.
+/// If `max_stack_bytes` is negative, the stack size is unlimited. If `loop_iterations` is negative,
+/// the trace doesn't loop. If an error occurs, the returned pointer will be null and `error_msg`
+/// will contain details of the error.
+#[no_mangle]
+unsafe extern "C" fn __ykshimtest_compile_tir_trace_with_options(
+    tir_trace: *mut TirTrace,
+    reg_pool: *const u8,
+    reg_pool_len: usize,
+    max_stack_bytes: i64,
+    loop_iterations: i64,
+    error_msg: *mut *mut c_char,
+) -> *mut CompiledTrace {
+    let tir_trace = Box::from_raw(tir_trace);
+    let mut opts = CompileOptions::default();
+    if !reg_pool.is_null() {
+        opts.register_pool = slice::from_raw_parts(reg_pool, reg_pool_len).to_vec();
+    }
+    opts.max_stack_bytes = u32::try_from(max_stack_bytes).ok();
+    opts.loop_iterations = u32::try_from(loop_iterations).ok();
+    match ykcompile::compile_trace_with_options(*tir_trace, &opts) {
+        Ok(compiled_trace) => Box::into_raw(Box::new(compiled_trace)),
+        Err(err) => {
+            *error_msg = CString::new(err.to_string()).unwrap().into_raw();
+            ptr::null_mut()
+        }
+    }
+}
+
 /// Consumes and compiles the given TIR trace to native code, returning an opaque pointer to the
-/// compiled trace.
+/// compiled trace. If an error occurs, the returned pointer will be null and `error_msg` will
+/// contain details of the error.
 #[no_mangle]
-fn __ykshimtest_compile_tir_trace(tir_trace: *mut TirTrace) -> *mut CompiledTrace {
-    let tir_trace = unsafe { Box::from_raw(tir_trace) };
-    let compiled_trace = ykcompile::compile_trace(*tir_trace);
-    Box::into_raw(Box::new(compiled_trace))
+unsafe extern "C" fn __ykshimtest_compile_tir_trace(
+    tir_trace: *mut TirTrace,
+    error_msg: *mut *mut c_char,
+) -> *mut CompiledTrace {
+    let tir_trace = Box::from_raw(tir_trace);
+    match ykcompile::compile_trace(*tir_trace) {
+        Ok(compiled_trace) => Box::into_raw(Box::new(compiled_trace)),
+        Err(err) => {
+            *error_msg = CString::new(err.to_string()).unwrap().into_raw();
+            ptr::null_mut()
+        }
+    }
 }
@@ -1,8 +1,9 @@
 //! Errors that can occur during tracing.
 
 use std::fmt::{self, Display, Formatter};
+use ykpack::Local;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 /// Reasons that a trace can be invalidated.
 pub enum InvalidTraceError {
     /// An empty trace was recorded.
@@ -11,7 +12,15 @@ pub enum InvalidTraceError {
     InternalError,
     /// There is no SIR for a location in the trace.
     /// The string inside is the binary symbol name in which the location appears.
-    NoSir(String)
+    NoSir(String),
+    /// The trace exceeded its block budget.
+    TraceTooLong,
+    /// The trace is structurally invalid, indicating a bug in the tracer.
+    Malformed(TraceValidationError),
+    /// After its first loop closed, the trace returned to its first block again, at the location
+    /// with the index inside. This suggests that the tracer wrapped around or recorded corrupted
+    /// data.
+    CycleDetected(usize)
 }
 
 impl InvalidTraceError {
@@ -29,6 +38,73 @@ impl Display for InvalidTraceError {
             InvalidTraceError::NoSir(symbol_name) => {
                 write!(f, "No SIR for location in symbol: {}", symbol_name)
             }
+            InvalidTraceError::TraceTooLong => write!(f, "Trace too long"),
+            InvalidTraceError::Malformed(e) => write!(f, "Malformed trace: {}", e),
+            InvalidTraceError::CycleDetected(idx) => {
+                write!(f, "Cycle detected at location {}", idx)
+            }
+        }
+    }
+}
+
+/// Reasons that a SIR trace can fail validation. Each variant (other than `EmptyTrace`) records
+/// the index of the offending location in the trace.
+#[derive(Debug, PartialEq)]
+pub enum TraceValidationError {
+    /// The trace contains no locations.
+    EmptyTrace,
+    /// A block immediately follows itself, but it can't branch to itself.
+    RepeatedBlock { idx: usize },
+    /// A location's block index is out of bounds for its body.
+    InvalidBlock { idx: usize }
+}
+
+impl Display for TraceValidationError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            TraceValidationError::EmptyTrace => write!(f, "empty trace"),
+            TraceValidationError::RepeatedBlock { idx } => {
+                write!(f, "location {}: block repeated without a self-edge", idx)
+            }
+            TraceValidationError::InvalidBlock { idx } => {
+                write!(f, "location {}: no such block", idx)
+            }
+        }
+    }
+}
+
+/// Reasons that a TIR trace can fail verification. Each variant records the index of the offending
+/// operation in the trace.
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    /// A local is used which has no declaration.
+    UndeclaredLocal { idx: usize, local: Local },
+    /// A local is used after being marked dead (and without being marked live again).
+    UseAfterDead { idx: usize, local: Local },
+    /// The operands of a binary operation have different types.
+    BinOpTypeMismatch { idx: usize },
+    /// A guard's value has a type unsuitable for the guard's kind.
+    GuardType { idx: usize },
+    /// The source and destination of a store differ in size.
+    StoreSizeMismatch { idx: usize }
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            VerifyError::UndeclaredLocal { idx, local } => {
+                write!(f, "op {}: use of undeclared local {}", idx, local)
+            }
+            VerifyError::UseAfterDead { idx, local } => {
+                write!(f, "op {}: use of dead local {}", idx, local)
+            }
+            VerifyError::BinOpTypeMismatch { idx } => {
+                write!(f, "op {}: binary operation on mismatched types", idx)
+            }
+            VerifyError::GuardType { idx } => write!(f, "op {}: guard value has wrong type", idx),
+            VerifyError::StoreSizeMismatch { idx } => {
+                write!(f, "op {}: store between places of different sizes", idx)
+            }
         }
     }
 }
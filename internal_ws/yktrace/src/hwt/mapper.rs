@@ -1,14 +1,14 @@
 //! The mapper loads SIR location labels from the executable and provides a way to map virtual
 //! block addresses to SIR locations, i.e. symbol name and block index.
 
-use object::{Object, ObjectSection};
+use object::{Object, ObjectSection, ObjectSymbol};
 use phdrs::objects;
 
 use crate::sir::SirLoc;
 use hwtracer::{HWTracerError, Trace};
 use intervaltree::IntervalTree;
 use lazy_static::lazy_static;
-use std::{convert::TryFrom, env, fs, iter::FromIterator};
+use std::{cmp::Ordering, collections::HashSet, convert::TryFrom, env, fs, iter::FromIterator};
 use ykpack::SirLabel;
 
 lazy_static! {
@@ -22,6 +22,37 @@ lazy_static! {
     ///
     /// FIXME if we want to support dlopen(), we will have to rethink this.
     static ref LABELS: IntervalTree<usize, SirLabel> = load_labels();
+
+    /// The address ranges of the functions which contain SIR location labels. An address outside
+    /// of these ranges can't be mapped to a SIR location.
+    static ref RANGES: AddressRanges = load_ranges();
+}
+
+/// A set of disjoint `[start, end)` address ranges, supporting fast membership queries.
+pub struct AddressRanges(Vec<(u64, u64)>);
+
+impl AddressRanges {
+    /// Create a set from `ranges`, which must not overlap.
+    pub fn new(mut ranges: Vec<(u64, u64)>) -> Self {
+        ranges.sort_unstable();
+        debug_assert!(ranges.windows(2).all(|w| w[0].1 <= w[1].0));
+        AddressRanges(ranges)
+    }
+
+    /// Returns true if `addr` falls within one of the ranges.
+    pub fn contains(&self, addr: u64) -> bool {
+        self.0
+            .binary_search_by(|&(start, end)| {
+                if end <= addr {
+                    Ordering::Less
+                } else if start > addr {
+                    Ordering::Greater
+                } else {
+                    Ordering::Equal
+                }
+            })
+            .is_ok()
+    }
 }
 
 pub struct HWTMapper {
@@ -34,6 +65,16 @@ impl HWTMapper {
         HWTMapper { phdr_offset }
     }
 
+    /// Returns true if the virtual address `addr` lies within a function containing SIR location
+    /// labels. This is much cheaper than a full lookup and allows addresses which can't possibly
+    /// be mapped to a SIR location to be discarded early.
+    fn contains_address(&self, addr: u64) -> bool {
+        match addr.checked_sub(self.phdr_offset) {
+            Some(off) => RANGES.contains(off),
+            None => false
+        }
+    }
+
     /// Maps each entry of a hardware trace to the appropriate SirLoc.
     ///
     /// For each block in the trace, the interval tree is queried for labels coinciding with the
@@ -43,6 +84,10 @@ impl HWTMapper {
         let mut annotrace = Vec::new();
         for block in trace.iter_blocks() {
             let block = block?;
+            // Blocks outside of the functions with SIR (e.g. in libc) can't contain any labels.
+            if !self.contains_address(block.first_instr()) {
+                continue;
+            }
 
             let start_addr = usize::try_from(block.first_instr() - self.phdr_offset).unwrap();
             let end_addr = usize::try_from(block.last_instr() - self.phdr_offset).unwrap();
@@ -93,3 +138,46 @@ fn load_labels() -> IntervalTree<usize, SirLabel> {
     let vec = bincode::deserialize::<Vec<SirLabel>>(sec.data().unwrap()).unwrap();
     IntervalTree::from_iter(vec.into_iter().map(|l| (l.off..(l.off + 1), l)))
 }
+
+/// Builds the address ranges of the functions in the executable which contain SIR location labels.
+fn load_ranges() -> AddressRanges {
+    let syms = LABELS
+        .iter()
+        .map(|e| e.value.symbol_name.as_str())
+        .collect::<HashSet<_>>();
+    let pathb = env::current_exe().unwrap();
+    let file = fs::File::open(&pathb.as_path()).unwrap();
+    let mmap = unsafe { memmap2::Mmap::map(&file).unwrap() };
+    let object = object::File::parse(&*mmap).unwrap();
+    let mut ranges = Vec::new();
+    let mut seen = HashSet::new();
+    for sym in object.symbols() {
+        if sym.size() == 0 || !sym.is_definition() {
+            continue;
+        }
+        if let Ok(name) = sym.name() {
+            // A symbol may appear in both the static and dynamic symbol tables.
+            if syms.contains(name) && seen.insert(sym.address()) {
+                ranges.push((sym.address(), sym.address() + sym.size()));
+            }
+        }
+    }
+    AddressRanges::new(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AddressRanges;
+
+    #[test]
+    fn address_ranges() {
+        let ranges = AddressRanges::new(vec![(0x300, 0x400), (0x100, 0x180), (0x200, 0x201)]);
+        for addr in &[0x100, 0x17f, 0x200, 0x300, 0x3ff] {
+            assert!(ranges.contains(*addr), "{:#x}", addr);
+        }
+        for addr in &[0, 0xff, 0x180, 0x1ff, 0x201, 0x2ff, 0x400, u64::MAX] {
+            assert!(!ranges.contains(*addr), "{:#x}", addr);
+        }
+        assert!(!AddressRanges::new(Vec::new()).contains(0));
+    }
+}
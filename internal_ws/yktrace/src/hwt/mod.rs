@@ -32,7 +32,7 @@ impl Drop for HWTThreadTracer {
     }
 }
 
-pub(crate) fn start_tracing() -> ThreadTracer {
+pub(crate) fn start_tracing(max_blocks: Option<usize>) -> ThreadTracer {
     let tracer = TracerBuilder::new().build().unwrap();
     let mut ttracer = (*tracer).thread_tracer();
     ttracer.start_tracing().expect("Failed to start tracer.");
@@ -40,6 +40,7 @@ pub(crate) fn start_tracing() -> ThreadTracer {
         t_impl: Box::new(HWTThreadTracer {
             active: true,
             ttracer
-        })
+        }),
+        max_blocks
     }
 }
@@ -20,8 +20,14 @@ mod hwt;
 #[cfg(feature = "trace_sw")]
 mod swt;
 
-pub use errors::InvalidTraceError;
+pub use errors::{InvalidTraceError, TraceValidationError, VerifyError};
 use sir::SirTrace;
+use std::cell::Cell;
+
+thread_local! {
+    /// The number of `ThreadTracer`s currently alive on this thread.
+    static TRACING_DEPTH: Cell<usize> = Cell::new(0);
+}
 
 /// The different ways by which we can collect a trace.
 #[derive(Clone, Copy)]
@@ -45,19 +51,28 @@ impl Default for TracingKind {
 /// Represents a thread which is currently tracing.
 pub struct ThreadTracer {
     /// The tracing implementation.
-    t_impl: Box<dyn ThreadTracerImpl>
+    t_impl: Box<dyn ThreadTracerImpl>,
+    /// The maximum number of blocks the trace may contain, if any.
+    max_blocks: Option<usize>
 }
 
 impl ThreadTracer {
     /// Stops tracing on the current thread, returning a TIR trace on success.
     pub fn stop_tracing(mut self) -> Result<SirTrace, InvalidTraceError> {
-        let trace = self.t_impl.stop_tracing();
-        if let Ok(inner) = &trace {
-            if inner.len() == 0 {
-                return Err(InvalidTraceError::EmptyTrace);
-            }
+        let trace = self.t_impl.stop_tracing()?;
+        if trace.len() == 0 {
+            return Err(InvalidTraceError::EmptyTrace);
         }
-        trace
+        if let Some(max_blocks) = self.max_blocks {
+            trace.check_budget(max_blocks)?;
+        }
+        Ok(trace)
+    }
+}
+
+impl Drop for ThreadTracer {
+    fn drop(&mut self) {
+        TRACING_DEPTH.with(|d| d.set(d.get() - 1));
     }
 }
 
@@ -71,21 +86,45 @@ trait ThreadTracerImpl {
 /// Each thread can have at most one active tracer; calling `start_tracing()` on a thread where
 /// there is already an active tracer leads to undefined behaviour.
 pub fn start_tracing(kind: TracingKind) -> ThreadTracer {
+    start_tracing_impl(kind, None)
+}
+
+/// Start tracing on the current thread using the specified tracing kind, limiting the trace to
+/// `max_blocks` blocks. If the trace is longer than this when tracing is stopped, `stop_tracing`
+/// returns `Err(InvalidTraceError::TraceTooLong)`.
+pub fn start_tracing_with_budget(kind: TracingKind, max_blocks: usize) -> ThreadTracer {
+    start_tracing_impl(kind, Some(max_blocks))
+}
+
+fn start_tracing_impl(kind: TracingKind, max_blocks: Option<usize>) -> ThreadTracer {
     #[cfg(not(any(doctest, feature = "trace_hw", feature = "trace_sw")))]
     compile_error!("Please compile with `-C tracer=T`, where T is one of 'hw' or 'sw'");
 
-    match kind {
+    let tt = match kind {
         TracingKind::SoftwareTracing => {
             #[cfg(feature = "trace_hw")]
             panic!("requested software tracing, but `-C tracer=hw`");
             #[cfg(feature = "trace_sw")]
-            swt::start_tracing()
+            swt::start_tracing(max_blocks)
         }
         TracingKind::HardwareTracing => {
             #[cfg(feature = "trace_sw")]
             panic!("requested hardware tracing, but `-C tracer=sw`");
             #[cfg(feature = "trace_hw")]
-            hwt::start_tracing()
+            hwt::start_tracing(max_blocks)
         }
-    }
+    };
+    TRACING_DEPTH.with(|d| d.set(d.get() + 1));
+    tt
+}
+
+/// Is the current thread being traced?
+pub fn is_tracing() -> bool {
+    tracing_depth() > 0
+}
+
+/// Return the number of tracers active on the current thread. Since nested tracing is not yet
+/// supported, this is currently always 0 or 1.
+pub fn tracing_depth() -> usize {
+    TRACING_DEPTH.with(|d| d.get())
 }
@@ -1,7 +1,8 @@
 //! Loading and tracing of Serialised Intermediate Representation (SIR).
 
+use crate::{InvalidTraceError, TraceValidationError};
 use fallible_iterator::FallibleIterator;
-use fxhash::FxHashMap;
+use fxhash::{FxHashMap, FxHashSet, FxHasher};
 use memmap2::Mmap;
 use object::{Object, ObjectSection};
 use std::{
@@ -10,11 +11,15 @@ use std::{
     error::Error,
     fmt::{self, Debug, Display, Write},
     fs::File,
+    hash::{Hash, Hasher},
     io::{Cursor, Seek, SeekFrom},
     iter::Iterator,
     sync::{Arc, RwLock}
 };
-use ykpack::{self, Body, BodyFlags, CguHash, Decoder, Local, Pack, SirHeader, SirOffset, Ty};
+use ykpack::{
+    self, BasicBlockIndex, Body, BodyFlags, CguHash, Decoder, Local, Pack, SirHeader, SirOffset,
+    Terminator, Ty
+};
 
 // The return local is always $0.
 pub const RETURN_LOCAL: Local = Local(0);
@@ -195,7 +200,7 @@ impl<'m> Display for Sir<'m> {
 
 /// The same as core::SirLoc, just with a String representation of the symbol name and with the
 /// traits we were disallowed from using in libcore.
-#[derive(Debug, Hash, Eq, PartialEq)]
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub struct SirLoc {
     pub symbol_name: &'static str,
     pub bb_idx: u32,
@@ -220,6 +225,163 @@ impl SirTrace {
     pub fn new(locs: Vec<SirLoc>) -> Self {
         SirTrace(locs)
     }
+
+    /// Returns the index of the first location in the trace which revisits a block seen earlier
+    /// in the trace, i.e. the end of the first loop back-edge. Returns `None` if the trace never
+    /// revisits a block.
+    pub fn loop_back_edge_index(&self) -> Option<usize> {
+        let mut seen = FxHashSet::default();
+        self.0
+            .iter()
+            .position(|loc| !seen.insert((loc.symbol_name, loc.bb_idx)))
+    }
+
+    /// Checks that the trace contains at most `max_blocks` locations, returning
+    /// `Err(InvalidTraceError::TraceTooLong)` if not. An over-budget trace can't simply be cut
+    /// short, as the compiled trace would then only perform part of the interpreter step.
+    pub fn check_budget(&self, max_blocks: usize) -> Result<(), InvalidTraceError> {
+        if self.0.len() > max_blocks {
+            Err(InvalidTraceError::TraceTooLong)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns a hash of the sequence of blocks in the trace. Two traces which visit the same
+    /// blocks in the same order have the same hash, even if they were collected at different
+    /// virtual addresses.
+    pub fn block_hash(&self) -> u64 {
+        let mut h = FxHasher::default();
+        for loc in &self.0 {
+            loc.symbol_name.hash(&mut h);
+            loc.bb_idx.hash(&mut h);
+        }
+        h.finish()
+    }
+
+    /// Returns a Graphviz DOT rendering of the trace, with one node per location. If the trace
+    /// contains a loop, its back-edge is drawn as a dashed edge.
+    pub fn to_dot(&self) -> String {
+        let mut res = String::from("digraph trace {\n");
+        for (i, loc) in self.0.iter().enumerate() {
+            writeln!(
+                res,
+                "    n{} [label=\"{}:{}\"];",
+                i, loc.symbol_name, loc.bb_idx
+            )
+            .unwrap();
+        }
+        for i in 1..self.0.len() {
+            writeln!(res, "    n{} -> n{};", i - 1, i).unwrap();
+        }
+        if let Some(i) = self.loop_back_edge_index() {
+            let head = self
+                .0
+                .iter()
+                .position(|loc| {
+                    loc.symbol_name == self.0[i].symbol_name && loc.bb_idx == self.0[i].bb_idx
+                })
+                .unwrap();
+            writeln!(res, "    n{} -> n{} [style=dashed];", i - 1, head).unwrap();
+        }
+        res.push('}');
+        res
+    }
+
+    /// Returns a copy of the trace with the leading and trailing locations in the interpreter's
+    /// dispatch function `dispatch_fn` removed, leaving only the locations specific to the traced
+    /// bytecode(s).
+    pub fn strip_interpreter_dispatch(&self, dispatch_fn: &str) -> SirTrace {
+        self.strip_both_ends(dispatch_fn, dispatch_fn)
+    }
+
+    /// Like `strip_interpreter_dispatch`, but for interpreters where the code before and after
+    /// the traced bytecode(s) lives in different functions: leading locations in `leading_fn` and
+    /// trailing locations in `trailing_fn` are removed.
+    pub fn strip_both_ends(&self, leading_fn: &str, trailing_fn: &str) -> SirTrace {
+        let start = self
+            .0
+            .iter()
+            .position(|loc| loc.symbol_name != leading_fn)
+            .unwrap_or_else(|| self.0.len());
+        let end = self.0[start..]
+            .iter()
+            .rposition(|loc| loc.symbol_name != trailing_fn)
+            .map_or(start, |i| start + i + 1);
+        SirTrace(self.0[start..end].to_vec())
+    }
+
+    /// Checks the trace for structural problems which indicate a bug in the tracer. Locations
+    /// with no SIR are not checked, since building a TIR trace reports those anyway.
+    pub fn validate(&self, sir: &Sir) -> Result<(), InvalidTraceError> {
+        validate(self, |sym| sir.body(sym))
+    }
+}
+
+/// Returns true if, in a trace, a block ending in `term` can be immediately followed by itself.
+fn may_repeat(term: &Terminator, bb: BasicBlockIndex) -> bool {
+    match term {
+        Terminator::Goto(target_bb) | Terminator::Assert { target_bb, .. } => *target_bb == bb,
+        Terminator::SwitchInt {
+            target_bbs,
+            otherwise_bb,
+            ..
+        } => *otherwise_bb == bb || target_bbs.contains(&bb),
+        // A call may recurse into the same function, and we can't tell what an unimplemented
+        // terminator does.
+        Terminator::Call { .. } | Terminator::Drop { .. } | Terminator::Unimplemented(_) => true,
+        Terminator::Return | Terminator::Unreachable | Terminator::TraceDebugCall { .. } => false
+    }
+}
+
+/// The guts of `SirTrace::validate`. Bodies are looked up with `body_of`, allowing this to be
+/// tested without real SIR.
+fn validate<F>(trace: &SirTrace, body_of: F) -> Result<(), InvalidTraceError>
+where
+    F: Fn(&str) -> Option<Arc<Body>>
+{
+    let locs = &trace.0;
+    if locs.is_empty() {
+        return Err(InvalidTraceError::Malformed(
+            TraceValidationError::EmptyTrace
+        ));
+    }
+    for (idx, loc) in locs.iter().enumerate() {
+        let body = match body_of(loc.symbol_name) {
+            Some(body) => body,
+            None => continue
+        };
+        let block = match body.blocks.get(usize::try_from(loc.bb_idx).unwrap()) {
+            Some(block) => block,
+            None => {
+                return Err(InvalidTraceError::Malformed(
+                    TraceValidationError::InvalidBlock { idx }
+                ))
+            }
+        };
+        if let Some(next) = locs.get(idx + 1) {
+            if next.symbol_name == loc.symbol_name
+                && next.bb_idx == loc.bb_idx
+                && !may_repeat(&block.term, loc.bb_idx)
+            {
+                return Err(InvalidTraceError::Malformed(
+                    TraceValidationError::RepeatedBlock { idx: idx + 1 }
+                ));
+            }
+        }
+    }
+    // Once the trace's first loop has closed, the trace mustn't return to its first block again:
+    // that would mean it contains more than one loop.
+    if let Some(back_edge) = trace.loop_back_edge_index() {
+        let first = (locs[0].symbol_name, locs[0].bb_idx);
+        if let Some(j) = locs[back_edge + 1..]
+            .iter()
+            .position(|loc| (loc.symbol_name, loc.bb_idx) == first)
+        {
+            return Err(InvalidTraceError::CycleDetected(back_edge + 1 + j));
+        }
+    }
+    Ok(())
 }
 
 impl std::ops::Deref for SirTrace {
@@ -267,3 +429,163 @@ impl Display for SirTrace {
         write!(f, "{}", sir_trace_str(&*SIR, self, true))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, SirLoc, SirTrace};
+    use crate::{InvalidTraceError, TraceValidationError};
+    use std::sync::Arc;
+    use ykpack::{BasicBlock, Body, BodyFlags, Terminator};
+
+    fn trace(blocks: &[(&'static str, u32)]) -> SirTrace {
+        SirTrace::new(
+            blocks
+                .iter()
+                .map(|(sym, bb)| SirLoc::new(sym, *bb, None))
+                .collect()
+        )
+    }
+
+    #[test]
+    fn loop_back_edge() {
+        let t = trace(&[("f", 0), ("f", 1), ("g", 0), ("f", 2), ("f", 1), ("g", 0)]);
+        assert_eq!(t.loop_back_edge_index(), Some(4));
+    }
+
+    #[test]
+    fn loop_back_edge_self_loop() {
+        let t = trace(&[("f", 0), ("f", 1), ("f", 1)]);
+        assert_eq!(t.loop_back_edge_index(), Some(2));
+    }
+
+    #[test]
+    fn no_loop_back_edge() {
+        // The same block index in a different function isn't a revisit.
+        let t = trace(&[("f", 0), ("g", 0), ("f", 1), ("g", 1)]);
+        assert_eq!(t.loop_back_edge_index(), None);
+        assert_eq!(trace(&[]).loop_back_edge_index(), None);
+    }
+
+    #[test]
+    fn check_budget() {
+        let t = trace(&[("f", 0), ("f", 1), ("f", 2), ("f", 1)]);
+        t.check_budget(10).unwrap();
+        t.check_budget(4).unwrap();
+        match t.check_budget(3) {
+            Err(InvalidTraceError::TraceTooLong) => (),
+            _ => panic!()
+        }
+        assert_eq!(t.len(), 4);
+    }
+
+    #[test]
+    fn strip_interpreter_dispatch() {
+        let t = trace(&[
+            ("dispatch", 0),
+            ("dispatch", 1),
+            ("dispatch", 2),
+            ("op_add", 0),
+            ("op_add", 1),
+            ("dispatch", 5),
+            ("op_jmp", 0),
+            ("op_jmp", 1),
+            ("dispatch", 3),
+            ("dispatch", 4)
+        ]);
+        let stripped = t.strip_interpreter_dispatch("dispatch");
+        assert_eq!(stripped.len(), 5);
+        assert_eq!(stripped[0], SirLoc::new("op_add", 0, None));
+        assert_eq!(stripped[4], SirLoc::new("op_jmp", 1, None));
+
+        let t = trace(&[("enter", 0), ("op", 0), ("leave", 0), ("leave", 1)]);
+        assert_eq!(t.strip_both_ends("enter", "leave").len(), 1);
+        assert_eq!(t.strip_both_ends("leave", "enter").len(), 4);
+        assert_eq!(trace(&[("f", 0), ("f", 1)]).strip_both_ends("f", "g").len(), 0);
+    }
+
+    #[test]
+    fn to_dot() {
+        let dot = trace(&[("f", 0), ("f", 1), ("g", 0), ("f", 0)]).to_dot();
+        assert!(dot.starts_with("digraph"));
+        assert!(dot.contains("n0 [label=\"f:0\"]"));
+        assert!(dot.contains("n1 [label=\"f:1\"]"));
+        assert!(dot.contains("n2 [label=\"g:0\"]"));
+        assert!(dot.contains("n1 -> n2;"));
+        assert!(dot.contains("n2 -> n0 [style=dashed];"));
+    }
+
+    #[test]
+    fn block_hash() {
+        let h = trace(&[("f", 0), ("f", 1), ("g", 0)]).block_hash();
+        assert_eq!(h, trace(&[("f", 0), ("f", 1), ("g", 0)]).block_hash());
+        // Addresses aren't part of the hash.
+        let t = SirTrace::new(vec![
+            SirLoc::new("f", 0, Some(0x1000)),
+            SirLoc::new("f", 1, Some(0x1010)),
+            SirLoc::new("g", 0, Some(0x2000))
+        ]);
+        assert_eq!(h, t.block_hash());
+        assert_ne!(h, trace(&[("f", 1), ("f", 0), ("g", 0)]).block_hash());
+        assert_ne!(h, trace(&[("f", 0), ("f", 1)]).block_hash());
+    }
+
+    #[test]
+    fn validate_trace() {
+        // A function whose block 1 loops back to itself.
+        let f = Arc::new(Body {
+            symbol_name: String::from("f"),
+            blocks: vec![
+                BasicBlock::new(Vec::new(), Terminator::Goto(1)),
+                BasicBlock::new(Vec::new(), Terminator::Goto(1))
+            ],
+            flags: BodyFlags::empty(),
+            local_decls: Vec::new(),
+            num_args: 0,
+            layout: (0, 1),
+            offsets: Vec::new()
+        });
+        let body_of = |sym: &str| if sym == "f" { Some(f.clone()) } else { None };
+        let check = |blocks| validate(&trace(blocks), body_of);
+
+        assert_eq!(check(&[("f", 0), ("f", 1), ("f", 1)]), Ok(()));
+        // Locations without SIR aren't checked.
+        assert_eq!(check(&[("f", 0), ("g", 7), ("g", 7)]), Ok(()));
+        assert_eq!(
+            check(&[]),
+            Err(InvalidTraceError::Malformed(
+                TraceValidationError::EmptyTrace
+            ))
+        );
+        assert_eq!(
+            check(&[("f", 0), ("f", 0), ("f", 1)]),
+            Err(InvalidTraceError::Malformed(
+                TraceValidationError::RepeatedBlock { idx: 1 }
+            ))
+        );
+        assert_eq!(
+            check(&[("f", 0), ("f", 2)]),
+            Err(InvalidTraceError::Malformed(
+                TraceValidationError::InvalidBlock { idx: 1 }
+            ))
+        );
+        // Inner loops may revisit blocks other than the first any number of times.
+        assert_eq!(
+            check(&[("f", 0), ("g", 0), ("g", 1), ("g", 0), ("g", 1), ("g", 0)]),
+            Ok(())
+        );
+        assert_eq!(check(&[("f", 0), ("g", 0), ("f", 0)]), Ok(()));
+        let mut three_loops = [("f", 0), ("g", 0)].repeat(3);
+        three_loops.push(("f", 0));
+        assert_eq!(
+            check(&three_loops),
+            Err(InvalidTraceError::CycleDetected(4))
+        );
+        // The first repeated block needn't be the first block.
+        let mut inner_first = vec![("f", 0), ("g", 0), ("g", 1), ("g", 0)];
+        inner_first.extend_from_slice(&[("f", 0), ("g", 0), ("f", 0)]);
+        assert_eq!(
+            check(&inner_first),
+            Err(InvalidTraceError::CycleDetected(4))
+        );
+    }
+}
@@ -29,17 +29,23 @@ impl ThreadTracerImpl for SWTThreadTracer {
     }
 }
 
-pub(crate) fn start_tracing() -> ThreadTracer {
+pub(crate) fn start_tracing(max_blocks: Option<usize>) -> ThreadTracer {
     TRACE_BUF.with(|trace_buf| {
         assert!(trace_buf.is_empty());
     });
 
     unsafe {
+        // We record one location beyond the budget, so that `ThreadTracer::stop_tracing` can
+        // tell that the budget was exceeded.
+        SWT_MAX_LOCS = max_blocks
+            .map(|n| n.saturating_add(1))
+            .unwrap_or(usize::MAX);
         __YK_SWT_ACTIVE = true;
     }
 
     ThreadTracer {
-        t_impl: Box::new(SWTThreadTracer {})
+        t_impl: Box::new(SWTThreadTracer {}),
+        max_blocks
     }
 }
 
@@ -61,6 +67,10 @@ thread_local! {
 #[no_mangle]
 static mut __YK_SWT_ACTIVE: bool = false;
 
+/// The maximum number of locations to record into the trace buffer.
+#[thread_local]
+static mut SWT_MAX_LOCS: usize = usize::MAX;
+
 /// Record a location into the trace buffer if tracing is enabled on the current thread.
 ///
 /// This function is separate from `__yk_swt_rec_loc_impl` to keep register spilling off the
@@ -120,10 +130,12 @@ __yk_swt_rec_loc_active:
 #[no_mangle]
 unsafe extern "C" fn __yk_swt_rec_loc_impl(symbol_name: *const i8, bb_idx: u32) {
     TRACE_BUF.with(|trace_buf| {
-        trace_buf.push(SwtLoc {
-            symbol_name,
-            bb_idx
-        });
+        if trace_buf.len() < SWT_MAX_LOCS {
+            trace_buf.push(SwtLoc {
+                symbol_name,
+                bb_idx
+            });
+        }
     });
 }
 
@@ -145,6 +157,12 @@ mod trace_buffer {
             unsafe { (&*self.0.get()).is_empty() }
         }
 
+        pub(super) fn len(&self) -> usize {
+            // SAFETY: The api of `TraceBuffer` prevents any mutable references for the duration of
+            // this call.
+            unsafe { (&*self.0.get()).len() }
+        }
+
         #[inline]
         pub(super) fn push(&self, loc: SwtLoc) {
             // SAFETY: The api of `TraceBuffer` prevents any other references for the duration of
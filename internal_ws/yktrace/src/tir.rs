@@ -4,18 +4,29 @@
 
 use super::SirTrace;
 use crate::{
-    errors::InvalidTraceError,
+    errors::{InvalidTraceError, VerifyError},
     sir::{self, Sir, INTERP_STEP_ARG}
 };
 use std::{
     collections::{HashMap, HashSet},
     convert::TryFrom,
-    fmt::{self, Display, Write}
+    env,
+    fmt::{self, Display, Write},
+    sync::Arc
 };
 pub use ykpack::{
     BinOp, BodyFlags, CallOperand, Constant, ConstantInt, IRPlace, Local, LocalDecl, LocalIndex,
     Ptr, SignedInt, Statement, Terminator, UnsignedInt
 };
+use ykpack::{OffT, Ty, TyKind, TypeId};
+
+lazy_static! {
+    // If `YKD_LOG_IR=1`, print each TIR trace to stderr after each stage of its construction.
+    static ref LOG_IR: bool = env::var("YKD_LOG_IR").map(|v| v == "1").unwrap_or(false);
+    // If `YKD_VERIFY_IR=1`, validate each SIR trace before building TIR from it, even in release
+    // builds.
+    static ref VERIFY_IR: bool = env::var("YKD_VERIFY_IR").map(|v| v == "1").unwrap_or(false);
+}
 
 /// A TIR trace is conceptually a straight-line path through the SIR with guarded speculation.
 #[derive(Debug)]
@@ -32,6 +43,10 @@ impl<'a, 'm> TirTrace<'a, 'm> {
     /// tracer. Returns a TIR trace and the bounds the SIR trace was trimmed to, or Err if a symbol
     /// is encountered for which no SIR is available.
     pub fn new<'s>(sir: &'a Sir<'m>, trace: &'s SirTrace) -> Result<Self, InvalidTraceError> {
+        if cfg!(debug_assertions) || *VERIFY_IR {
+            trace.validate(sir)?;
+        }
+
         let mut ops = Vec::new();
         let mut itr = trace.iter().peekable();
         let mut rnm = VarRenamer::new();
@@ -419,12 +434,113 @@ impl<'a, 'm> TirTrace<'a, 'm> {
 
         let local_decls = rnm.done();
 
-        Ok(Self {
+        let mut tt = Self {
             ops,
             local_decls,
             addr_map,
             sir
-        })
+        };
+        tt.log_ir("construction");
+        tt.fold_constants();
+        tt.log_ir("fold_constants");
+        tt.strength_reduce();
+        tt.log_ir("strength_reduce");
+        tt.resolve_constant_guards();
+        tt.log_ir("resolve_constant_guards");
+        tt.remove_redundant_loads();
+        tt.log_ir("remove_redundant_loads");
+        tt.remove_dead_statements();
+        tt.log_ir("remove_dead_statements");
+        Ok(tt)
+    }
+
+    /// If `YKD_LOG_IR=1`, print the trace to stderr, labelled as being the result of `stage`.
+    fn log_ir(&self, stage: &str) {
+        if *LOG_IR {
+            eprintln!("--- TIR after {} ---\n{}", stage, self);
+        }
+    }
+
+    /// Statically resolve guards whose value is a constant. A guard which always passes is
+    /// replaced with a `Nop`. A guard which always fails is kept (the code generator compiles it
+    /// to an unconditional jump to the guard failure code) and all operations after it are
+    /// removed, since they are unreachable. Returns the number of guards resolved.
+    pub fn resolve_constant_guards(&mut self) -> usize {
+        let mut resolved = 0;
+        let mut idx = 0;
+        while idx < self.ops.len() {
+            if let TirOp::Guard(g) = &self.ops[idx] {
+                match g.const_outcome() {
+                    Some(true) => {
+                        self.ops[idx] = TirOp::Statement(Statement::Nop);
+                        resolved += 1;
+                    }
+                    Some(false) => {
+                        self.ops.truncate(idx + 1);
+                        resolved += 1;
+                        break;
+                    }
+                    None => ()
+                }
+            }
+            idx += 1;
+        }
+        resolved
+    }
+
+    /// Statically evaluate binary operations whose operands are known to be constant, replacing
+    /// them with stores of the result. Constants stored into locals are propagated into later
+    /// binary operations and guards. Since the trace is only valid while its guards pass, the
+    /// trace is also specialised on the values that guards check for. Returns the number of binary
+    /// operations folded.
+    pub fn fold_constants(&mut self) -> usize {
+        let sir = self.sir;
+        fold_constants(&mut self.ops, &self.local_decls, |tyid| sir.ty(tyid))
+    }
+
+    /// Replace unchecked integer multiplications by a constant power of two with left shifts, and
+    /// unchecked unsigned divisions by a constant power of two with right shifts. Returns the
+    /// number of operations replaced.
+    pub fn strength_reduce(&mut self) -> usize {
+        let sir = self.sir;
+        strength_reduce(&mut self.ops, |tyid| sir.ty(tyid))
+    }
+
+    /// Replace loads through a pointer which repeat an earlier load of the same place, with no
+    /// intervening write to memory, with copies of the local the earlier load was stored into.
+    /// Returns the number of loads removed.
+    pub fn remove_redundant_loads(&mut self) -> usize {
+        remove_redundant_loads(&mut self.ops, &self.local_decls)
+    }
+
+    /// Count the ops in the trace by kind, as named by `TirOp::kind_name`.
+    pub fn count_ops_by_kind(&self) -> HashMap<&'static str, usize> {
+        count_ops_by_kind(&self.ops)
+    }
+
+    /// Replace with `Nop`s the statements whose only effect is to define a local which is never
+    /// read afterwards. Returns the number of statements removed.
+    pub fn remove_dead_statements(&mut self) -> usize {
+        remove_dead_statements(&mut self.ops, &self.local_decls)
+    }
+
+    /// Maps the index of each operation defining a local to the indices of the operations which
+    /// use that definition. This is recomputed on each call, as the trace may have been mutated.
+    pub fn def_use_chains(&self) -> HashMap<usize, Vec<usize>> {
+        def_use_chains(&self.ops, &self.local_decls)
+    }
+
+    /// Returns, in trace order, the indices of the operations which use the definition made by
+    /// the operation at index `def`. This scans forward from `def`, so to query many definitions,
+    /// `def_use_chains` is cheaper.
+    pub fn iter_uses(&self, def: usize) -> impl Iterator<Item = usize> + '_ {
+        iter_uses(&self.ops, &self.local_decls, def)
+    }
+
+    /// Check that the trace is well-formed, returning all of the problems found if not. The code
+    /// generator relies on these properties holding.
+    pub fn verify(&self) -> Result<(), Vec<VerifyError>> {
+        verify(&self.ops, &self.local_decls, |tyid| self.sir.ty(tyid))
     }
 
     /// Return the TIR operation at index `idx` in the trace.
@@ -437,6 +553,17 @@ impl<'a, 'm> TirTrace<'a, 'm> {
         &self.ops.get_unchecked(idx)
     }
 
+    /// Return the TIR operations from index `start` up to, but not including, index `end`. Panics
+    /// if the range is out of bounds.
+    pub fn ops_slice(&self, start: usize, end: usize) -> &[TirOp] {
+        &self.ops[start..end]
+    }
+
+    /// Return the index of the first TIR operation for which `pred` returns true, if any.
+    pub fn find_op<F: Fn(&TirOp) -> bool>(&self, pred: F) -> Option<usize> {
+        find_op(&self.ops, pred)
+    }
+
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
@@ -447,6 +574,520 @@ impl<'a, 'm> TirTrace<'a, 'm> {
     }
 }
 
+/// Compute the def-use chains of `ops`. Since TIR isn't in SSA form, this tracks which definitions
+/// of each local reach each use. A write to the whole of a local replaces the definitions reaching
+/// it, whereas a write to part of a local adds to them.
+fn def_use_chains(
+    ops: &[TirOp],
+    local_decls: &HashMap<Local, LocalDecl>
+) -> HashMap<usize, Vec<usize>> {
+    /// Record that the op at `idx` uses the definitions of `local` which reach it.
+    fn use_local(
+        chains: &mut HashMap<usize, Vec<usize>>,
+        reaching: &HashMap<Local, Vec<usize>>,
+        idx: usize,
+        local: Local
+    ) {
+        for d in reaching.get(&local).into_iter().flatten() {
+            let uses = chains.get_mut(d).unwrap();
+            if uses.last() != Some(&idx) {
+                uses.push(idx);
+            }
+        }
+    }
+
+    let mut chains = HashMap::new();
+    let mut reaching: HashMap<Local, Vec<usize>> = HashMap::new();
+
+    for (idx, op) in ops.iter().enumerate() {
+        let stmt = match op {
+            TirOp::Statement(stmt) => stmt,
+            TirOp::Guard(g) => {
+                if let Some(l) = g.val.local() {
+                    use_local(&mut chains, &reaching, idx, l);
+                }
+                for ll in g.live_locals.iter().flatten() {
+                    use_local(&mut chains, &reaching, idx, ll.tir);
+                }
+                continue;
+            }
+        };
+
+        if let Statement::StorageLive(l) | Statement::StorageDead(l) = stmt {
+            reaching.remove(l);
+            continue;
+        }
+        // Uses come before the definition, so that e.g. `%1 = %1 + 1` uses the old value of `%1`.
+        for ip in stmt.operands() {
+            if let Some(l) = ip.local() {
+                use_local(&mut chains, &reaching, idx, l);
+            }
+        }
+        match stmt.dest() {
+            Some(IRPlace::Val { local, off, ty }) => {
+                chains.insert(idx, Vec::new());
+                let whole = *off == 0 && local_decls.get(local).map(|d| d.ty) == Some(*ty);
+                let defs = reaching.entry(*local).or_default();
+                if whole {
+                    defs.clear();
+                }
+                defs.push(idx);
+            }
+            Some(dest @ IRPlace::Indirect { .. }) => {
+                use_local(&mut chains, &reaching, idx, dest.local().unwrap());
+            }
+            _ => ()
+        }
+    }
+    chains
+}
+
+/// The linear scan behind `TirTrace::iter_uses`. If the op at `def` doesn't define a local, there
+/// are no uses. The results agree with those of `def_use_chains`.
+fn iter_uses<'t>(
+    ops: &'t [TirOp],
+    local_decls: &'t HashMap<Local, LocalDecl>,
+    def: usize
+) -> impl Iterator<Item = usize> + 't {
+    let local = match ops.get(def) {
+        Some(TirOp::Statement(stmt)) => match stmt.dest() {
+            Some(IRPlace::Val { local, .. }) => Some(*local),
+            _ => None
+        },
+        _ => None
+    };
+    // The scan state is the local whose definition we are following, which becomes `None` once
+    // the definition no longer reaches later ops.
+    ops.iter()
+        .enumerate()
+        .skip(def + 1)
+        .scan(local, move |state, (idx, op)| {
+            let l = (*state)?;
+            let (used, killed) = match op {
+                TirOp::Guard(g) => {
+                    let used = g.val.local() == Some(l)
+                        || g.live_locals.iter().flatten().any(|ll| ll.tir == l);
+                    (used, false)
+                }
+                TirOp::Statement(Statement::StorageLive(x))
+                | TirOp::Statement(Statement::StorageDead(x)) => (false, *x == l),
+                TirOp::Statement(stmt) => {
+                    let mut used = stmt.operands().iter().any(|ip| ip.local() == Some(l));
+                    let mut killed = false;
+                    match stmt.dest() {
+                        Some(IRPlace::Val { local, off, ty }) if *local == l => {
+                            killed = *off == 0 && local_decls.get(local).map(|d| d.ty) == Some(*ty);
+                        }
+                        Some(dest @ IRPlace::Indirect { .. }) => {
+                            used |= dest.local() == Some(l);
+                        }
+                        _ => ()
+                    }
+                    (used, killed)
+                }
+            };
+            if killed {
+                *state = None;
+            }
+            Some(if used { Some(idx) } else { None })
+        })
+        .flatten()
+}
+
+/// Check the well-formedness of `ops`. `ty` looks up a type.
+fn verify(
+    ops: &[TirOp],
+    local_decls: &HashMap<Local, LocalDecl>,
+    ty: impl Fn(&TypeId) -> Arc<Ty>
+) -> Result<(), Vec<VerifyError>> {
+    let mut errs = Vec::new();
+    let mut dead = HashSet::new();
+    let use_local = |errs: &mut Vec<VerifyError>, dead: &HashSet<Local>, idx, local| {
+        if !local_decls.contains_key(&local) {
+            errs.push(VerifyError::UndeclaredLocal { idx, local });
+        } else if dead.contains(&local) {
+            errs.push(VerifyError::UseAfterDead { idx, local });
+        }
+    };
+
+    for (idx, op) in ops.iter().enumerate() {
+        let stmt = match op {
+            TirOp::Statement(stmt) => stmt,
+            TirOp::Guard(g) => {
+                if let Some(l) = g.val.local() {
+                    use_local(&mut errs, &dead, idx, l);
+                }
+                for ll in g.live_locals.iter().flatten() {
+                    use_local(&mut errs, &dead, idx, ll.tir);
+                }
+                if let IRPlace::Unimplemented(_) = g.val {
+                    continue;
+                }
+                let vty = ty(&g.val.ty());
+                let ok = match g.kind {
+                    GuardKind::Boolean(_) => vty.is_bool(),
+                    GuardKind::Integer(_) | GuardKind::OtherInteger(_) => {
+                        vty.is_int() || vty.kind == TyKind::Char
+                    }
+                };
+                if !ok {
+                    errs.push(VerifyError::GuardType { idx });
+                }
+                continue;
+            }
+        };
+
+        match stmt {
+            Statement::Store(dest, src) => {
+                if !matches!(src, IRPlace::Unimplemented(_))
+                    && !matches!(dest, IRPlace::Unimplemented(_))
+                    && ty(&dest.ty()).size != ty(&src.ty()).size
+                {
+                    errs.push(VerifyError::StoreSizeMismatch { idx });
+                }
+            }
+            Statement::BinaryOp {
+                op, opnd1, opnd2, ..
+            } => {
+                let same_ty = match op {
+                    BinOp::Shl | BinOp::Shr | BinOp::Offset => true,
+                    _ => match (opnd1, opnd2) {
+                        (IRPlace::Unimplemented(_), _) | (_, IRPlace::Unimplemented(_)) => true,
+                        _ => ty(&opnd1.ty()) == ty(&opnd2.ty())
+                    }
+                };
+                if !same_ty {
+                    errs.push(VerifyError::BinOpTypeMismatch { idx });
+                }
+            }
+            Statement::StorageLive(l) => {
+                dead.remove(l);
+            }
+            Statement::StorageDead(l) => {
+                dead.insert(*l);
+            }
+            _ => ()
+        }
+        for ip in stmt.operands().into_iter().chain(stmt.dest()) {
+            if let Some(l) = ip.local() {
+                use_local(&mut errs, &dead, idx, l);
+            }
+        }
+    }
+
+    if errs.is_empty() {
+        Ok(())
+    } else {
+        Err(errs)
+    }
+}
+
+/// Scan `ops` forwards, tracking which locals are known to hold a constant. We only track whole
+/// locals which aren't referenced, since a referenced local could be mutated through a pointer.
+/// `ty` looks up a type.
+fn fold_constants(
+    ops: &mut [TirOp],
+    local_decls: &HashMap<Local, LocalDecl>,
+    ty: impl Fn(&TypeId) -> Arc<Ty>
+) -> usize {
+    /// Can a constant value be tracked for the place `ip`?
+    fn trackable(local_decls: &HashMap<Local, LocalDecl>, ip: &IRPlace) -> bool {
+        match ip {
+            IRPlace::Val { local, off: 0, ty } => local_decls
+                .get(local)
+                .map_or(false, |d| !d.referenced && d.ty == *ty),
+            _ => false
+        }
+    }
+
+    /// If `ip` is a constant, or a local known to hold a constant, return the constant.
+    fn const_of(consts: &HashMap<Local, (Constant, TypeId)>, ip: &IRPlace) -> Option<Constant> {
+        match ip {
+            IRPlace::Const { val, .. } => Some(val.clone()),
+            IRPlace::Val { local, off: 0, ty } => match consts.get(local) {
+                Some((val, cty)) if cty == ty => Some(val.clone()),
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    let mut consts: HashMap<Local, (Constant, TypeId)> = HashMap::new();
+    let mut folded = 0;
+    for op in ops.iter_mut() {
+        let stmt = match op {
+            TirOp::Statement(stmt) => stmt,
+            TirOp::Guard(g) => {
+                if let Some(val) = const_of(&consts, &g.val) {
+                    let ty = g.val.ty();
+                    g.val = IRPlace::Const { val, ty };
+                } else if trackable(local_decls, &g.val) {
+                    // Beyond this guard, the guarded local must hold the value the guard checks
+                    // for.
+                    let gty = g.val.ty();
+                    let val = match g.kind {
+                        GuardKind::Integer(v) => {
+                            ConstantInt::from_bits(&ty(&gty), v).map(Constant::Int)
+                        }
+                        GuardKind::Boolean(b) => Some(Constant::Bool(b)),
+                        GuardKind::OtherInteger(_) => None
+                    };
+                    if let Some(val) = val {
+                        consts.insert(g.val.local().unwrap(), (val, gty));
+                    }
+                }
+                continue;
+            }
+        };
+
+        if let Statement::BinaryOp {
+            dest,
+            op,
+            opnd1,
+            opnd2,
+            checked: false
+        } = stmt
+        {
+            if let (Some(Constant::Int(c1)), Some(Constant::Int(c2))) =
+                (const_of(&consts, opnd1), const_of(&consts, opnd2))
+            {
+                if let Some(val) = c1.eval_binop(*op, &c2) {
+                    let ty = dest.ty();
+                    *stmt = Statement::Store(dest.clone(), IRPlace::Const { val, ty });
+                    folded += 1;
+                }
+            }
+        }
+
+        // Forget about any local whose value this statement changes, then record the local's new
+        // value if it is a constant.
+        if let Statement::StorageLive(l) | Statement::StorageDead(l) = stmt {
+            consts.remove(l);
+            continue;
+        }
+        let local = match stmt.dest() {
+            Some(IRPlace::Val { local, .. }) => *local,
+            _ => continue
+        };
+        consts.remove(&local);
+        if let Statement::Store(
+            dest,
+            IRPlace::Const {
+                val: val @ Constant::Int(_),
+                ..
+            }
+        )
+        | Statement::Store(
+            dest,
+            IRPlace::Const {
+                val: val @ Constant::Bool(_),
+                ..
+            }
+        ) = stmt
+        {
+            if trackable(local_decls, dest) {
+                consts.insert(local, (val.clone(), dest.ty()));
+            }
+        }
+    }
+    folded
+}
+
+/// Replace multiplications and divisions by constant powers of two with shifts. See
+/// `TirTrace::strength_reduce`.
+fn strength_reduce(ops: &mut [TirOp], ty: impl Fn(&TypeId) -> Arc<Ty>) -> usize {
+    /// If `ip` is a positive integer constant which is a power of two, return its log2.
+    fn const_log2(ip: &IRPlace) -> Option<u32> {
+        match ip {
+            IRPlace::Const {
+                val: Constant::Int(ci),
+                ..
+            } => {
+                let bits = ci.to_bits();
+                if bits.is_power_of_two() {
+                    Some(bits.trailing_zeros())
+                } else {
+                    None
+                }
+            }
+            _ => None
+        }
+    }
+
+    let mut reduced = 0;
+    for op in ops.iter_mut() {
+        if let TirOp::Statement(Statement::BinaryOp {
+            op,
+            opnd1,
+            opnd2,
+            checked: false,
+            ..
+        }) = op
+        {
+            let opnd_ty = ty(&opnd1.ty());
+            if !opnd_ty.is_int() || opnd_ty.size() > 8 {
+                continue;
+            }
+            let (new_op, log2) = match op {
+                BinOp::Mul => {
+                    if const_log2(opnd1).is_some() && const_log2(opnd2).is_none() {
+                        // Multiplication is commutative: put the constant on the right.
+                        std::mem::swap(opnd1, opnd2);
+                    }
+                    match const_log2(opnd2) {
+                        Some(log2) => (BinOp::Shl, log2),
+                        None => continue
+                    }
+                }
+                // Division of a negative number rounds towards zero, whereas an arithmetic right
+                // shift rounds towards negative infinity, so only unsigned division is reduced.
+                BinOp::Div if opnd_ty.is_unsigned_int() => match const_log2(opnd2) {
+                    Some(log2) => (BinOp::Shr, log2),
+                    None => continue
+                },
+                _ => continue
+            };
+            let val = Constant::Int(ConstantInt::from_bits(&opnd_ty, u128::from(log2)).unwrap());
+            *opnd2 = IRPlace::Const {
+                val,
+                ty: opnd1.ty()
+            };
+            *op = new_op;
+            reduced += 1;
+        }
+    }
+    reduced
+}
+
+/// Scan `ops` forwards, tracking which unreferenced locals hold the value last loaded from each
+/// indirect place. Since we don't know what a pointer may alias, any write through a pointer, call
+/// or write to a referenced local forgets everything.
+fn remove_redundant_loads(ops: &mut [TirOp], local_decls: &HashMap<Local, LocalDecl>) -> usize {
+    // Maps an indirect place, as (pointer local, pointer offset, offset, type), to the local
+    // holding the value loaded from it.
+    let mut loaded: HashMap<(Local, OffT, OffT, TypeId), Local> = HashMap::new();
+    let mut removed = 0;
+    for op in ops.iter_mut() {
+        let stmt = match op {
+            TirOp::Statement(stmt) => stmt,
+            TirOp::Guard(_) => continue
+        };
+
+        if let Statement::Store(_, IRPlace::Indirect { ptr, off, ty }) = stmt {
+            if let Some(prev) = loaded.get(&(ptr.local, ptr.off, *off, *ty)) {
+                let src = IRPlace::Val {
+                    local: *prev,
+                    off: 0,
+                    ty: *ty
+                };
+                *stmt = Statement::Store(stmt.dest().unwrap().clone(), src);
+                removed += 1;
+            }
+        }
+
+        // Forget about anything this statement may change.
+        match stmt {
+            Statement::Call(..) => loaded.clear(),
+            Statement::StorageLive(l) | Statement::StorageDead(l) => {
+                let l = *l;
+                loaded.retain(|k, v| k.0 != l && *v != l);
+            }
+            _ => match stmt.dest() {
+                Some(IRPlace::Val { local, .. }) => {
+                    if local_decls.get(local).map_or(true, |d| d.referenced) {
+                        loaded.clear();
+                    } else {
+                        let l = *local;
+                        loaded.retain(|k, v| k.0 != l && *v != l);
+                    }
+                }
+                Some(_) => loaded.clear(),
+                None => ()
+            }
+        }
+
+        // Record a load into the whole of an unreferenced local.
+        if let Statement::Store(
+            IRPlace::Val {
+                local,
+                off: 0,
+                ty: dty
+            },
+            IRPlace::Indirect { ptr, off, ty }
+        ) = stmt
+        {
+            let tracked = local_decls
+                .get(local)
+                .map_or(false, |d| !d.referenced && d.ty == *dty);
+            if tracked && *local != ptr.local {
+                loaded.insert((ptr.local, ptr.off, *off, *ty), *local);
+            }
+        }
+    }
+    removed
+}
+
+/// Return the index of the first op in `ops` for which `pred` returns true, if any.
+fn find_op<F: Fn(&TirOp) -> bool>(ops: &[TirOp], pred: F) -> Option<usize> {
+    ops.iter().position(pred)
+}
+
+/// Count `ops` by kind, as named by `TirOp::kind_name`.
+fn count_ops_by_kind(ops: &[TirOp]) -> HashMap<&'static str, usize> {
+    let mut counts = HashMap::new();
+    for op in ops {
+        *counts.entry(op.kind_name()).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Scan `ops` backwards, collecting the locals which are read. A statement is dead if it isn't a
+/// call (which may have side effects) and it defines a local which isn't read later in the trace.
+/// Locals which are referenced may be read through a pointer, so definitions of those are never
+/// dead.
+fn remove_dead_statements(ops: &mut [TirOp], local_decls: &HashMap<Local, LocalDecl>) -> usize {
+    fn use_place(used: &mut HashSet<Local>, ip: &IRPlace) {
+        if let Some(l) = ip.local() {
+            used.insert(l);
+        }
+    }
+
+    let mut used = HashSet::new();
+    let mut removed = 0;
+    for op in ops.iter_mut().rev() {
+        let stmt = match op {
+            TirOp::Statement(stmt) => stmt,
+            TirOp::Guard(g) => {
+                use_place(&mut used, &g.val);
+                for ll in g.live_locals.iter().flatten() {
+                    used.insert(ll.tir);
+                }
+                continue;
+            }
+        };
+
+        match stmt.dest() {
+            Some(IRPlace::Val { local, .. })
+                if !matches!(stmt, Statement::Call(..))
+                    && *local != INTERP_STEP_ARG
+                    && !local_decls.get(local).map_or(true, |d| d.referenced)
+                    && !used.contains(local) =>
+            {
+                *stmt = Statement::Nop;
+                removed += 1;
+                continue;
+            }
+            // A store through a pointer reads the pointer.
+            Some(dest @ IRPlace::Indirect { .. }) => use_place(&mut used, dest),
+            _ => ()
+        }
+        for src in stmt.operands() {
+            use_place(&mut used, src);
+        }
+    }
+    removed
+}
+
 struct VarRenamer {
     /// Stores the offset before entering an inlined call, so that the correct offset can be
     /// restored again after leaving that call.
@@ -593,6 +1234,29 @@ pub struct Guard {
     pub live_locals: Vec<Vec<LiveLocal>>
 }
 
+impl Guard {
+    /// If the guard's value is a constant, returns whether the guard always passes (`true`) or
+    /// always fails (`false`). Returns `None` if the outcome can only be known at runtime.
+    pub fn const_outcome(&self) -> Option<bool> {
+        let bits = match &self.val {
+            IRPlace::Const {
+                val: Constant::Int(ci),
+                ..
+            } => ci.to_bits(),
+            IRPlace::Const {
+                val: Constant::Bool(b),
+                ..
+            } => u128::from(*b),
+            _ => return None
+        };
+        Some(match &self.kind {
+            GuardKind::Integer(v) => bits == *v,
+            GuardKind::OtherInteger(vs) => !vs.contains(&bits),
+            GuardKind::Boolean(expect) => bits == u128::from(*expect)
+        })
+    }
+}
+
 impl fmt::Display for Guard {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut live = String::from("");
@@ -643,6 +1307,28 @@ pub enum TirOp {
     Guard(Guard)
 }
 
+impl TirOp {
+    /// Returns the name of the kind of this op, e.g. `"BinaryOp"` or `"Guard"`.
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            TirOp::Statement(st) => match st {
+                Statement::Nop => "Nop",
+                Statement::Store(..) => "Store",
+                Statement::BinaryOp { .. } => "BinaryOp",
+                Statement::MkRef(..) => "MkRef",
+                Statement::DynOffs { .. } => "DynOffs",
+                Statement::StorageLive(_) => "StorageLive",
+                Statement::StorageDead(_) => "StorageDead",
+                Statement::Call(..) => "Call",
+                Statement::Cast(..) => "Cast",
+                Statement::Debug(_) => "Debug",
+                Statement::Unimplemented(_) => "Unimplemented"
+            },
+            TirOp::Guard(_) => "Guard"
+        }
+    }
+}
+
 impl fmt::Display for TirOp {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -651,3 +1337,482 @@ impl fmt::Display for TirOp {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        count_ops_by_kind, def_use_chains, find_op, fold_constants, iter_uses,
+        remove_dead_statements, remove_redundant_loads, strength_reduce, verify, BinOp, Constant,
+        ConstantInt, Guard, GuardKind, IRPlace, Local, LocalDecl, Ptr, Statement, TirOp,
+        VerifyError
+    };
+    use std::{collections::HashMap, sync::Arc};
+    use ykpack::{CguHash, Ty, TyIndex, TyKind, TypeId, UnsignedIntTy};
+
+    const TY: TypeId = TypeId {
+        cgu: CguHash(0),
+        idx: TyIndex(0)
+    };
+
+    fn const_guard(val: Constant, kind: GuardKind) -> Guard {
+        Guard {
+            val: IRPlace::Const { val, ty: TY },
+            kind,
+            block: Vec::new(),
+            live_locals: Vec::new()
+        }
+    }
+
+    fn val(l: u32) -> IRPlace {
+        IRPlace::Val {
+            local: Local(l),
+            off: 0,
+            ty: TY
+        }
+    }
+
+    fn add(dest: u32, opnd1: u32, opnd2: u32) -> TirOp {
+        TirOp::Statement(Statement::BinaryOp {
+            dest: val(dest),
+            op: BinOp::Add,
+            opnd1: val(opnd1),
+            opnd2: val(opnd2),
+            checked: false
+        })
+    }
+
+    fn is_nop(op: &TirOp) -> bool {
+        matches!(op, TirOp::Statement(Statement::Nop))
+    }
+
+    #[test]
+    fn const_guard_bool() {
+        let g = const_guard(Constant::Bool(true), GuardKind::Boolean(true));
+        assert_eq!(g.const_outcome(), Some(true));
+        let g = const_guard(Constant::Bool(false), GuardKind::Boolean(true));
+        assert_eq!(g.const_outcome(), Some(false));
+    }
+
+    #[test]
+    fn const_guard_integer() {
+        let five = || Constant::Int(ConstantInt::u8_from_bits(5));
+        assert_eq!(
+            const_guard(five(), GuardKind::Integer(5)).const_outcome(),
+            Some(true)
+        );
+        assert_eq!(
+            const_guard(five(), GuardKind::Integer(6)).const_outcome(),
+            Some(false)
+        );
+        assert_eq!(
+            const_guard(five(), GuardKind::OtherInteger(vec![1, 2])).const_outcome(),
+            Some(true)
+        );
+        assert_eq!(
+            const_guard(five(), GuardKind::OtherInteger(vec![5])).const_outcome(),
+            Some(false)
+        );
+    }
+
+    /// The place pointed to by `%l`.
+    fn deref(l: u32) -> IRPlace {
+        IRPlace::Indirect {
+            ptr: Ptr {
+                local: Local(l),
+                off: 0
+            },
+            off: 0,
+            ty: TY
+        }
+    }
+
+    fn store(dest: IRPlace, src: IRPlace) -> TirOp {
+        TirOp::Statement(Statement::Store(dest, src))
+    }
+
+    #[test]
+    fn redundant_loads() {
+        let local_decls = (2..7)
+            .map(|l| (Local(l), LocalDecl::new(TY, false)))
+            .collect::<HashMap<_, _>>();
+        let is_load = |op: &TirOp| {
+            matches!(
+                op,
+                TirOp::Statement(Statement::Store(_, IRPlace::Indirect { .. }))
+            )
+        };
+        let mut ops = vec![
+            store(val(2), deref(6)),
+            store(val(3), deref(6)),
+            add(4, 2, 3),
+            store(val(4), deref(6))
+        ];
+        assert_eq!(remove_redundant_loads(&mut ops, &local_decls), 2);
+        assert_eq!(ops.iter().filter(|op| is_load(op)).count(), 1);
+        let is_copy = |op: &TirOp, dest, src| match op {
+            TirOp::Statement(stmt) => *stmt == Statement::Store(val(dest), val(src)),
+            TirOp::Guard(_) => false
+        };
+        assert!(is_copy(&ops[1], 3, 2));
+        assert!(is_copy(&ops[3], 4, 2));
+
+        // A write through any pointer, or a change to the pointer or to the local holding the
+        // first load's value, means that the place must be loaded again.
+        for op in vec![store(deref(5), val(3)), store(val(6), val(3)), add(2, 3, 3)] {
+            let mut ops = vec![store(val(2), deref(6)), op, store(val(4), deref(6))];
+            assert_eq!(remove_redundant_loads(&mut ops, &local_decls), 0);
+        }
+    }
+
+    #[test]
+    fn ops_by_kind() {
+        let ops = vec![
+            store(val(2), deref(6)),
+            store(val(3), deref(6)),
+            add(4, 2, 3),
+            store(val(5), deref(6)),
+            add(4, 4, 5),
+            TirOp::Guard(const_guard(Constant::Bool(true), GuardKind::Boolean(true)))
+        ];
+        let counts = count_ops_by_kind(&ops);
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts["Store"], 3);
+        assert_eq!(counts["BinaryOp"], 2);
+        assert_eq!(counts["Guard"], 1);
+    }
+
+    #[test]
+    fn find_ops() {
+        let guard = TirOp::Guard(const_guard(Constant::Bool(true), GuardKind::Boolean(true)));
+        let mut ops = (2..7).map(|l| add(l, l, l)).collect::<Vec<_>>();
+        ops.push(guard);
+        ops.push(add(2, 2, 2));
+        let is_guard = |op: &TirOp| matches!(op, TirOp::Guard(_));
+        assert_eq!(find_op(&ops, is_guard), Some(5));
+        assert_eq!(find_op(&ops[..5], is_guard), None);
+    }
+
+    #[test]
+    fn dead_statements() {
+        let local_decls = (2..6)
+            .map(|l| (Local(l), LocalDecl::new(TY, false)))
+            .collect::<HashMap<_, _>>();
+        let mut ops = vec![
+            add(2, 3, 3), // Dead: %2 is never read.
+            add(4, 3, 3), // Live: %4 is read by the store below.
+            add(5, 4, 4), // Dead: %5 is never read.
+            TirOp::Statement(Statement::Store(
+                IRPlace::Indirect {
+                    ptr: Ptr {
+                        local: Local(1),
+                        off: 0
+                    },
+                    off: 0,
+                    ty: TY
+                },
+                val(4)
+            ))
+        ];
+        assert_eq!(remove_dead_statements(&mut ops, &local_decls), 2);
+        assert!(is_nop(&ops[0]));
+        assert!(!is_nop(&ops[1]));
+        assert!(is_nop(&ops[2]));
+        assert!(!is_nop(&ops[3]));
+    }
+
+    #[test]
+    fn dead_statements_referenced() {
+        let mut local_decls = HashMap::new();
+        local_decls.insert(Local(2), LocalDecl::new(TY, true));
+        let mut ops = vec![add(2, 3, 3)];
+        assert_eq!(remove_dead_statements(&mut ops, &local_decls), 0);
+        assert!(!is_nop(&ops[0]));
+    }
+
+    #[test]
+    fn fold_binops() {
+        let local_decls = (2..5)
+            .map(|l| (Local(l), LocalDecl::new(TY, false)))
+            .collect::<HashMap<_, _>>();
+        let cst = |v| IRPlace::Const {
+            val: Constant::Int(ConstantInt::u8_from_bits(v)),
+            ty: TY
+        };
+        let mut ops = vec![
+            TirOp::Statement(Statement::Store(val(2), cst(3))),
+            add(3, 2, 2),
+            add(4, 3, 5), // Not foldable: %5 isn't known.
+            TirOp::Guard(Guard {
+                val: val(3),
+                kind: GuardKind::Integer(6),
+                block: Vec::new(),
+                live_locals: Vec::new()
+            })
+        ];
+        assert_eq!(fold_constants(&mut ops, &local_decls, test_ty), 1);
+        match &ops[1] {
+            TirOp::Statement(Statement::Store(dest, src)) => {
+                assert_eq!(dest, &val(3));
+                assert_eq!(src, &cst(6));
+            }
+            _ => panic!()
+        }
+        assert!(matches!(
+            ops[2],
+            TirOp::Statement(Statement::BinaryOp { .. })
+        ));
+        match &ops[3] {
+            TirOp::Guard(g) => assert_eq!(g.const_outcome(), Some(true)),
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn strength_reduction() {
+        let cst = |v| IRPlace::Const {
+            val: Constant::Int(ConstantInt::u8_from_bits(v)),
+            ty: TY
+        };
+        let binop = |op, opnd1, opnd2, checked| Statement::BinaryOp {
+            dest: val(2),
+            op,
+            opnd1,
+            opnd2,
+            checked
+        };
+        let stmts = vec![
+            binop(BinOp::Mul, val(3), cst(8), false),
+            binop(BinOp::Mul, cst(4), val(3), false),
+            binop(BinOp::Div, val(3), cst(2), false),
+            binop(BinOp::Mul, val(3), cst(6), false),
+            binop(BinOp::Mul, val(3), cst(8), true),
+            binop(BinOp::Rem, val(3), cst(8), false)
+        ];
+        let mut ops = stmts.into_iter().map(TirOp::Statement).collect::<Vec<_>>();
+        assert_eq!(strength_reduce(&mut ops, test_ty), 3);
+        let expected = vec![
+            binop(BinOp::Shl, val(3), cst(3), false),
+            binop(BinOp::Shl, val(3), cst(2), false),
+            binop(BinOp::Shr, val(3), cst(1), false),
+            binop(BinOp::Mul, val(3), cst(6), false),
+            binop(BinOp::Mul, val(3), cst(8), true),
+            binop(BinOp::Rem, val(3), cst(8), false)
+        ];
+        for (op, exp) in ops.iter().zip(expected.iter()) {
+            match op {
+                TirOp::Statement(stmt) => assert_eq!(stmt, exp),
+                _ => panic!()
+            }
+        }
+    }
+
+    #[test]
+    fn fold_binops_redefined() {
+        let local_decls = (2..5)
+            .map(|l| (Local(l), LocalDecl::new(TY, false)))
+            .collect::<HashMap<_, _>>();
+        let cst = |v| IRPlace::Const {
+            val: Constant::Int(ConstantInt::u8_from_bits(v)),
+            ty: TY
+        };
+        let mut ops = vec![
+            TirOp::Statement(Statement::Store(val(2), cst(3))),
+            add(2, 2, 4), // %2 is no longer known after this.
+            add(3, 2, 2)
+        ];
+        assert_eq!(fold_constants(&mut ops, &local_decls, test_ty), 0);
+    }
+
+    /// The types used by the verifier tests are: `BOOL_TY` is a `bool`, `U64_TY` a `u64` and any
+    /// other type (e.g. `TY`) a `u8`.
+    const BOOL_TY: TypeId = TypeId {
+        cgu: CguHash(0),
+        idx: TyIndex(1)
+    };
+    const U64_TY: TypeId = TypeId {
+        cgu: CguHash(0),
+        idx: TyIndex(2)
+    };
+
+    fn test_ty(tyid: &TypeId) -> Arc<Ty> {
+        let (size, kind) = if *tyid == BOOL_TY {
+            (1, TyKind::Bool)
+        } else if *tyid == U64_TY {
+            (8, TyKind::UnsignedInt(UnsignedIntTy::U64))
+        } else {
+            (1, TyKind::UnsignedInt(UnsignedIntTy::U8))
+        };
+        Arc::new(Ty {
+            size,
+            align: size,
+            kind
+        })
+    }
+
+    fn test_decls() -> HashMap<Local, LocalDecl> {
+        let mut local_decls = (2..5)
+            .map(|l| (Local(l), LocalDecl::new(TY, false)))
+            .collect::<HashMap<_, _>>();
+        local_decls.insert(Local(5), LocalDecl::new(BOOL_TY, false));
+        local_decls.insert(Local(6), LocalDecl::new(U64_TY, false));
+        local_decls
+    }
+
+    fn guard(val: IRPlace, kind: GuardKind) -> TirOp {
+        TirOp::Guard(Guard {
+            val,
+            kind,
+            block: Vec::new(),
+            live_locals: Vec::new()
+        })
+    }
+
+    #[test]
+    fn verify_ok() {
+        // A `u8` from another codegen unit is structurally the same type as `TY`.
+        let other_cgu_u8 = IRPlace::Const {
+            val: Constant::Int(ConstantInt::u8_from_bits(1)),
+            ty: TypeId {
+                cgu: CguHash(1),
+                idx: TyIndex(0)
+            }
+        };
+        let ops = vec![
+            TirOp::Statement(Statement::StorageLive(Local(2))),
+            add(2, 3, 4),
+            TirOp::Statement(Statement::BinaryOp {
+                dest: val(2),
+                op: BinOp::Add,
+                opnd1: val(3),
+                opnd2: other_cgu_u8,
+                checked: false
+            }),
+            guard(val(2), GuardKind::Integer(1)),
+            TirOp::Statement(Statement::StorageDead(Local(2))),
+            TirOp::Statement(Statement::StorageLive(Local(2))),
+            TirOp::Statement(Statement::Store(val(2), val(3)))
+        ];
+        assert_eq!(verify(&ops, &test_decls(), test_ty), Ok(()));
+    }
+
+    #[test]
+    fn verify_errors() {
+        let bool_val = IRPlace::Val {
+            local: Local(5),
+            off: 0,
+            ty: BOOL_TY
+        };
+        let ops = vec![
+            add(2, 3, 9),
+            TirOp::Statement(Statement::BinaryOp {
+                dest: val(2),
+                op: BinOp::Add,
+                opnd1: val(3),
+                opnd2: bool_val,
+                checked: false
+            }),
+            guard(val(2), GuardKind::Boolean(true)),
+            TirOp::Statement(Statement::Store(
+                val(2),
+                IRPlace::Val {
+                    local: Local(6),
+                    off: 0,
+                    ty: U64_TY
+                }
+            )),
+            TirOp::Statement(Statement::StorageDead(Local(3))),
+            add(2, 3, 4)
+        ];
+        assert_eq!(
+            verify(&ops, &test_decls(), test_ty),
+            Err(vec![
+                VerifyError::UndeclaredLocal {
+                    idx: 0,
+                    local: Local(9)
+                },
+                VerifyError::BinOpTypeMismatch { idx: 1 },
+                VerifyError::GuardType { idx: 2 },
+                VerifyError::StoreSizeMismatch { idx: 3 },
+                VerifyError::UseAfterDead {
+                    idx: 5,
+                    local: Local(3)
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn def_uses() {
+        let local_decls = (2..5)
+            .map(|l| (Local(l), LocalDecl::new(TY, false)))
+            .collect::<HashMap<_, _>>();
+        let ops = vec![
+            add(2, 3, 3),
+            add(4, 2, 2),
+            guard(val(4), GuardKind::Integer(1)),
+            add(2, 2, 4),
+            TirOp::Statement(Statement::Store(val(3), val(2))),
+            TirOp::Statement(Statement::StorageDead(Local(2))),
+            TirOp::Statement(Statement::Store(val(4), val(2)))
+        ];
+        let chains = def_use_chains(&ops, &local_decls);
+        assert_eq!(chains.len(), 5);
+        assert_eq!(chains[&0], vec![1, 3]);
+        assert_eq!(chains[&1], vec![2, 3]);
+        assert_eq!(chains[&3], vec![4]);
+        assert!(chains[&4].is_empty());
+        assert!(chains[&6].is_empty());
+
+        // Following a single definition gives the same results.
+        for (def, uses) in &chains {
+            let scanned = iter_uses(&ops, &local_decls, *def).collect::<Vec<_>>();
+            assert_eq!(&scanned, uses);
+        }
+        // Non-definitions have no uses.
+        assert_eq!(iter_uses(&ops, &local_decls, 2).count(), 0);
+        assert_eq!(iter_uses(&ops, &local_decls, 5).count(), 0);
+    }
+
+    #[test]
+    fn iter_uses_chain() {
+        let local_decls = (2..5)
+            .map(|l| (Local(l), LocalDecl::new(TY, false)))
+            .collect::<HashMap<_, _>>();
+        let ops = vec![
+            TirOp::Statement(Statement::Store(val(2), val(3))),
+            add(3, 2, 2),
+            add(4, 3, 2)
+        ];
+        let uses = |def| iter_uses(&ops, &local_decls, def).collect::<Vec<_>>();
+        assert_eq!(uses(0), vec![1, 2]);
+        assert_eq!(uses(1), vec![2]);
+        assert!(uses(2).is_empty());
+    }
+
+    #[test]
+    fn fold_guarded_values() {
+        let mut ops = vec![
+            guard(val(2), GuardKind::Integer(3)),
+            add(3, 2, 2),
+            guard(val(2), GuardKind::Integer(3)),
+            guard(val(3), GuardKind::Integer(7)),
+            guard(val(4), GuardKind::OtherInteger(vec![1])),
+            add(3, 4, 4)
+        ];
+        assert_eq!(fold_constants(&mut ops, &test_decls(), test_ty), 1);
+        let outcome = |op: &TirOp| match op {
+            TirOp::Guard(g) => g.const_outcome(),
+            _ => panic!()
+        };
+        // The first guard is what we specialise upon, so it must remain.
+        assert_eq!(outcome(&ops[0]), None);
+        assert!(matches!(ops[1], TirOp::Statement(Statement::Store(..))));
+        assert_eq!(outcome(&ops[2]), Some(true));
+        assert_eq!(outcome(&ops[3]), Some(false));
+        // `OtherInteger` doesn't tell us the value of the local.
+        assert!(matches!(
+            ops[5],
+            TirOp::Statement(Statement::BinaryOp { .. })
+        ));
+    }
+}
@@ -0,0 +1,69 @@
+//! Benchmarks for the code generator.
+//!
+//! These measure how long it takes to turn a SIR trace into native code (which is the pause an
+//! interpreter observes while a trace is being compiled) and how quickly a compiled trace runs.
+
+extern crate test;
+use self::test::{black_box, Bencher};
+use ykshim_client::{compile_tir_trace, start_tracing, SirTrace, TirTrace, TracingKind};
+
+#[derive(Debug)]
+struct InterpCtx {
+    iters: u64,
+    acc: u64,
+}
+
+#[interp_step]
+fn interp_step(io: &mut InterpCtx) {
+    let mut i = 0;
+    while i < io.iters {
+        io.acc = io.acc.wrapping_add(i * 3);
+        i += 1;
+    }
+}
+
+/// Trace a single call to `interp_step`, whose loop runs for `iters` iterations. The trace length
+/// is thus roughly proportional to `iters`.
+fn trace(iters: u64) -> SirTrace {
+    let mut ctx = InterpCtx { iters, acc: 0 };
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    th.stop_tracing().unwrap()
+}
+
+/// Benchmark building and compiling a TIR trace from a SIR trace with `iters` loop iterations.
+fn bench_compile(b: &mut Bencher, iters: u64) {
+    let sir_trace = trace(iters);
+    b.iter(|| {
+        let tir_trace = TirTrace::new(&sir_trace);
+        black_box(compile_tir_trace::<InterpCtx>(tir_trace).unwrap());
+    });
+}
+
+#[bench]
+fn bench_compile_10_iters(b: &mut Bencher) {
+    bench_compile(b, 10);
+}
+
+#[bench]
+fn bench_compile_100_iters(b: &mut Bencher) {
+    bench_compile(b, 100);
+}
+
+#[bench]
+fn bench_compile_1000_iters(b: &mut Bencher) {
+    bench_compile(b, 1000);
+}
+
+#[bench]
+fn bench_execute_100_iters(b: &mut Bencher) {
+    let sir_trace = trace(100);
+    let ct = compile_tir_trace::<InterpCtx>(TirTrace::new(&sir_trace)).unwrap();
+    let mut ctx = InterpCtx { iters: 100, acc: 0 };
+    b.iter(|| {
+        black_box(unsafe { ct.execute(&mut ctx) });
+    });
+}
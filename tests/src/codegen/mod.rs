@@ -1,10 +1,15 @@
 //! Tests for the code generator (compiling TIR traces to native code).
 
-use crate::helpers::{add6, add_some};
+use crate::helpers::{add6, add7, add8, add_some, wsum12};
 use libc;
 use libc::{abs, getuid};
-use ykshim_client::{compile_tir_trace, compile_trace, start_tracing, TirTrace, TracingKind};
+use std::process::Command;
+use ykshim_client::{
+    compile_tir_trace, compile_tir_trace_with_options, compile_trace, start_tracing,
+    CompileOptions, TirTrace, TracingKind,
+};
 
+mod bench;
 mod reg_alloc;
 
 #[test]
@@ -30,6 +35,71 @@ fn simple() {
     assert_eq!(args.0, 13);
 }
 
+#[test]
+fn code_size_and_alignment() {
+    struct InterpCtx(u8);
+
+    #[interp_step]
+    #[inline(never)]
+    fn simple(io: &mut InterpCtx) {
+        io.0 = 13;
+    }
+
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    simple(&mut InterpCtx(0));
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace::<InterpCtx>(sir_trace).unwrap();
+    assert!(ct.code_size() > 0);
+    assert!(!ct.ptr().is_null());
+    // The SysV ABI requires function entry points to be 16-byte aligned.
+    assert_eq!(ct.ptr() as usize % 16, 0);
+}
+
+#[test]
+fn compile_with_options() {
+    struct InterpCtx(u64, u64);
+
+    #[interp_step]
+    #[inline(never)]
+    fn calc(io: &mut InterpCtx) {
+        let x = io.0 * 3;
+        let y = x + io.0;
+        io.1 = y + 7;
+    }
+
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    calc(&mut InterpCtx(0, 0));
+    let sir_trace = th.stop_tracing().unwrap();
+
+    // With no registers to allocate to, all locals live on the stack, but the trace still works.
+    let opts = CompileOptions {
+        register_pool: Some(Vec::new()),
+        max_stack_bytes: None,
+        loop_iterations: None,
+    };
+    let ct = compile_tir_trace_with_options(TirTrace::new(&sir_trace), &opts).unwrap();
+    let mut args = InterpCtx(5, 0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args.1, 27);
+
+    // ...but if the stack is limited, compilation fails.
+    let opts = CompileOptions {
+        register_pool: Some(Vec::new()),
+        max_stack_bytes: Some(0),
+        loop_iterations: None,
+    };
+    let err = compile_tir_trace_with_options::<InterpCtx>(TirTrace::new(&sir_trace), &opts)
+        .err()
+        .unwrap();
+    assert!(err.to_str().unwrap().contains("bytes of stack"));
+}
+
 #[inline(never)]
 fn farg(i: u8) -> u8 {
     i
@@ -180,6 +250,74 @@ fn exec_call_symbol_with_many_args() {
     assert_eq!(ctx.0, args.0);
 }
 
+#[test]
+fn exec_call_symbol_with_7_args() {
+    struct InterpCtx(u64);
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        io.0 = unsafe { add7(1, 2, 3, 4, 5, 6, 7) };
+    }
+
+    let mut ctx = InterpCtx(0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+    let mut args = InterpCtx(0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(ctx.0, 28);
+    assert_eq!(ctx.0, args.0);
+}
+
+#[test]
+fn exec_call_symbol_with_8_args() {
+    struct InterpCtx(u64, u64);
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        let x = io.1;
+        io.0 = unsafe { add8(1, 2, 3, 4, 5, 6, x, x + 1) };
+    }
+
+    let mut ctx = InterpCtx(0, 7);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+    let mut args = InterpCtx(0, 10);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(ctx.0, 36);
+    assert_eq!(args.0, 42);
+}
+
+#[test]
+fn exec_call_symbol_with_12_args() {
+    struct InterpCtx(u64, u64);
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        let x = io.1;
+        io.0 = unsafe { wsum12(x, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, x) };
+    }
+
+    let mut ctx = InterpCtx(0, 1);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+    let mut args = InterpCtx(0, 12);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(ctx.0, 518);
+    assert_eq!(args.0, 661);
+}
+
 #[test]
 fn exec_call_symbol_with_many_args_some_ignored() {
     struct InterpCtx(u64);
@@ -233,6 +371,142 @@ fn ext_call_and_spilling() {
     assert_eq!(ctx.0, args.0);
 }
 
+#[test]
+fn loop_back() {
+    #[derive(Debug)]
+    struct InterpCtx(u64);
+
+    #[interp_step]
+    fn interp_stepx(io: &mut InterpCtx) {
+        io.0 += 1;
+    }
+
+    let mut ctx = InterpCtx(0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_stepx(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+
+    let opts = CompileOptions {
+        loop_iterations: Some(100),
+        ..Default::default()
+    };
+    let ct = compile_tir_trace_with_options(TirTrace::new(&sir_trace), &opts).unwrap();
+    let mut args = InterpCtx(0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args.0, 100);
+
+    // A guard failure part way through the loop exits the trace.
+    let mut args = InterpCtx(u64::MAX - 10);
+    assert!(!unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args.0, u64::MAX);
+}
+
+#[test]
+fn loop_exit() {
+    // A trace which loops once exits normally, returning null.
+    #[derive(Debug)]
+    struct InterpCtx(u64);
+
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        io.0 += 2;
+    }
+
+    let mut ctx = InterpCtx(0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+
+    let opts = CompileOptions {
+        loop_iterations: Some(1),
+        ..Default::default()
+    };
+    let ct = compile_tir_trace_with_options(TirTrace::new(&sir_trace), &opts).unwrap();
+    let mut args = InterpCtx(5);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args.0, 7);
+
+    // A trace can't loop zero times, or more times than the loop counter can count to.
+    for &iters in &[0, u32::MAX] {
+        let opts = CompileOptions {
+            loop_iterations: Some(iters),
+            ..Default::default()
+        };
+        let err = compile_tir_trace_with_options::<InterpCtx>(TirTrace::new(&sir_trace), &opts)
+            .err()
+            .unwrap();
+        assert!(err
+            .to_str()
+            .unwrap()
+            .starts_with("Invalid number of loop iterations"));
+    }
+}
+
+#[test]
+fn guard_offsets() {
+    #[derive(Debug)]
+    struct InterpCtx(u8, u8);
+
+    #[interp_step]
+    fn interp_stepx(io: &mut InterpCtx) {
+        io.0 += 1;
+        io.1 += 2;
+    }
+
+    let mut ctx = InterpCtx(0, 0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_stepx(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_tir_trace::<InterpCtx>(TirTrace::new(&sir_trace)).unwrap();
+
+    // Each of the (overflow checking) additions is guarded.
+    let off0 = ct.find_guard_offset(0).unwrap();
+    let off1 = ct.find_guard_offset(1).unwrap();
+    assert!(off0 > 0);
+    assert!(off1 > off0);
+    assert_eq!(ct.find_guard_offset(2), None);
+
+    assert!(ct.disassemble_range(off1, ct.code_size() + 1).is_err());
+}
+
+#[test]
+fn disassemble() {
+    struct InterpCtx(u64);
+
+    #[interp_step]
+    #[inline(never)]
+    fn interp_step(io: &mut InterpCtx) {
+        io.0 += 1;
+    }
+
+    let mut ctx = InterpCtx(0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+
+    // Disassembly requires `rasm2`, which may not be installed.
+    if Command::new("rasm2").arg("-v").output().is_err() {
+        assert!(ct.disassemble().is_err());
+        return;
+    }
+    // Every trace returns from its epilogue.
+    let asm = ct.disassemble().unwrap();
+    assert!(asm.lines().any(|l| l.trim() == "ret"));
+}
+
 #[test]
 fn binop_add_simple() {
     #[derive(Eq, PartialEq, Debug)]
@@ -286,6 +560,65 @@ fn binop_add_overflow() {
     assert!(!unsafe { ct.execute(&mut args).is_null() });
 }
 
+#[test]
+fn patch_guard() {
+    #[derive(Eq, PartialEq, Debug)]
+    struct InterpCtx(u8, u8);
+
+    #[interp_step]
+    fn interp_stepx(io: &mut InterpCtx) {
+        io.1 = io.0 + 1;
+    }
+
+    let mut ctx = InterpCtx(1, 0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_stepx(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace::<InterpCtx>(sir_trace).unwrap();
+
+    // Patch the overflow guard to exit the trace as if it had succeeded, rather than handing over
+    // to the stopgap interpreter.
+    unsafe { ct.patch_guard(0, ct.exit_ptr()).unwrap() };
+    let mut args = InterpCtx(255, 5);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args, InterpCtx(255, 5));
+    assert_eq!(ct.guard_failures(), vec![0]);
+
+    assert!(unsafe { ct.patch_guard(1, ct.exit_ptr()) }.is_err());
+}
+
+#[test]
+fn binop_add_u128() {
+    #[derive(Eq, PartialEq, Debug)]
+    struct InterpCtx(u128, u128, u128);
+
+    #[interp_step]
+    fn interp_stepx(io: &mut InterpCtx) {
+        io.2 = io.0 + io.1 + 1;
+    }
+
+    let mut ctx = InterpCtx(1, 2, 0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_stepx(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+
+    // The carry out of the low 64 bits must make it into the high 64 bits.
+    let mut args = InterpCtx(u128::from(u64::MAX), 1 << 64 | 5, 0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args.2, 2 << 64 | 5);
+
+    // Overflowing the full 128 bits fails a guard.
+    let mut args = InterpCtx(u128::MAX, 0, 0);
+    assert!(!unsafe { ct.execute(&mut args).is_null() });
+}
+
 #[test]
 fn binop_other() {
     #[derive(Eq, PartialEq, Debug)]
@@ -310,6 +643,94 @@ fn binop_other() {
     assert_eq!(args, InterpCtx(5, 5, 10));
 }
 
+#[test]
+fn binop_signed_mul_div() {
+    #[derive(Eq, PartialEq, Debug)]
+    struct InterpCtx(i32, i32, i32);
+
+    #[interp_step]
+    fn interp_stepx(io: &mut InterpCtx) {
+        io.2 = io.0 * io.1 / 3;
+    }
+
+    let mut ctx = InterpCtx(2, 3, 0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_stepx(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+    let mut args = InterpCtx(-7, 5, 0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args, InterpCtx(-7, 5, -11));
+    let mut args = InterpCtx(9, -2, 0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args, InterpCtx(9, -2, -6));
+
+    // Signed multiplication overflow fails a guard.
+    let mut args = InterpCtx(i32::MAX, 2, 0);
+    assert!(!unsafe { ct.execute(&mut args).is_null() });
+}
+
+#[test]
+fn binop_shifts() {
+    #[derive(Eq, PartialEq, Debug)]
+    struct InterpCtx(u64, u32, u64, i16);
+
+    #[interp_step]
+    fn interp_stepx(io: &mut InterpCtx) {
+        io.2 = (io.0 << io.1) >> 1;
+        io.3 = io.3 >> io.1;
+    }
+
+    let mut ctx = InterpCtx(1, 2, 0, 8);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_stepx(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+    let mut args = InterpCtx(3, 4, 0, -64);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args, InterpCtx(3, 4, 24, -4));
+
+    // Shifting by at least the bit width overflows and fails a guard.
+    let mut args = InterpCtx(3, 70, 0, -64);
+    assert!(!unsafe { ct.execute(&mut args).is_null() });
+}
+
+#[test]
+fn binop_stack_operands() {
+    #[derive(Eq, PartialEq, Debug)]
+    struct InterpCtx(u64, u64, u64);
+
+    #[interp_step]
+    fn interp_stepx(io: &mut InterpCtx) {
+        io.2 = ((io.0 - io.1) * io.1 / 2) << io.1;
+    }
+
+    let mut ctx = InterpCtx(5, 1, 0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_stepx(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+
+    // With no registers available, every operand is read from memory.
+    let opts = CompileOptions {
+        register_pool: Some(Vec::new()),
+        max_stack_bytes: None,
+        loop_iterations: None,
+    };
+    let ct = compile_tir_trace_with_options(TirTrace::new(&sir_trace), &opts).unwrap();
+    let mut args = InterpCtx(10, 3, 0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args, InterpCtx(10, 3, 80));
+}
+
 #[test]
 fn ref_deref_simple() {
     #[derive(Debug)]
@@ -843,6 +1264,288 @@ fn comparison() {
     assert_eq!(args.1, true);
 }
 
+#[test]
+fn comparison_unsigned() {
+    #[derive(Debug, PartialEq)]
+    struct InterpCtx(u8, u8, bool, bool, bool, bool);
+
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        io.2 = io.0 < io.1;
+        io.3 = io.0 <= io.1;
+        io.4 = io.0 > io.1;
+        io.5 = io.0 >= io.1;
+    }
+
+    let mut ctx = InterpCtx(1, 2, false, false, false, false);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+
+    // These values would compare the other way around if treated as signed.
+    let mut args = InterpCtx(200, 100, false, false, false, false);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args, InterpCtx(200, 100, false, false, true, true));
+
+    let mut args = InterpCtx(7, 7, false, false, false, false);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args, InterpCtx(7, 7, false, true, false, true));
+}
+
+#[test]
+fn comparison_signed() {
+    #[derive(Debug, PartialEq)]
+    struct InterpCtx(i8, i8, bool, bool, bool, bool);
+
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        io.2 = io.0 < io.1;
+        io.3 = io.0 <= io.1;
+        io.4 = io.0 > io.1;
+        io.5 = io.0 >= io.1;
+    }
+
+    let mut ctx = InterpCtx(1, 2, false, false, false, false);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+
+    let mut args = InterpCtx(-100, 100, false, false, false, false);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args, InterpCtx(-100, 100, true, true, false, false));
+
+    let mut args = InterpCtx(-7, -7, false, false, false, false);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args, InterpCtx(-7, -7, false, true, false, true));
+}
+
+#[test]
+#[ignore = "ykrustc does not yet lower floats to SIR"]
+fn binop_f64() {
+    #[derive(Debug, PartialEq)]
+    struct InterpCtx(f64, f64, f64, f64, f64, f64);
+
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        io.2 = io.0 + io.1;
+        io.3 = io.0 - io.1;
+        io.4 = io.0 * io.1;
+        io.5 = io.0 / io.1;
+    }
+
+    let mut ctx = InterpCtx(1.0, 2.0, 0.0, 0.0, 0.0, 0.0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+
+    let mut args = InterpCtx(7.5, 2.5, 0.0, 0.0, 0.0, 0.0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args, InterpCtx(7.5, 2.5, 10.0, 5.0, 18.75, 3.0));
+}
+
+#[test]
+#[ignore = "ykrustc does not yet lower floats to SIR"]
+fn binop_f32() {
+    #[derive(Debug, PartialEq)]
+    struct InterpCtx(f32, f32, f32, f32, f32, f32);
+
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        io.2 = io.0 + io.1;
+        io.3 = io.0 - io.1;
+        io.4 = io.0 * io.1;
+        io.5 = io.0 / io.1;
+    }
+
+    let mut ctx = InterpCtx(1.0, 2.0, 0.0, 0.0, 0.0, 0.0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+
+    let mut args = InterpCtx(-3.0, 0.5, 0.0, 0.0, 0.0, 0.0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args, InterpCtx(-3.0, 0.5, -2.5, -3.5, -1.5, -6.0));
+}
+
+#[test]
+#[ignore = "ykrustc does not yet lower floats to SIR"]
+fn float_call() {
+    // Floats passed to, and returned from, a call which rustc doesn't inline.
+    #[derive(Debug, PartialEq)]
+    struct InterpCtx(f64, f64, f64);
+
+    #[inline(never)]
+    fn add_floats(a: f64, b: f64) -> f64 {
+        a + b
+    }
+
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        io.2 = add_floats(io.0, io.1);
+    }
+
+    let mut ctx = InterpCtx(1.0, 2.0, 0.0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+
+    // The result must be rounded exactly as IEEE 754 requires.
+    let mut args = InterpCtx(0.1, 0.2, 0.0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args.2.to_bits(), 0.30000000000000004f64.to_bits());
+
+    let mut args = InterpCtx(f64::INFINITY, f64::NEG_INFINITY, 0.0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert!(args.2.is_nan());
+}
+
+#[test]
+#[ignore = "ykrustc does not yet lower floats to SIR"]
+fn float_consts() {
+    #[derive(Debug, PartialEq)]
+    struct InterpCtx(f64, f64, f32);
+
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        io.1 = io.0 * -1.5;
+        io.2 = 0.25;
+    }
+
+    let mut ctx = InterpCtx(1.0, 0.0, 0.0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+
+    let mut args = InterpCtx(3.0, 0.0, 0.0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args, InterpCtx(3.0, -4.5, 0.25));
+}
+
+#[test]
+#[ignore = "ykrustc does not yet lower floats to SIR"]
+fn float_casts() {
+    #[derive(Debug, PartialEq)]
+    struct InterpCtx(f64, f32, i32, u8, f64, f32, i64, u16, f32, f64);
+
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        io.4 = io.1 as f64;
+        io.5 = io.0 as f32;
+        io.6 = io.0 as i64;
+        io.7 = io.1 as u16;
+        io.8 = io.2 as f32;
+        io.9 = io.3 as f64;
+    }
+
+    let mut ctx = InterpCtx(1.0, 2.0, 3, 4, 0.0, 0.0, 0, 0, 0.0, 0.0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+
+    let mut args = InterpCtx(-2.75, 300.5, -5, 200, 0.0, 0.0, 0, 0, 0.0, 0.0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(
+        args,
+        InterpCtx(-2.75, 300.5, -5, 200, 300.5, -2.75, -2, 300, -5.0, 200.0)
+    );
+
+    // Out of range floats saturate.
+    let mut args = InterpCtx(1e30, -1.0, 0, 0, 0.0, 0.0, 0, 0, 0.0, 0.0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(
+        args,
+        InterpCtx(1e30, -1.0, 0, 0, -1.0, f32::INFINITY, i64::MAX, 0, 0.0, 0.0)
+    );
+}
+
+#[test]
+fn div_by_power_of_two() {
+    // Unsigned division by a constant power of two is compiled to a right shift.
+    #[derive(Debug, PartialEq)]
+    struct InterpCtx(u64, u64, u8, u8);
+
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        io.1 = io.0 / 8;
+        io.3 = io.2 / 2;
+    }
+
+    let mut ctx = InterpCtx(16, 0, 4, 0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+
+    let mut args = InterpCtx(1001, 0, 255, 0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args, InterpCtx(1001, 125, 255, 127));
+}
+
+#[test]
+fn div_operand_in_rax_or_rdx() {
+    // Division uses RAX and RDX, which are also allocatable, so the divisor may live in either.
+    #[derive(Debug, PartialEq)]
+    struct InterpCtx(u64, u64, u64, i32, i32, i32);
+
+    #[interp_step]
+    #[inline(never)]
+    fn interp_step(io: &mut InterpCtx) {
+        io.2 = io.0 / io.1;
+        io.5 = io.3 / io.4;
+    }
+
+    let mut ctx = InterpCtx(10, 2, 0, 10, 2, 0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+
+    // With only RAX (0) or RDX (2) available, the operands of the division compete for it.
+    for pool in &[vec![0], vec![2], vec![0, 2]] {
+        let opts = CompileOptions {
+            register_pool: Some(pool.clone()),
+            ..Default::default()
+        };
+        let ct = compile_tir_trace_with_options(TirTrace::new(&sir_trace), &opts).unwrap();
+        let mut args = InterpCtx(1001, 7, 0, -1001, 7, 0);
+        assert!(unsafe { ct.execute(&mut args).is_null() });
+        assert_eq!(args, InterpCtx(1001, 7, 143, -1001, 7, -143));
+    }
+}
+
 #[test]
 fn guard() {
     struct InterpCtx(u8, u8);
@@ -878,6 +1581,32 @@ fn guard() {
     assert!(!ptr.is_null());
 }
 
+#[test]
+fn guard_comparison() {
+    struct InterpCtx(i64, i64);
+
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        let x = io.0;
+        io.1 = if x > 5 { x } else { x + 1 };
+    }
+
+    let mut ctx = InterpCtx(10, 0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+    let mut args = InterpCtx(10, 0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args.1, 10);
+    // The comparison is false, so the guard fails.
+    let mut args = InterpCtx(3, 0);
+    assert!(!unsafe { ct.execute(&mut args).is_null() });
+}
+
 #[test]
 fn matching() {
     struct InterpCtx(u8);
@@ -934,6 +1663,60 @@ fn cast() {
     assert_eq!(args.0, 1);
 }
 
+#[test]
+fn zero_extend() {
+    #[derive(Debug, PartialEq)]
+    struct InterpCtx(u8, u16, u32, u16, u32, u64, u32, u64, u64);
+
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        io.3 = io.0 as u16;
+        io.4 = io.0 as u32;
+        io.5 = io.0 as u64;
+        io.6 = io.1 as u32;
+        io.7 = io.1 as u64;
+        io.8 = io.2 as u64;
+    }
+
+    let mut ctx = InterpCtx(1, 2, 3, 0, 0, 0, 0, 0, 0);
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    interp_step(&mut ctx);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+
+    // The destinations start off with all bits set, so that a result stored with the source's,
+    // rather than the destination's, width shows up.
+    let mut args = InterpCtx(
+        0xff,
+        0xffff,
+        0xffff_ffff,
+        u16::MAX,
+        u32::MAX,
+        u64::MAX,
+        u32::MAX,
+        u64::MAX,
+        u64::MAX,
+    );
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(
+        args,
+        InterpCtx(
+            0xff,
+            0xffff,
+            0xffff_ffff,
+            0xff,
+            0xff,
+            0xff,
+            0xffff,
+            0xffff,
+            0xffff_ffff
+        )
+    );
+}
+
 #[test]
 fn vec_add() {
     struct InterpCtx {
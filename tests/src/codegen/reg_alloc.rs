@@ -124,6 +124,43 @@ fn reg_alloc_spills_and_frees() {
         .starts_with("Mem("));
 }
 
+// When registers are full, the local which dies last should be the one spilled.
+#[test]
+fn reg_alloc_spills_longest_lived() {
+    let types = TestTypes::new();
+    let num_regs = reg_pool_size() + 1; // Plus one for ICTX_REG.
+    let num_decls = num_regs + 2;
+    let mut local_decls = HashMap::new();
+    for i in 0..num_decls {
+        local_decls.insert(
+            Local(u32::try_from(i).unwrap()),
+            LocalDecl::new(types.t_u8, false),
+        );
+    }
+
+    let mut tc = TraceCompiler::new(local_decls);
+
+    // Fill registers. Local 3 lives the longest.
+    for l in 0..num_regs {
+        let local = Local(LocalIndex::try_from(l).unwrap());
+        tc.set_last_use(local, if l == 3 { 1000 } else { 100 });
+        assert!(tc.local_to_location_str(local).starts_with("Reg("));
+    }
+
+    // A local that dies before local 3 takes local 3's register.
+    let short = Local(LocalIndex::try_from(num_regs).unwrap());
+    tc.set_last_use(short, 50);
+    tc.local_live(short);
+    assert!(tc.local_to_location_str(short).starts_with("Reg("));
+    assert!(tc.local_to_location_str(Local(3)).starts_with("Mem("));
+
+    // A local that outlives everything in a register is itself spilled.
+    let long = Local(LocalIndex::try_from(num_regs + 1).unwrap());
+    tc.set_last_use(long, 2000);
+    tc.local_live(long);
+    assert!(tc.local_to_location_str(long).starts_with("Mem("));
+}
+
 // Test cases where a local is allocated on the stack even if registers are available.
 #[test]
 fn reg_alloc_always_on_stack() {
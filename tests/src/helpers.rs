@@ -7,6 +7,28 @@ use ykshim_client::{sir_body_ret_ty, TirTrace, TypeId};
 extern "C" {
     pub fn add6(a: u64, b: u64, c: u64, d: u64, e: u64, f: u64) -> u64;
 }
+extern "C" {
+    pub fn add7(a: u64, b: u64, c: u64, d: u64, e: u64, f: u64, g: u64) -> u64;
+}
+extern "C" {
+    pub fn add8(a: u64, b: u64, c: u64, d: u64, e: u64, f: u64, g: u64, h: u64) -> u64;
+}
+extern "C" {
+    pub fn wsum12(
+        a: u64,
+        b: u64,
+        c: u64,
+        d: u64,
+        e: u64,
+        f: u64,
+        g: u64,
+        h: u64,
+        i: u64,
+        j: u64,
+        k: u64,
+        l: u64,
+    ) -> u64;
+}
 extern "C" {
     pub fn add_some(a: u64, b: u64, c: u64, d: u64, e: u64) -> u64;
 }
@@ -10,9 +10,8 @@ fn find_symbol_shared() {
     assert!(find_symbol("printf") == libc::printf as *mut c_void);
 }
 
-// Test finding a symbol in the main binary.
-// For this to work the binary must have been linked with `--export-dynamic`, which ykrustc
-// appends to the linker command line.
+// Test finding a symbol in the main binary. ykrustc links binaries with `--export-dynamic`, but
+// even without it the symbol can be found in the binary's symbol table.
 #[test]
 #[no_mangle]
 fn find_symbol_main() {
@@ -6,6 +6,29 @@ add6(uint64_t a, uint64_t b, uint64_t c, uint64_t d, uint64_t e, uint64_t f)
     return a + b + c + d + e + f;
 }
 
+uint64_t
+add7(uint64_t a, uint64_t b, uint64_t c, uint64_t d, uint64_t e, uint64_t f,
+     uint64_t g)
+{
+    return a + b + c + d + e + f + g;
+}
+
+uint64_t
+add8(uint64_t a, uint64_t b, uint64_t c, uint64_t d, uint64_t e, uint64_t f,
+     uint64_t g, uint64_t h)
+{
+    return a + b + c + d + e + f + g + h;
+}
+
+// Returns a weighted sum, so that passing the arguments in the wrong order is detected.
+uint64_t
+wsum12(uint64_t a, uint64_t b, uint64_t c, uint64_t d, uint64_t e, uint64_t f,
+       uint64_t g, uint64_t h, uint64_t i, uint64_t j, uint64_t k, uint64_t l)
+{
+    return a + 2 * b + 3 * c + 4 * d + 5 * e + 6 * f + 7 * g + 8 * h + 9 * i +
+           10 * j + 11 * k + 12 * l;
+}
+
 uint64_t
 add_some(uint64_t a, uint64_t b, uint64_t c, uint64_t d, uint64_t e)
 {
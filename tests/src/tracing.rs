@@ -3,7 +3,9 @@
 // FIXME hard-coded hardware testing. Use default mode, but requires more shimming.
 
 use std::{hint::black_box, thread};
-use ykshim_client::{start_tracing, TracingKind};
+use ykshim_client::{
+    compile_trace, is_tracing, start_tracing, start_tracing_with_budget, tracing_depth, TracingKind,
+};
 
 // Some work to trace.
 #[interp_step]
@@ -52,6 +54,28 @@ fn trace_twice() {
     assert!(trace1.len() < trace2.len());
 }
 
+/// Test that a trace's blocks identify the path it took.
+#[test]
+fn trace_blocks() {
+    #[cfg(tracermode = "hw")]
+    let kind = TracingKind::HardwareTracing;
+    #[cfg(tracermode = "sw")]
+    let kind = TracingKind::SoftwareTracing;
+
+    let trace = |n| {
+        let th = start_tracing(kind);
+        black_box(work(&mut InterpCtx(n)));
+        th.stop_tracing().unwrap()
+    };
+    let (trace1, trace2, trace3) = (trace(10), trace(10), trace(20));
+
+    let blocks = trace1.blocks();
+    assert_eq!(blocks.len(), trace1.len());
+    assert_eq!(blocks, trace2.blocks());
+    assert_eq!(trace1.block_hash(), trace2.block_hash());
+    assert_ne!(blocks, trace3.blocks());
+}
+
 /// Test that tracing in different threads works.
 #[test]
 pub(crate) fn trace_concurrent() {
@@ -73,3 +97,108 @@ pub(crate) fn trace_concurrent() {
     let len1 = thr.join().unwrap();
     assert!(len1 < len2);
 }
+
+/// Test that a trace within its block budget is kept whole, so that the compiled trace performs
+/// the entire interpreter step.
+#[test]
+fn trace_with_budget() {
+    #[derive(Debug, PartialEq)]
+    struct InterpCtx(u64, u64);
+
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        for _ in 0..4 {
+            io.0 += 1;
+        }
+        io.1 = io.0 * 2;
+    }
+
+    #[cfg(tracermode = "hw")]
+    let kind = TracingKind::HardwareTracing;
+    #[cfg(tracermode = "sw")]
+    let kind = TracingKind::SoftwareTracing;
+
+    let th = start_tracing(kind);
+    interp_step(&mut InterpCtx(0, 0));
+    let len = th.stop_tracing().unwrap().len();
+
+    let th = start_tracing_with_budget(kind, len);
+    interp_step(&mut InterpCtx(0, 0));
+    let sir_trace = th.stop_tracing().unwrap();
+    assert_eq!(sir_trace.len(), len);
+    let ct = compile_trace(sir_trace).unwrap();
+
+    let mut args = InterpCtx(10, 0);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args, InterpCtx(14, 28));
+}
+
+/// Test that a trace which exceeds its block budget is rejected rather than cut short.
+#[test]
+fn trace_too_long() {
+    #[cfg(tracermode = "hw")]
+    let kind = TracingKind::HardwareTracing;
+    #[cfg(tracermode = "sw")]
+    let kind = TracingKind::SoftwareTracing;
+
+    let th = start_tracing(kind);
+    black_box(work(&mut InterpCtx(10)));
+    let len = th.stop_tracing().unwrap().len();
+
+    let th = start_tracing_with_budget(kind, len - 1);
+    black_box(work(&mut InterpCtx(10)));
+    let err = th.stop_tracing().err().unwrap();
+    assert_eq!(err.to_str().unwrap(), "Trace too long");
+}
+
+/// Test that a thread knows whether it is being traced.
+#[test]
+fn is_tracing_status() {
+    #[cfg(tracermode = "hw")]
+    let kind = TracingKind::HardwareTracing;
+    #[cfg(tracermode = "sw")]
+    let kind = TracingKind::SoftwareTracing;
+
+    assert!(!is_tracing());
+    assert_eq!(tracing_depth(), 0);
+    let th = start_tracing(kind);
+    assert!(is_tracing());
+    assert_eq!(tracing_depth(), 1);
+    // Other threads aren't affected.
+    assert!(!thread::spawn(is_tracing).join().unwrap());
+    th.stop_tracing().unwrap();
+    assert!(!is_tracing());
+    assert_eq!(tracing_depth(), 0);
+
+    // Dropping a tracer also stops tracing.
+    let th = start_tracing(kind);
+    drop(th);
+    assert!(!is_tracing());
+}
+
+/// Test that calling `is_tracing` from traced code leaves a trace which can be compiled and run.
+#[test]
+fn is_tracing_in_trace() {
+    struct InterpCtx(usize, bool);
+
+    #[interp_step]
+    fn interp_step(io: &mut InterpCtx) {
+        io.0 += 1;
+        io.1 = is_tracing();
+    }
+
+    #[cfg(tracermode = "hw")]
+    let th = start_tracing(TracingKind::HardwareTracing);
+    #[cfg(tracermode = "sw")]
+    let th = start_tracing(TracingKind::SoftwareTracing);
+    let mut ctx = InterpCtx(0, false);
+    interp_step(&mut ctx);
+    assert!(ctx.1);
+    let sir_trace = th.stop_tracing().unwrap();
+    let ct = compile_trace(sir_trace).unwrap();
+
+    let mut args = InterpCtx(10, true);
+    assert!(unsafe { ct.execute(&mut args).is_null() });
+    assert_eq!(args.0, 11);
+    assert!(!args.1);
+}
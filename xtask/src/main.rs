@@ -7,8 +7,9 @@
 //! https://softdevteam.github.io/ykdocs/tech/yk_structure.html
 
 use std::{
-    env,
-    path::PathBuf,
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
     process::{exit, Command},
 };
 
@@ -79,9 +80,9 @@ fn run_action(workspace: Workspace, target: &str, extra_args: &[String]) {
             }
         }
         "bench" | "build" | "check" | "clippy" | "test" => {
-            // Ensure that the whole workspace is tested and not just the base crate in the
-            // workspace.
-            if target == "test" {
+            // Ensure that the whole workspace is tested (or benchmarked) and not just the base
+            // crate in the workspace.
+            if target == "test" || target == "bench" {
                 cmd.arg("--workspace");
             }
 
@@ -95,13 +96,15 @@ fn run_action(workspace: Workspace, target: &str, extra_args: &[String]) {
                 }
                 // Set the tracermode cfg macro, but without changing anything relating to code
                 // generation. We can't use `-C tracer=hw` as this would turn off optimisations
-                // and emit SIR for stuff we will never trace.
+                // and emit SIR for stuff we will never trace. We also always expose the testing
+                // API, as the external workspace's `tests` crate links against it.
                 cmd.arg("--features");
-                cmd.arg(format!("yktrace/trace_{}", tracing_kind));
+                cmd.arg(format!("yktrace/trace_{},ykshim/yk_testing", tracing_kind));
 
-                // `cargo test` in the internal workspace won't build libykshim.so, so we have
-                // to force-build it to avoid linkage problems for the external workspace.
-                if target == "test" {
+                // `cargo test` and `cargo bench` in the internal workspace won't build
+                // libykshim.so, so we have to force-build it to avoid linkage problems for the
+                // external workspace.
+                if target == "test" || target == "bench" {
                     run_action(Workspace::Internal, "build", &[]);
                 }
             } else if workspace == Workspace::External && target == "clippy" {
@@ -133,6 +136,139 @@ fn run_action(workspace: Workspace, target: &str, extra_args: &[String]) {
     }
 }
 
+/// The fuzz targets in `internal_ws/fuzz`.
+const FUZZ_TARGETS: [&str; 1] = ["fuzz_ykpack"];
+
+/// Run each fuzz target in turn with `cargo fuzz`, passing `extra_args` to `cargo fuzz run`. With
+/// no arguments, each target is run for five minutes.
+fn run_fuzz(extra_args: &[String]) {
+    let this_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let ws_dir = [&this_dir, "..", "internal_ws"].iter().collect::<PathBuf>();
+    let default_args = ["--".to_owned(), "-max_total_time=300".to_owned()];
+    let args = if extra_args.is_empty() {
+        &default_args
+    } else {
+        extra_args
+    };
+
+    for target in &FUZZ_TARGETS {
+        // cargo-fuzz only works with a nightly toolchain. See `run_action` for why we use rustup.
+        let mut cmd = Command::new("rustup");
+        cmd.args(&["run", "nightly", "cargo", "fuzz", "run", target])
+            .args(args)
+            .current_dir(&ws_dir);
+        let status = cmd.spawn().unwrap().wait().unwrap();
+        if !status.success() {
+            bail(format!("{:?} failed with {}", cmd, status));
+        }
+    }
+}
+
+/// Run the tests of both workspaces with coverage instrumentation, and write an LCOV report of
+/// the combined coverage to `target/coverage/lcov.info`. If `--open` is passed, an HTML report is
+/// also generated and opened in a browser. Any other arguments are passed to `cargo test`.
+/// Requires `-Z instrument-coverage`, `llvm-profdata` and `llvm-cov`, so CI doesn't run this.
+fn run_coverage(extra_args: &[String]) {
+    let open = extra_args.iter().any(|a| a == "--open");
+    let test_args = extra_args
+        .iter()
+        .filter(|a| *a != "--open")
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let this_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let root_dir = [&this_dir, ".."].iter().collect::<PathBuf>();
+    let cov_dir = root_dir.join("target").join("coverage");
+    // Remove the profiles of any previous run, as they may refer to stale binaries.
+    let _ = fs::remove_dir_all(&cov_dir);
+    fs::create_dir_all(&cov_dir).unwrap();
+
+    // `run_action` picks these up, and passes them on to both workspaces. `%p` and `%m` stop
+    // concurrently running test binaries from overwriting each other's profiles.
+    let rust_flags = env::var("RUSTFLAGS").unwrap_or_else(|_| String::new());
+    env::set_var(
+        "RUSTFLAGS",
+        format!("{} -Z instrument-coverage", rust_flags),
+    );
+    env::set_var("LLVM_PROFILE_FILE", cov_dir.join("yk-%p-%m.profraw"));
+    run_action(Workspace::External, "test", &test_args);
+
+    let profraws = fs::read_dir(&cov_dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().map_or(false, |e| e == "profraw"))
+        .collect::<Vec<_>>();
+    let profdata = cov_dir.join("yk.profdata");
+    let mut cmd = Command::new("llvm-profdata");
+    cmd.args(&["merge", "-sparse"])
+        .args(&profraws)
+        .arg("-o")
+        .arg(&profdata);
+    run_coverage_tool(&mut cmd);
+
+    // The instrumented code is in the test binaries of both workspaces and in libykshim.so.
+    let internal_target = root_dir.join("internal_ws").join("target").join("release");
+    let mut objects = vec![internal_target.join("libykshim.so")];
+    for deps_dir in &[
+        internal_target.join("deps"),
+        root_dir.join("target").join("debug").join("deps"),
+    ] {
+        objects.extend(test_binaries(deps_dir));
+    }
+    let mut object_args = Vec::new();
+    for o in &objects {
+        object_args.push("-object".to_owned());
+        object_args.push(o.to_str().unwrap().to_owned());
+    }
+    let profile_arg = format!("-instr-profile={}", profdata.to_str().unwrap());
+
+    let lcov = cov_dir.join("lcov.info");
+    let mut cmd = Command::new("llvm-cov");
+    cmd.args(&["export", "-format=lcov", &profile_arg])
+        .args(&object_args);
+    let out = cmd.output().unwrap();
+    if !out.status.success() {
+        bail(format!("{:?} failed with {}", cmd, out.status));
+    }
+    fs::write(&lcov, out.stdout).unwrap();
+    eprintln!("xtask: coverage report written to {}", lcov.display());
+
+    if open {
+        let html_dir = cov_dir.join("html");
+        let mut cmd = Command::new("llvm-cov");
+        cmd.args(&["show", "-format=html", &profile_arg])
+            .arg(format!("-output-dir={}", html_dir.to_str().unwrap()))
+            .args(&object_args);
+        run_coverage_tool(&mut cmd);
+        run_coverage_tool(Command::new("xdg-open").arg(html_dir.join("index.html")));
+    }
+}
+
+/// Run `cmd`, bailing out if it fails.
+fn run_coverage_tool(cmd: &mut Command) {
+    let status = cmd.spawn().unwrap().wait().unwrap();
+    if !status.success() {
+        bail(format!("{:?} failed with {}", cmd, status));
+    }
+}
+
+/// Return the executable files directly inside `dir`, which are taken to be test binaries.
+fn test_binaries(dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .map(|e| e.unwrap().path())
+        .filter(|p| {
+            p.extension().is_none()
+                && p.metadata().map_or(false, |m| {
+                    m.is_file() && m.permissions().mode() & 0o111 != 0
+                })
+        })
+        .collect()
+}
+
 fn bail(err_str: String) -> ! {
     eprintln!("xtask: {}", err_str);
     exit(1);
@@ -144,5 +280,13 @@ fn main() {
     let target = args.next().unwrap();
     let extra_args = args.collect::<Vec<_>>();
 
+    if target == "fuzz" {
+        run_fuzz(&extra_args);
+        return;
+    }
+    if target == "coverage" {
+        run_coverage(&extra_args);
+        return;
+    }
     run_action(Workspace::External, &target, &extra_args);
 }
@@ -4,9 +4,11 @@
 
 mod location;
 pub mod mt;
+mod ykstats;
 
 pub use self::location::Location;
-pub use self::mt::{MTBuilder, MT};
+pub use self::mt::{CompileFn, GuardId, MTBuilder, MT};
+pub use self::ykstats::YkStats;
 
 /// A debugging aid for traces.
 /// Calls to this function are recognised by Yorick and a special debug TIR statement is inserted
@@ -1,6 +1,7 @@
 //! Trace location: track the state of a program location (counting, tracing, compiled, etc).
 
 use std::{
+    ffi::CString,
     marker::PhantomData,
     sync::{
         atomic::{AtomicUsize, Ordering},
@@ -10,7 +11,8 @@ use std::{
 
 use parking_lot::Mutex;
 use parking_lot_core::{
-    park, unpark_one, ParkResult, SpinWait, UnparkResult, UnparkToken, DEFAULT_PARK_TOKEN,
+    park, unpark_all, unpark_one, ParkResult, SpinWait, UnparkResult, UnparkToken,
+    DEFAULT_PARK_TOKEN,
 };
 use strum::EnumDiscriminants;
 
@@ -48,9 +50,9 @@ pub struct Location<I> {
     //  │             │ start compiling trace
     //  │             │ in thread
     //  │             ▼
-    //  │           ┌──────────────┐
-    //  |           |  Compiling   |
-    //  │           └──────────────┘
+    //  │           ┌──────────────┐ compilation ┌─────────────┐
+    //  |           |  Compiling   |────────────▶|  DontTrace  |
+    //  │           └──────────────┘ failed      └─────────────┘
     //  │             │
     //  │             │ trace compiled
     //  │             ▼
@@ -140,7 +142,9 @@ impl<I> Location<I> {
     pub(crate) fn lock(&self) -> Result<State<I>, ()> {
         {
             let ls = self.load(Ordering::Relaxed);
-            debug_assert!(!ls.is_counting());
+            if ls.is_counting() {
+                return Err(());
+            }
             let new_ls = ls.with_lock().with_unparked();
             if self
                 .state
@@ -265,11 +269,30 @@ impl<I> Location<I> {
         }
     }
 
-    /// Try obtaining the lock, returning the new `State` if successful.
+    /// Unlocks this `State`, moving it back to the Counting state with a count of 0 (i.e. the
+    /// "reprofile" transition), and returns the now-detached `HotLocation`, which the caller is
+    /// responsible for freeing. Threads parked waiting for the lock are woken up, and will observe
+    /// that the location is now counting.
+    pub(crate) fn unlock_reprofile(&self) -> *mut HotLocation<I> {
+        let ls = self.load(Ordering::Relaxed);
+        debug_assert!(ls.is_locked());
+        debug_assert!(!ls.is_counting());
+        let hl_ptr = unsafe { ls.hot_location() } as *mut HotLocation<I>;
+        let old = State::<I>::from_usize(self.state.swap(State::<I>::new().x, Ordering::Release));
+        if old.is_parked() {
+            unsafe {
+                unpark_all(hl_ptr as usize, TOKEN_NORMAL);
+            }
+        }
+        hl_ptr
+    }
+
+    /// Try obtaining the lock, returning the new `State` if successful. This fails if the lock is
+    /// held by another thread or if the location is in the Counting state.
     pub(crate) fn try_lock(&self) -> Result<State<I>, ()> {
         let mut ls = self.load(Ordering::Relaxed);
         loop {
-            if ls.is_locked() {
+            if ls.is_locked() || ls.is_counting() {
                 return Err(());
             }
             let new_ls = ls.with_lock();
@@ -428,8 +451,10 @@ pub(crate) struct ThreadIdInner;
 /// A `Location`'s non-counting states.
 #[derive(EnumDiscriminants)]
 pub(crate) enum HotLocation<I> {
-    Compiled(Box<CompiledTrace<I>>),
-    Compiling(Arc<Mutex<Option<Box<CompiledTrace<I>>>>>),
+    Compiled(Arc<CompiledTrace<I>>),
+    /// Once compilation has finished, the mutex holds the compiled trace, or the reason that
+    /// compilation failed.
+    Compiling(Arc<Mutex<Option<Result<Arc<CompiledTrace<I>>, CString>>>>),
     DontTrace,
     Tracing(Option<(Arc<ThreadIdInner>, ThreadTracer)>),
 }
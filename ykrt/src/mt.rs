@@ -3,35 +3,61 @@
 #[cfg(test)]
 use std::time::Duration;
 use std::{
-    io,
+    any::Any,
+    collections::{HashMap, HashSet},
+    env,
+    ffi::CString,
+    fs, io,
     marker::PhantomData,
     mem,
-    panic::{catch_unwind, resume_unwind, UnwindSafe},
+    panic::{catch_unwind, resume_unwind, RefUnwindSafe, UnwindSafe},
+    path::Path,
     ptr,
     rc::Rc,
     sync::{
-        atomic::{AtomicBool, AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
         Arc,
     },
     thread::{self, JoinHandle},
+    time::Instant,
 };
 
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use parking_lot_core::SpinWait;
 
-use crate::location::{HotLocation, Location, State, ThreadIdInner};
+use crate::{
+    location::{HotLocation, Location, State, ThreadIdInner},
+    ykstats::{Stats, YkStats},
+};
 use ykshim_client::{
-    compile_trace, start_tracing, RawStopgapInterpreter, StopgapInterpreter, TracingKind,
+    compile_trace, install_trace_dump_hook, start_tracing, CompiledTrace, RawStopgapInterpreter,
+    SirTrace, StopgapInterpreter, TracingKind,
 };
 
 pub type HotThreshold = usize;
 const DEFAULT_HOT_THRESHOLD: HotThreshold = 50;
+const DEFAULT_GUARD_DEOPT_THRESHOLD: u64 = 50;
+
+/// A function which compiles a SIR trace, as registered with
+/// [`MT::set_compile_fn`](struct.MT.html#method.set_compile_fn).
+pub type CompileFn = Box<dyn Fn(SirTrace) -> Result<CompiledTrace<()>, CString> + Send + Sync>;
+
+/// Identifies a guard in a compiled trace.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct GuardId {
+    /// The block hash of the SIR trace the compiled trace was built from.
+    pub trace: u64,
+    /// The index of the guard within the compiled trace.
+    pub guard: usize,
+}
 
 /// Configure a meta-tracer. Note that a process can only have one meta-tracer active at one point.
 pub struct MTBuilder {
     hot_threshold: HotThreshold,
     /// The kind of tracer to use.
     tracing_kind: TracingKind,
+    /// Should traces be compiled in a separate thread?
+    background_compilation: bool,
 }
 
 impl MTBuilder {
@@ -43,13 +69,18 @@ impl MTBuilder {
             tracing_kind: TracingKind::HardwareTracing,
             #[cfg(tracermode = "sw")]
             tracing_kind: TracingKind::SoftwareTracing,
+            background_compilation: true,
         }
     }
 
     /// Consume the `MTBuilder` and create a meta-tracer, returning the
     /// [`MTThread`](struct.MTThread.html) representing the current thread.
     pub fn init(self) -> MTThread {
-        MTInner::init(self.hot_threshold, self.tracing_kind)
+        MTInner::init(
+            self.hot_threshold,
+            self.tracing_kind,
+            self.background_compilation,
+        )
     }
 
     /// Change this meta-tracer builder's `hot_threshold` value.
@@ -63,6 +94,14 @@ impl MTBuilder {
         self.tracing_kind = tracing_kind;
         self
     }
+
+    /// Select whether traces are compiled in a separate thread (the default), leaving the thread
+    /// which recorded the trace free to carry on interpreting, or by the thread which recorded the
+    /// trace, in which case the compiled trace is run the next time the location is visited.
+    pub fn background_compilation(mut self, enabled: bool) -> Self {
+        self.background_compilation = enabled;
+        self
+    }
 }
 
 #[derive(Clone)]
@@ -80,7 +119,139 @@ impl MT {
 
     /// Return the kind of tracing that this meta-tracer is using.
     pub fn tracing_kind(&self) -> TracingKind {
-        self.inner.tracing_kind
+        match self.inner.tracing_kind.load(Ordering::Relaxed) {
+            x if x == TracingKind::SoftwareTracing as u8 => TracingKind::SoftwareTracing,
+            x if x == TracingKind::HardwareTracing as u8 => TracingKind::HardwareTracing,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Set the kind of tracing that this meta-tracer uses. This only affects traces started after
+    /// the call. Note that the tracing kind must be supported by the tracer the internal workspace
+    /// was compiled with.
+    pub fn set_tracing_kind(&self, tracing_kind: TracingKind) {
+        self.inner
+            .tracing_kind
+            .store(tracing_kind as u8, Ordering::Relaxed);
+    }
+
+    /// Return the number of `Location`s which have become hot (i.e. have started being traced)
+    /// under this meta-tracer. Since `Location`s are owned by the interpreter, and can be freely
+    /// moved, the meta-tracer cannot enumerate them: this count is a cheap approximation intended
+    /// for profiling. Locations which are subsequently dropped are still counted.
+    pub fn hot_location_count(&self) -> usize {
+        self.inner.hot_locations.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of times a newly recorded trace was found to visit the same blocks as an
+    /// already compiled trace, allowing the compiled trace to be reused.
+    pub fn cache_hit_count(&self) -> usize {
+        self.inner.cache_hits.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of times a newly recorded trace had to be compiled because no compiled
+    /// trace visiting the same blocks was available.
+    pub fn cache_miss_count(&self) -> usize {
+        self.inner.cache_misses.load(Ordering::Relaxed)
+    }
+
+    /// Return a snapshot of this meta-tracer's statistics. If `YKD_PRINT_STATS=1`, the statistics
+    /// are also printed to stderr when the meta-tracer is dropped.
+    pub fn stats(&self) -> YkStats {
+        self.inner.stats.snapshot()
+    }
+
+    /// Return the number of TIR ops of each kind (e.g. `"BinaryOp"` or `"Guard"`) in all the traces
+    /// this meta-tracer has compiled. If `YKD_PRINT_IR_HISTOGRAM=1`, the histogram is also printed
+    /// to stderr when the meta-tracer is dropped.
+    pub fn trace_op_histogram(&self) -> HashMap<String, usize> {
+        self.inner.stats.op_histogram()
+    }
+
+    /// Return, for every guard in every cached compiled trace, the number of times that guard has
+    /// failed and handed over to the stopgap interpreter. The result is sorted by `GuardId`.
+    pub fn guard_stats(&self) -> Vec<(GuardId, u64)> {
+        self.inner.guard_stats()
+    }
+
+    /// Set the number of failures after which a guard is considered hot and reported to the
+    /// callback registered with [`on_hot_guard`](#method.on_hot_guard).
+    pub fn set_guard_deopt_threshold(&self, threshold: u64) {
+        self.inner
+            .guard_deopt_threshold
+            .store(threshold, Ordering::Relaxed);
+    }
+
+    /// Register a callback to be called, once per guard, when a guard's failure count reaches the
+    /// guard deoptimisation threshold. Replaces any previously registered callback. The callback
+    /// is run on the thread whose deoptimisation made the guard hot.
+    pub fn on_hot_guard(&self, f: Box<dyn Fn(GuardId) + Send + Sync>) {
+        *self.inner.hot_guard_callback.lock() = Some(Arc::from(f));
+    }
+
+    /// Write the disassembly of each cached compiled trace to `dir/trace_<hash>.asm`, where
+    /// `<hash>` is the block hash of the SIR trace it was compiled from, creating `dir` if
+    /// necessary. If `YKD_DUMP_TRACES_ON_DROP=<dir>`, this is also done when the meta-tracer is
+    /// dropped. Requires `rasm2`.
+    pub fn dump_traces_to_dir(&self, dir: &Path) -> io::Result<()> {
+        self.inner.dump_traces_to_dir(dir)
+    }
+
+    /// Compile traces with `f` rather than with the default trace compiler. Traces which have
+    /// already been compiled, or are being compiled, are unaffected. The compiled traces `f`
+    /// returns are treated as taking the interpreter context type the traces were recorded with.
+    ///
+    /// # Safety
+    ///
+    /// Each compiled trace `f` returns must access the interpreter context according to the
+    /// layout of the context type the SIR trace it was given was recorded with, e.g. because it
+    /// was compiled from that SIR trace.
+    pub unsafe fn set_compile_fn(&self, f: CompileFn) {
+        *self.inner.compile_fn.write() = Some(f);
+    }
+
+    /// Evict the compiled trace associated with `loc`, e.g. because the code it was traced from
+    /// has been unloaded or redefined. `loc` is returned to the counting state, so that it can
+    /// later be traced and compiled afresh, and the trace is removed from the compiled trace cache.
+    /// Threads already executing the trace are unaffected: its code is freed once the last of
+    /// them has finished with it. Returns `true` if `loc` had a compiled trace.
+    pub fn invalidate_trace<I>(&self, loc: &Location<I>) -> bool {
+        if loc.load(Ordering::Relaxed).is_counting() {
+            return false;
+        }
+        let ls = match loc.lock() {
+            Ok(ls) => ls,
+            Err(()) => return false,
+        };
+        if !matches!(unsafe { ls.hot_location() }, HotLocation::Compiled(_)) {
+            loc.unlock();
+            return false;
+        }
+        // Once `loc` is back in the counting state, no new executions of the trace can start.
+        let hl = unsafe { Box::from_raw(loc.unlock_reprofile()) };
+        if let HotLocation::Compiled(tr) = *hl {
+            let tr_ptr = Arc::as_ptr(&tr) as *const ();
+            self.inner
+                .compiled_traces
+                .lock()
+                .retain(|_, e| !ptr::eq(Arc::as_ptr(&e.trace) as *const (), tr_ptr));
+        }
+        true
+    }
+
+    /// Compile `sir` in a new thread, calling `on_done` (from that thread) with the compiled
+    /// trace, or the reason that compilation failed. The compiled trace is also cached, so that
+    /// later traces visiting the same blocks reuse it; if an identical trace has already been
+    /// cached, `on_done` is passed that instead.
+    pub fn compile_in_background<I: Send + 'static>(
+        &self,
+        sir: SirTrace,
+        on_done: Box<dyn FnOnce(Result<Arc<CompiledTrace<I>>, CString>) + Send>,
+    ) {
+        let mt_inner = Arc::clone(&self.inner);
+        thread::spawn(move || {
+            on_done(mt_inner.compile_and_cache(sir));
+        });
     }
 
     /// Create a new thread that can be used in the meta-tracer: the new thread that is created is
@@ -114,7 +285,41 @@ impl Drop for MT {
 struct MTInner {
     hot_threshold: AtomicUsize,
     active_threads: AtomicUsize,
-    tracing_kind: TracingKind,
+    tracing_kind: AtomicU8,
+    /// The number of `Location`s which have become hot.
+    hot_locations: AtomicUsize,
+    /// Compiled traces, keyed by the block hash of the SIR trace they were compiled from. Entries
+    /// are only removed by `MT::invalidate_trace`, so the cache grows with the number of distinct
+    /// traces compiled.
+    compiled_traces: Mutex<HashMap<u64, CacheEntry>>,
+    cache_hits: AtomicUsize,
+    cache_misses: AtomicUsize,
+    stats: Stats,
+    guard_deopt_threshold: AtomicU64,
+    hot_guard_callback: Mutex<Option<Arc<dyn Fn(GuardId) + Send + Sync>>>,
+    /// Guards which have already been reported to `hot_guard_callback`.
+    hot_guards: Mutex<HashSet<GuardId>>,
+    /// If set, used to compile traces instead of `compile_trace`.
+    compile_fn: RwLock<Option<CompileFn>>,
+    /// Are traces compiled in a separate thread?
+    background_compilation: bool,
+}
+
+// `compiled_traces` and `hot_guards` are only ever mutated by single insertions, and
+// `hot_guard_callback` and `compile_fn` by single assignments, so a panic cannot leave them in an
+// inconsistent state.
+impl RefUnwindSafe for MTInner {}
+
+impl Drop for MTInner {
+    fn drop(&mut self) {
+        self.stats.print_if_requested();
+        if let Some(dir) = env::var_os("YKD_DUMP_TRACES_ON_DROP") {
+            let dir = Path::new(&dir);
+            if let Err(e) = self.dump_traces_to_dir(dir) {
+                eprintln!("Failed to dump traces to {}: {}", dir.display(), e);
+            }
+        }
+    }
 }
 
 /// It's only safe to have one `MT` instance active at a time.
@@ -122,7 +327,11 @@ static MT_ACTIVE: AtomicBool = AtomicBool::new(false);
 
 impl MTInner {
     /// Create a new `MT`, wrapped immediately in an [`MTThread`](struct.MTThread.html).
-    fn init(hot_threshold: HotThreshold, tracing_kind: TracingKind) -> MTThread {
+    fn init(
+        hot_threshold: HotThreshold,
+        tracing_kind: TracingKind,
+        background_compilation: bool,
+    ) -> MTThread {
         // A process can only have a single MT instance.
 
         // In non-testing, we panic if the user calls this method while an MT instance is active.
@@ -145,16 +354,199 @@ impl MTInner {
             }
         }
 
+        if env::var_os("YKD_DUMP_TRACE_ON_ABORT").is_some() {
+            install_trace_dump_hook();
+        }
+
         let mtc = Self {
             hot_threshold: AtomicUsize::new(hot_threshold),
             active_threads: AtomicUsize::new(1),
-            tracing_kind,
+            tracing_kind: AtomicU8::new(tracing_kind as u8),
+            hot_locations: AtomicUsize::new(0),
+            compiled_traces: Mutex::new(HashMap::new()),
+            cache_hits: AtomicUsize::new(0),
+            cache_misses: AtomicUsize::new(0),
+            stats: Stats::new(),
+            guard_deopt_threshold: AtomicU64::new(DEFAULT_GUARD_DEOPT_THRESHOLD),
+            hot_guard_callback: Mutex::new(None),
+            compile_fn: RwLock::new(None),
+            hot_guards: Mutex::new(HashSet::new()),
+            background_compilation,
         };
         let mt = MT {
             inner: Arc::new(mtc),
         };
         MTThreadInner::init(mt)
     }
+
+    /// If a trace visiting the same blocks as `sir` has already been compiled for interpreter
+    /// context `I`, return it. Note that a trace which is still being compiled is not found.
+    fn cached_trace<I: Send + 'static>(&self, sir: &SirTrace) -> Option<Arc<CompiledTrace<I>>> {
+        let key = sir.block_hash();
+        let blocks = sir.blocks();
+        let tr = self
+            .compiled_traces
+            .lock()
+            .get(&key)
+            .filter(|e| e.blocks == blocks)
+            .and_then(|e| {
+                Arc::clone(&e.trace)
+                    .into_any()
+                    .downcast::<CompiledTrace<I>>()
+                    .ok()
+            });
+        if tr.is_some() {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+        tr
+    }
+
+    /// Compile `sir` with the function registered with `MT::set_compile_fn`, or with the default
+    /// trace compiler if there is none.
+    fn compile<I>(&self, sir: SirTrace) -> Result<CompiledTrace<I>, CString> {
+        match &*self.compile_fn.read() {
+            // `sir` was recorded from the interpreter whose context has type `I`, and the caller of
+            // `set_compile_fn` promised that `f` returns traces for that context type.
+            Some(f) => f(sir).map(|ct| unsafe { ct.cast() }),
+            None => compile_trace::<I>(sir),
+        }
+    }
+
+    /// Compile `sir`, recording statistics about the compiled trace, and cache it. If an identical
+    /// trace has already been cached, that is returned instead. If a different trace with the
+    /// same block hash has been cached, the new trace is returned without being cached.
+    fn compile_and_cache<I: Send + 'static>(
+        &self,
+        sir: SirTrace,
+    ) -> Result<Arc<CompiledTrace<I>>, CString> {
+        let key = sir.block_hash();
+        let blocks = sir.blocks();
+        let before = Instant::now();
+        let compiled = match self.compile::<I>(sir) {
+            Ok(compiled) => compiled,
+            Err(e) => {
+                self.stats.trace_failed();
+                return Err(e);
+            }
+        };
+        self.stats
+            .trace_compiled(before.elapsed(), compiled.code_size());
+        self.stats.ops_compiled(compiled.op_counts());
+        let compiled = Arc::new(compiled);
+        // If another thread cached an identical trace first, use that, so that all guard failures
+        // are counted against the cached trace.
+        let mut compiled_traces = self.compiled_traces.lock();
+        let entry = compiled_traces.entry(key).or_insert_with(|| CacheEntry {
+            blocks: blocks.clone(),
+            trace: Arc::clone(&compiled) as _,
+        });
+        if entry.blocks != blocks {
+            return Ok(compiled);
+        }
+        Ok(Arc::clone(&entry.trace)
+            .into_any()
+            .downcast::<CompiledTrace<I>>()
+            .unwrap_or(compiled))
+    }
+
+    fn dump_traces_to_dir(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        for (hash, e) in self.compiled_traces.lock().iter() {
+            let asm = e.trace.disassemble().map_err(|e| {
+                io::Error::new(io::ErrorKind::Other, e.to_string_lossy().into_owned())
+            })?;
+            fs::write(dir.join(format!("trace_{:016x}.asm", hash)), asm)?;
+        }
+        Ok(())
+    }
+
+    fn guard_stats(&self) -> Vec<(GuardId, u64)> {
+        let mut stats = Vec::new();
+        for (&trace, e) in self.compiled_traces.lock().iter() {
+            for (guard, n) in e.trace.guard_failures().into_iter().enumerate() {
+                stats.push((GuardId { trace, guard }, n));
+            }
+        }
+        stats.sort();
+        stats
+    }
+
+    /// Called when `tr` has failed a guard: report any of its guards which have newly reached the
+    /// guard deoptimisation threshold to the hot guard callback (if there is one).
+    fn report_hot_guards<I: Send + 'static>(&self, tr: &Arc<CompiledTrace<I>>) {
+        let cb = match &*self.hot_guard_callback.lock() {
+            Some(cb) => Arc::clone(cb),
+            None => return,
+        };
+        let threshold = self.guard_deopt_threshold.load(Ordering::Relaxed);
+        let hot = tr
+            .guard_failures()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, n)| *n >= threshold)
+            .map(|(guard, _)| guard)
+            .collect::<Vec<_>>();
+        if hot.is_empty() {
+            return;
+        }
+        // Guards are identified by the cache key of their trace. An invalidated trace, or one
+        // which wasn't cached because of a hash collision, has no key, so isn't reported.
+        let tr_ptr = Arc::as_ptr(tr) as *const ();
+        let trace = match self
+            .compiled_traces
+            .lock()
+            .iter()
+            .find(|(_, e)| ptr::eq(Arc::as_ptr(&e.trace) as *const (), tr_ptr))
+        {
+            Some((&trace, _)) => trace,
+            None => return,
+        };
+        let newly_hot = {
+            let mut hot_guards = self.hot_guards.lock();
+            hot.into_iter()
+                .map(|guard| GuardId { trace, guard })
+                .filter(|gid| hot_guards.insert(*gid))
+                .collect::<Vec<_>>()
+        };
+        // The callback is called without any locks held, so it may itself use the meta-tracer.
+        for gid in newly_hot {
+            cb(gid);
+        }
+    }
+}
+
+/// An entry in the compiled trace cache.
+struct CacheEntry {
+    /// The `(symbol name, block index)` sequence the trace was compiled from. Since the cache is
+    /// keyed by a hash of this, it is compared on lookup so that a hash collision can't cause the
+    /// wrong trace to be executed.
+    blocks: Vec<(String, u32)>,
+    /// An `Arc<CompiledTrace<I>>`, with `I` the interpreter context type.
+    trace: Arc<dyn CachedTrace>,
+}
+
+/// A compiled trace whose interpreter context type has been erased, so that traces for different
+/// interpreter context types can be stored in the same cache.
+trait CachedTrace: Send + Sync {
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+    fn guard_failures(&self) -> Vec<u64>;
+    fn disassemble(&self) -> Result<String, CString>;
+}
+
+impl<I: Send + 'static> CachedTrace for CompiledTrace<I> {
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn guard_failures(&self) -> Vec<u64> {
+        CompiledTrace::guard_failures(self)
+    }
+
+    fn disassemble(&self) -> Result<String, CString> {
+        CompiledTrace::disassemble(self)
+    }
 }
 
 /// A meta-tracer aware thread. Note that this is conceptually a "front-end" to the actual
@@ -186,12 +578,16 @@ impl MTThread {
         // If a loop can start at this position then update the location and potentially start/stop
         // this thread's tracer.
         if let Some(loc) = loc {
-            if let Some(func) = self.transition_location::<I>(loc) {
-                let ptr = self.exec_trace(func, ctx);
+            if let Some(tr) = self.transition_location::<I>(loc) {
+                // `tr` keeps the compiled trace alive, even if it is invalidated by another
+                // thread, until we have finished with it.
+                let ptr = self.exec_trace(&tr, ctx);
                 if ptr.is_null() {
                     // Trace succesfully executed.
                     return;
                 } else {
+                    self.inner.mt.inner.stats.deopt();
+                    self.inner.mt.inner.report_hot_guards(&tr);
                     unsafe {
                         let mut si = StopgapInterpreter(ptr);
                         si.interpret();
@@ -202,21 +598,27 @@ impl MTThread {
         step_fn(ctx)
     }
 
-    fn exec_trace<I>(
-        &mut self,
-        func: fn(&mut I) -> *mut RawStopgapInterpreter,
-        ctx: &mut I,
-    ) -> *mut RawStopgapInterpreter {
-        func(ctx)
+    fn exec_trace<I>(&mut self, tr: &CompiledTrace<I>, ctx: &mut I) -> *mut RawStopgapInterpreter {
+        let func =
+            unsafe { mem::transmute::<_, fn(&mut I) -> *mut RawStopgapInterpreter>(tr.ptr()) };
+        let stats = &self.inner.mt.inner.stats;
+        if stats.time_execution() {
+            let before = Instant::now();
+            let ptr = func(ctx);
+            stats.trace_executed(before.elapsed());
+            ptr
+        } else {
+            func(ctx)
+        }
     }
 
     /// `Location`s represent a statemachine: this function transitions to the next state (which
     /// may be the same as the previous state!). If this results in a compiled trace, it returns
-    /// `Some(pointer_to_trace_function)`.
+    /// `Some(compiled_trace)`.
     fn transition_location<I: Send + 'static>(
         &mut self,
         loc: &Location<I>,
-    ) -> Option<fn(&mut I) -> *mut RawStopgapInterpreter> {
+    ) -> Option<Arc<CompiledTrace<I>>> {
         let mut ls = loc.load(Ordering::Relaxed);
 
         if ls.is_counting() {
@@ -287,8 +689,13 @@ impl MTThread {
                         Ok(_) => {
                             // We've initialised this Location and obtained the lock, so we can now
                             // start tracing for real.
+                            self.inner
+                                .mt
+                                .inner
+                                .hot_locations
+                                .fetch_add(1, Ordering::Relaxed);
                             let tid = Arc::clone(&self.inner.tid);
-                            let tt = start_tracing(self.inner.tracing_kind);
+                            let tt = start_tracing(self.inner.mt.tracing_kind());
                             *unsafe { new_ls.hot_location() } =
                                 HotLocation::Tracing(Some((tid, tt)));
                             Rc::get_mut(&mut self.inner).unwrap().tracing =
@@ -322,7 +729,8 @@ impl MTThread {
                     }
                     match loc.lock() {
                         Ok(x) => ls = x,
-                        Err(()) => unreachable!(),
+                        // The location has been invalidated.
+                        Err(()) => return None,
                     }
                 }
             }
@@ -330,13 +738,9 @@ impl MTThread {
             let hl_ptr = hl as *mut _ as *mut ();
             match hl {
                 HotLocation::Compiled(tr) => {
-                    // FIXME: If we want to free compiled traces, we'll need to refcount (or use
-                    // a GC) to know if anyone's executing that trace at the moment.
-                    let f = unsafe {
-                        mem::transmute::<_, fn(&mut I) -> *mut RawStopgapInterpreter>(tr.ptr())
-                    };
+                    let tr = Arc::clone(tr);
                     loc.unlock();
-                    return Some(f);
+                    return Some(tr);
                 }
                 HotLocation::Compiling(mtx) => {
                     let tr = {
@@ -354,12 +758,20 @@ impl MTThread {
                         }
                         (*gd).take().unwrap()
                     };
-                    let f = unsafe {
-                        mem::transmute::<_, fn(&mut I) -> *mut RawStopgapInterpreter>(tr.ptr())
-                    };
-                    *hl = HotLocation::Compiled(tr);
-                    loc.unlock();
-                    return Some(f);
+                    match tr {
+                        Ok(tr) => {
+                            *hl = HotLocation::Compiled(Arc::clone(&tr));
+                            loc.unlock();
+                            return Some(tr);
+                        }
+                        Err(_) => {
+                            // The trace can't be compiled, so tracing this location again would
+                            // be a waste of time.
+                            *hl = HotLocation::DontTrace;
+                            loc.unlock();
+                            return None;
+                        }
+                    }
                 }
                 HotLocation::Tracing(opt) => {
                     match self.inner.tracing {
@@ -390,6 +802,25 @@ impl MTThread {
                     // while tracing.
                     match opt.take().unwrap().1.stop_tracing() {
                         Ok(sir) => {
+                            if let Some(tr) = self.inner.mt.inner.cached_trace::<I>(&sir) {
+                                // The same blocks have already been traced and compiled.
+                                *hl = HotLocation::Compiled(tr);
+                                loc.unlock();
+                                Rc::get_mut(&mut self.inner).unwrap().tracing = None;
+                                return None;
+                            }
+
+                            if !self.inner.mt.inner.background_compilation {
+                                *hl = match self.inner.mt.inner.compile_and_cache::<I>(sir) {
+                                    Ok(tr) => HotLocation::Compiled(tr),
+                                    // As with background compilation, don't try again.
+                                    Err(_) => HotLocation::DontTrace,
+                                };
+                                loc.unlock();
+                                Rc::get_mut(&mut self.inner).unwrap().tracing = None;
+                                return None;
+                            }
+
                             // Start a compilation thread.
                             let mtx = Arc::new(Mutex::new(None));
                             let mtx_cl = Arc::clone(&mtx);
@@ -397,13 +828,16 @@ impl MTThread {
                             loc.unlock();
 
                             Rc::get_mut(&mut self.inner).unwrap().tracing = None;
-                            thread::spawn(move || {
-                                let compiled = compile_trace::<I>(sir).unwrap();
-                                *mtx_cl.lock() = Some(Box::new(compiled));
-                                // FIXME: although we've now put the compiled trace into the mutex, there's no
-                                // guarantee that the Location for which we're compiling will ever be executed
-                                // again. In such a case, the memory has, in essence, leaked.
-                            });
+                            self.inner.mt.compile_in_background(
+                                sir,
+                                Box::new(move |compiled| {
+                                    *mtx_cl.lock() = Some(compiled);
+                                    // FIXME: although we've now put the compiled trace into the
+                                    // mutex, there's no guarantee that the Location for which
+                                    // we're compiling will ever be executed again. In such a case,
+                                    // the memory has, in essence, leaked.
+                                }),
+                            );
 
                             return None;
                         }
@@ -428,7 +862,6 @@ struct MTThreadInner {
     /// there is (or, at least, was at some point) a Location stuck in PHASE_TRACING.
     tid: Arc<ThreadIdInner>,
     hot_threshold: HotThreshold,
-    tracing_kind: TracingKind,
     /// If this thread is tracing, store a pointer to the `HotLocation`: this allows us to
     /// differentiate which thread is actually tracing the location.
     tracing: Option<*const ()>,
@@ -437,12 +870,10 @@ struct MTThreadInner {
 impl MTThreadInner {
     fn init(mt: MT) -> MTThread {
         let hot_threshold = mt.hot_threshold();
-        let tracing_kind = mt.tracing_kind();
         let inner = MTThreadInner {
             mt,
             tid: Arc::new(ThreadIdInner),
             hot_threshold,
-            tracing_kind,
             tracing: None,
         };
         MTThread {
@@ -454,11 +885,15 @@ impl MTThreadInner {
 
 #[cfg(test)]
 mod tests {
-    use std::thread::yield_now;
+    use std::{process::Command, thread::yield_now};
     extern crate test;
     use self::test::{black_box, Bencher};
     use super::*;
-    use crate::location::{HotLocationDiscriminants, State};
+    use crate::{
+        location::{HotLocationDiscriminants, State},
+        ykstats::print_stats_requested,
+    };
+    use parking_lot::Condvar;
 
     fn hotlocation_discriminant<I>(loc: &Location<I>) -> HotLocationDiscriminants {
         loc.lock().unwrap();
@@ -475,6 +910,74 @@ mod tests {
     #[interp_step]
     fn empty_step(_: &mut EmptyInterpCtx) {}
 
+    const INC: u8 = 0;
+    const RESTART: u8 = 1;
+
+    /// The context of a toy interpreter whose program does nothing twice, then starts again.
+    /// Each `INC` increments `count` and, if `run` is 0, sets `run` to 99, so that setting `run` to
+    /// 0 fails a guard in a trace recorded with `run` set to 1.
+    struct IncCtx {
+        prog: Vec<u8>,
+        pc: usize,
+        count: u64,
+        run: u8,
+    }
+
+    impl IncCtx {
+        fn new() -> Self {
+            IncCtx {
+                prog: vec![INC, INC, RESTART],
+                pc: 0,
+                count: 0,
+                run: 1,
+            }
+        }
+    }
+
+    #[interp_step]
+    fn inc_step(ctx: &mut IncCtx) {
+        match ctx.prog[ctx.pc] {
+            INC => {
+                ctx.pc += 1;
+                ctx.count += 1;
+                if ctx.run == 0 {
+                    ctx.run = 99;
+                }
+            }
+            RESTART => ctx.pc = 0,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Run the `IncCtx` program on `mtt` until a trace has been compiled for its only location,
+    /// which is at the start of the program. Returns that location and the context, whose `pc` is
+    /// 0.
+    fn run_until_compiled(mtt: &mut MTThread) -> (Location<IncCtx>, IncCtx) {
+        let loc = Location::new();
+        let mut ctx = IncCtx::new();
+        loop {
+            if ctx.pc == 0
+                && !loc.load(Ordering::Relaxed).is_counting()
+                && hotlocation_discriminant(&loc) == HotLocationDiscriminants::Compiled
+            {
+                break;
+            }
+            mtt.control_point(inc_loc(&loc, &ctx), inc_step, &mut ctx);
+            yield_now();
+        }
+        (loc, ctx)
+    }
+
+    /// Returns `loc` if `ctx` is at the start of its program, i.e. the location passed to
+    /// `control_point` in the `IncCtx` interpreter.
+    fn inc_loc<'a>(loc: &'a Location<IncCtx>, ctx: &IncCtx) -> Option<&'a Location<IncCtx>> {
+        if ctx.pc == 0 {
+            Some(loc)
+        } else {
+            None
+        }
+    }
+
     #[test]
     fn threshold_passed() {
         let hot_thrsh = 1500;
@@ -485,12 +988,14 @@ mod tests {
             mtt.control_point(Some(&loc), empty_step, &mut ctx);
             assert_eq!(loc.load(Ordering::Relaxed), State::new().with_count(i + 1));
         }
+        assert_eq!(mtt.mt().hot_location_count(), 0);
         assert!(loc.load(Ordering::Relaxed).is_counting());
         mtt.control_point(Some(&loc), empty_step, &mut ctx);
         assert_eq!(
             hotlocation_discriminant(&loc),
             HotLocationDiscriminants::Tracing
         );
+        assert_eq!(mtt.mt().hot_location_count(), 1);
         mtt.control_point(Some(&loc), empty_step, &mut ctx);
         assert!([
             HotLocationDiscriminants::Compiling,
@@ -499,6 +1004,17 @@ mod tests {
         .contains(&hotlocation_discriminant(&loc)));
     }
 
+    #[test]
+    fn set_tracing_kind() {
+        let mtt = MTBuilder::new().init();
+        let kind = mtt.mt().tracing_kind();
+        mtt.mt().set_tracing_kind(TracingKind::SoftwareTracing);
+        assert_eq!(mtt.mt().tracing_kind(), TracingKind::SoftwareTracing);
+        mtt.mt().set_tracing_kind(TracingKind::HardwareTracing);
+        assert_eq!(mtt.mt().tracing_kind(), TracingKind::HardwareTracing);
+        mtt.mt().set_tracing_kind(kind);
+    }
+
     #[test]
     fn stop_while_tracing() {
         let hot_thrsh = 5;
@@ -948,4 +1464,335 @@ mod tests {
         mtt.control_point(loc, simple_interp_step, &mut ctx);
         assert_eq!(ctx.run, 99);
     }
+
+    #[test]
+    fn compile_in_background() {
+        let mtt = MTBuilder::new().init();
+
+        struct InterpCtx(u64);
+
+        #[interp_step]
+        fn simple_interp_step(ctx: &mut InterpCtx) {
+            ctx.0 += 1;
+        }
+
+        let th = start_tracing(mtt.mt().tracing_kind());
+        simple_interp_step(&mut InterpCtx(0));
+        let sir = th.stop_tracing().unwrap();
+
+        let done = Arc::new((Mutex::new(None), Condvar::new()));
+        let done_cl = Arc::clone(&done);
+        mtt.mt().compile_in_background::<InterpCtx>(
+            sir,
+            Box::new(move |ct| {
+                *done_cl.0.lock() = Some(ct);
+                done_cl.1.notify_one();
+            }),
+        );
+
+        let (mtx, cv) = &*done;
+        let mut gd = mtx.lock();
+        while gd.is_none() {
+            cv.wait(&mut gd);
+        }
+        let ct = gd.take().unwrap().unwrap();
+        assert!(!ct.ptr().is_null());
+        let mut ctx = InterpCtx(10);
+        assert!(unsafe { ct.execute(&mut ctx) }.is_null());
+        assert_eq!(ctx.0, 11);
+        assert_eq!(mtt.mt().stats().traces_compiled, 1);
+    }
+
+    #[test]
+    fn dump_traces_to_dir() {
+        let mtt = MTBuilder::new().init();
+
+        struct InterpCtx(u64);
+
+        #[interp_step]
+        fn simple_interp_step(ctx: &mut InterpCtx) {
+            ctx.0 += 1;
+        }
+
+        let th = start_tracing(mtt.mt().tracing_kind());
+        simple_interp_step(&mut InterpCtx(0));
+        let sir = th.stop_tracing().unwrap();
+        let hash = sir.block_hash();
+
+        let done = Arc::new((Mutex::new(false), Condvar::new()));
+        let done_cl = Arc::clone(&done);
+        mtt.mt().compile_in_background::<InterpCtx>(
+            sir,
+            Box::new(move |_| {
+                *done_cl.0.lock() = true;
+                done_cl.1.notify_one();
+            }),
+        );
+        let (mtx, cv) = &*done;
+        let mut gd = mtx.lock();
+        while !*gd {
+            cv.wait(&mut gd);
+        }
+
+        let dir = env::temp_dir().join(format!("yk_dump_traces_{}", std::process::id()));
+        // Disassembly requires `rasm2`, which may not be installed.
+        if Command::new("rasm2").arg("-v").output().is_err() {
+            assert!(mtt.mt().dump_traces_to_dir(&dir).is_err());
+            let _ = fs::remove_dir_all(&dir);
+            return;
+        }
+        mtt.mt().dump_traces_to_dir(&dir).unwrap();
+        let asm = fs::read_to_string(dir.join(format!("trace_{:016x}.asm", hash))).unwrap();
+        assert!(!asm.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn set_compile_fn() {
+        let mtt = MTBuilder::new().init();
+
+        struct InterpCtx(u64);
+
+        #[interp_step]
+        fn simple_interp_step(ctx: &mut InterpCtx) {
+            ctx.0 += 1;
+        }
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_cl = Arc::clone(&calls);
+        unsafe {
+            mtt.mt().set_compile_fn(Box::new(move |sir| {
+                calls_cl.fetch_add(1, Ordering::Relaxed);
+                compile_trace(sir)
+            }));
+        }
+
+        let th = start_tracing(mtt.mt().tracing_kind());
+        simple_interp_step(&mut InterpCtx(0));
+        let sir = th.stop_tracing().unwrap();
+
+        let done = Arc::new((Mutex::new(None), Condvar::new()));
+        let done_cl = Arc::clone(&done);
+        mtt.mt().compile_in_background::<InterpCtx>(
+            sir,
+            Box::new(move |ct| {
+                *done_cl.0.lock() = Some(ct);
+                done_cl.1.notify_one();
+            }),
+        );
+
+        let (mtx, cv) = &*done;
+        let mut gd = mtx.lock();
+        while gd.is_none() {
+            cv.wait(&mut gd);
+        }
+        let ct = gd.take().unwrap().unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        let mut ctx = InterpCtx(10);
+        assert!(unsafe { ct.execute(&mut ctx) }.is_null());
+        assert_eq!(ctx.0, 11);
+    }
+
+    #[test]
+    fn guard_stats() {
+        let mut mtt = MTBuilder::new().hot_threshold(2).init();
+        let (loc, mut ctx) = run_until_compiled(&mut mtt);
+        assert!(mtt.mt().guard_stats().iter().all(|(_, n)| *n == 0));
+
+        let hot = Arc::new(Mutex::new(Vec::new()));
+        let hot_cl = Arc::clone(&hot);
+        mtt.mt().set_guard_deopt_threshold(5);
+        mtt.mt()
+            .on_hot_guard(Box::new(move |gid| hot_cl.lock().push(gid)));
+
+        // Fail the same guard 10 times.
+        for _ in 0..10 {
+            ctx.pc = 0;
+            ctx.run = 0;
+            mtt.control_point(Some(&loc), inc_step, &mut ctx);
+            assert_eq!(ctx.run, 99);
+        }
+
+        let failed = mtt
+            .mt()
+            .guard_stats()
+            .into_iter()
+            .filter(|(_, n)| *n > 0)
+            .collect::<Vec<_>>();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].1, 10);
+        assert_eq!(*hot.lock(), vec![failed[0].0]);
+        assert_eq!(mtt.mt().stats().total_deopts, 10);
+    }
+
+    #[test]
+    fn stats() {
+        let mut mtt = MTBuilder::new().hot_threshold(2).init();
+        assert_eq!(mtt.mt().stats(), YkStats::default());
+        let (loc, mut ctx) = run_until_compiled(&mut mtt);
+
+        let stats = mtt.mt().stats();
+        assert_eq!(stats.traces_compiled, 1);
+        assert!(stats.total_compile_ns > 0);
+        assert!(stats.total_code_bytes > 0);
+        assert_eq!(stats.total_deopts, 0);
+        let hist = mtt.mt().trace_op_histogram();
+        assert!(hist["Guard"] > 0);
+        assert!(hist.values().all(|&c| c > 0));
+
+        // Execute the trace successfully, then fail a guard.
+        mtt.control_point(Some(&loc), inc_step, &mut ctx);
+        assert_eq!(mtt.mt().stats().total_deopts, 0);
+        ctx.pc = 0;
+        ctx.run = 0;
+        mtt.control_point(Some(&loc), inc_step, &mut ctx);
+        assert_eq!(ctx.run, 99);
+
+        let stats = mtt.mt().stats();
+        assert_eq!(stats.traces_compiled, 1);
+        assert_eq!(stats.total_execute_ns > 0, print_stats_requested());
+        assert_eq!(stats.total_deopts, 1);
+    }
+
+    #[test]
+    fn compilation_failure() {
+        let mut mtt = MTBuilder::new().hot_threshold(2).init();
+        unsafe {
+            mtt.mt()
+                .set_compile_fn(Box::new(|_| Err(CString::new("unimplemented").unwrap())));
+        }
+
+        // A location whose trace fails to compile is never traced again.
+        let loc = Location::new();
+        let mut ctx = IncCtx::new();
+        loop {
+            if ctx.pc == 0
+                && !loc.load(Ordering::Relaxed).is_counting()
+                && hotlocation_discriminant(&loc) == HotLocationDiscriminants::DontTrace
+            {
+                break;
+            }
+            mtt.control_point(inc_loc(&loc, &ctx), inc_step, &mut ctx);
+            yield_now();
+        }
+        let stats = mtt.mt().stats();
+        assert_eq!(stats.traces_compiled, 0);
+        assert_eq!(stats.traces_failed, 1);
+
+        // The interpreter carries on regardless.
+        ctx.count = 0;
+        for _ in 0..10 {
+            mtt.control_point(inc_loc(&loc, &ctx), inc_step, &mut ctx);
+        }
+        assert_eq!(ctx.count, 7);
+        assert_eq!(mtt.mt().stats().traces_failed, 1);
+    }
+
+    #[test]
+    fn foreground_compilation() {
+        let mut mtt = MTBuilder::new()
+            .hot_threshold(1)
+            .background_compilation(false)
+            .init();
+
+        let loc = Location::new();
+        let mut ctx = IncCtx::new();
+        // The first visit to the location makes it hot, the second starts tracing, and the third
+        // stops tracing and compiles the trace before returning.
+        for _ in 0..3 {
+            loop {
+                mtt.control_point(inc_loc(&loc, &ctx), inc_step, &mut ctx);
+                if ctx.pc == 0 {
+                    break;
+                }
+            }
+        }
+        assert!(!loc.load(Ordering::Relaxed).is_counting());
+        assert_eq!(
+            hotlocation_discriminant(&loc),
+            HotLocationDiscriminants::Compiled
+        );
+        assert_eq!(mtt.mt().stats().traces_compiled, 1);
+
+        ctx.count = 0;
+        mtt.control_point(Some(&loc), inc_step, &mut ctx);
+        while ctx.pc != 0 {
+            mtt.control_point(None, inc_step, &mut ctx);
+        }
+        assert_eq!(ctx.count, 2);
+    }
+
+    #[test]
+    fn invalidate_trace() {
+        const NUM_THREADS: usize = 8;
+        const ITERS: u64 = 10000;
+
+        let mut mtt = MTBuilder::new().hot_threshold(2).init();
+        let (loc, _) = run_until_compiled(&mut mtt);
+        let loc = Arc::new(loc);
+
+        // Whether they execute the compiled trace, or fall back to the interpreter because the
+        // trace has been invalidated, the other threads must compute the same result.
+        let done = Arc::new(AtomicUsize::new(0));
+        let mut thrs = vec![];
+        for _ in 0..NUM_THREADS {
+            let loc = Arc::clone(&loc);
+            let done = Arc::clone(&done);
+            let mut ctx = IncCtx::new();
+            let t = mtt
+                .mt()
+                .spawn(move |mut mtt| {
+                    for _ in 0..ITERS {
+                        mtt.control_point(Some(&*loc), inc_step, &mut ctx);
+                        while ctx.pc != 0 {
+                            mtt.control_point(None, inc_step, &mut ctx);
+                        }
+                    }
+                    done.fetch_add(1, Ordering::Relaxed);
+                    ctx.count
+                })
+                .unwrap();
+            thrs.push(t);
+        }
+
+        assert!(mtt.mt().invalidate_trace(&*loc));
+        assert!(loc.load(Ordering::Relaxed).is_counting());
+        assert!(!mtt.mt().invalidate_trace(&*loc));
+        // The other threads may retrace and recompile the location: keep invalidating it.
+        while done.load(Ordering::Relaxed) < NUM_THREADS {
+            mtt.mt().invalidate_trace(&*loc);
+            yield_now();
+        }
+        for t in thrs {
+            assert_eq!(t.join().unwrap(), ITERS * 2);
+        }
+    }
+
+    #[test]
+    fn compiled_trace_cache() {
+        let mut mtt = MTBuilder::new().hot_threshold(2).init();
+
+        fn compiled_trace<I>(loc: &Location<I>) -> Arc<CompiledTrace<I>> {
+            loc.lock().unwrap();
+            let ls = loc.load(Ordering::Acquire);
+            let tr = match unsafe { ls.hot_location() } {
+                HotLocation::Compiled(tr) => Arc::clone(tr),
+                _ => unreachable!(),
+            };
+            loc.unlock();
+            tr
+        }
+
+        // Run the same program with two different locations: the second time around, the recorded
+        // trace visits exactly the same blocks as the first.
+        let mut trs = Vec::new();
+        for _ in 0..2 {
+            let (loc, _) = run_until_compiled(&mut mtt);
+            trs.push(compiled_trace(&loc));
+        }
+
+        assert_eq!(mtt.mt().cache_miss_count(), 1);
+        assert_eq!(mtt.mt().cache_hit_count(), 1);
+        assert!(Arc::ptr_eq(&trs[0], &trs[1]));
+    }
 }
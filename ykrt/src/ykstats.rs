@@ -0,0 +1,150 @@
+//! Statistics about what the meta-tracer has been doing.
+
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    env, fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// A snapshot of a meta-tracer's statistics, as returned by [`MT::stats`](../mt/struct.MT.html).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct YkStats {
+    /// The number of traces which have been successfully compiled.
+    pub traces_compiled: u64,
+    /// The number of traces which failed to compile, e.g. because they contain something the trace
+    /// compiler doesn't yet support. The locations they were recorded at are never traced again.
+    pub traces_failed: u64,
+    /// The total time spent compiling traces, in nanoseconds.
+    pub total_compile_ns: u64,
+    /// The total size, in bytes, of the machine code of all compiled traces.
+    pub total_code_bytes: u64,
+    /// The total time spent executing compiled traces, in nanoseconds. This includes the time
+    /// taken for a trace to fail a guard, but not the time then spent in the stopgap interpreter.
+    /// Timing each execution of a trace is costly, so this is only measured if
+    /// `YKD_PRINT_STATS=1`, and is otherwise always 0.
+    pub total_execute_ns: u64,
+    /// The number of times a compiled trace failed a guard and handed over to the stopgap
+    /// interpreter.
+    pub total_deopts: u64,
+}
+
+impl fmt::Display for YkStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "traces_compiled: {}", self.traces_compiled)?;
+        writeln!(f, "traces_failed: {}", self.traces_failed)?;
+        writeln!(f, "total_compile_ns: {}", self.total_compile_ns)?;
+        writeln!(f, "total_code_bytes: {}", self.total_code_bytes)?;
+        writeln!(f, "total_execute_ns: {}", self.total_execute_ns)?;
+        write!(f, "total_deopts: {}", self.total_deopts)
+    }
+}
+
+/// The counters backing `YkStats`. These are updated concurrently by all of a meta-tracer's
+/// threads (including the compilation threads).
+#[derive(Default)]
+pub(crate) struct Stats {
+    traces_compiled: AtomicU64,
+    traces_failed: AtomicU64,
+    compile_ns: AtomicU64,
+    code_bytes: AtomicU64,
+    execute_ns: AtomicU64,
+    deopts: AtomicU64,
+    /// The number of TIR ops of each kind in all compiled traces.
+    op_counts: Mutex<HashMap<String, usize>>,
+    /// Should the execution of compiled traces be timed?
+    time_execution: bool,
+}
+
+impl Stats {
+    pub(crate) fn new() -> Self {
+        Stats {
+            time_execution: print_stats_requested(),
+            ..Default::default()
+        }
+    }
+
+    /// Should callers time the execution of compiled traces and report it with `trace_executed`?
+    pub(crate) fn time_execution(&self) -> bool {
+        self.time_execution
+    }
+
+    /// Record that a trace of `code_size` bytes was compiled in `d` time.
+    pub(crate) fn trace_compiled(&self, d: Duration, code_size: usize) {
+        self.traces_compiled.fetch_add(1, Ordering::Relaxed);
+        self.compile_ns.fetch_add(duration_ns(d), Ordering::Relaxed);
+        self.code_bytes
+            .fetch_add(u64::try_from(code_size).unwrap(), Ordering::Relaxed);
+    }
+
+    /// Record that a trace failed to compile.
+    pub(crate) fn trace_failed(&self) {
+        self.traces_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that a trace containing `op_counts` TIR ops of each kind was compiled.
+    pub(crate) fn ops_compiled(&self, op_counts: HashMap<String, usize>) {
+        let mut all = self.op_counts.lock();
+        for (kind, count) in op_counts {
+            *all.entry(kind).or_insert(0) += count;
+        }
+    }
+
+    /// Return the number of TIR ops of each kind in all compiled traces.
+    pub(crate) fn op_histogram(&self) -> HashMap<String, usize> {
+        self.op_counts.lock().clone()
+    }
+
+    /// Record that a compiled trace executed for `d` time.
+    pub(crate) fn trace_executed(&self, d: Duration) {
+        self.execute_ns.fetch_add(duration_ns(d), Ordering::Relaxed);
+    }
+
+    /// Record that a compiled trace failed a guard.
+    pub(crate) fn deopt(&self) {
+        self.deopts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> YkStats {
+        YkStats {
+            traces_compiled: self.traces_compiled.load(Ordering::Relaxed),
+            traces_failed: self.traces_failed.load(Ordering::Relaxed),
+            total_compile_ns: self.compile_ns.load(Ordering::Relaxed),
+            total_code_bytes: self.code_bytes.load(Ordering::Relaxed),
+            total_execute_ns: self.execute_ns.load(Ordering::Relaxed),
+            total_deopts: self.deopts.load(Ordering::Relaxed),
+        }
+    }
+
+    /// If `YKD_PRINT_STATS=1`, print the statistics to stderr. If `YKD_PRINT_IR_HISTOGRAM=1`, print
+    /// the number of TIR ops of each kind in all compiled traces to stderr, most frequent first.
+    pub(crate) fn print_if_requested(&self) {
+        if print_stats_requested() {
+            eprintln!("{}", self.snapshot());
+        }
+        if env::var("YKD_PRINT_IR_HISTOGRAM")
+            .map(|v| v == "1")
+            .unwrap_or(false)
+        {
+            let mut hist = self.op_histogram().into_iter().collect::<Vec<_>>();
+            hist.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            for (kind, count) in hist {
+                eprintln!("{}: {}", kind, count);
+            }
+        }
+    }
+}
+
+/// Has `YKD_PRINT_STATS=1` been set?
+pub(crate) fn print_stats_requested() -> bool {
+    env::var("YKD_PRINT_STATS")
+        .map(|v| v == "1")
+        .unwrap_or(false)
+}
+
+/// Convert `d` into nanoseconds, saturating at `u64::MAX` (which is over 500 years).
+fn duration_ns(d: Duration) -> u64 {
+    u64::try_from(d.as_nanos()).unwrap_or(u64::MAX)
+}
@@ -3,7 +3,8 @@
 //! For more information, see this section in the documentation:
 //! https://softdevteam.github.io/ykdocs/tech/yk_structure.html
 //!
-//! Put anything used only in testing in the `test_api` module.
+//! Put anything used only in testing in the `test_api` module, which is only available with the
+//! `yk_testing` feature.
 //!
 //! The exception to this rule is `Drop` implementations for opaque pointer wrappers. These should
 //! always go in the `prod_api` module. It's hard to know all of the call sites for `drop()` since
@@ -11,17 +12,22 @@
 
 // FIXME handle all errors that may pass over the API boundary.
 
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::ffi::{c_void, CString};
 use std::marker::PhantomData;
 use std::os::raw::c_char;
 use std::{mem, ptr};
 
+#[cfg(feature = "yk_testing")]
 mod test_api;
+#[cfg(feature = "yk_testing")]
 pub use test_api::*;
 
 pub(crate) type RawCompiledTrace = c_void;
 pub(crate) type RawSirTrace = c_void;
 type RawThreadTracer = c_void;
+#[cfg(feature = "yk_testing")]
 pub(crate) type RawTirTrace = c_void;
 pub type RawStopgapInterpreter = c_void;
 
@@ -37,23 +43,56 @@ pub struct TyIndex(pub u32);
 
 extern "C" {
     fn __ykshim_start_tracing(tracing_kind: u8) -> *mut RawThreadTracer;
+    fn __ykshim_start_tracing_with_budget(
+        tracing_kind: u8,
+        max_blocks: usize,
+    ) -> *mut RawThreadTracer;
     fn __ykshim_stop_tracing(
         tracer: *mut RawThreadTracer,
         error_msg: *mut *mut c_char,
     ) -> *mut RawSirTrace;
+    fn __ykshim_is_tracing() -> bool;
+    fn __ykshim_tracing_depth() -> usize;
+    fn __ykshim_install_trace_dump_hook();
     fn __ykshim_compile_trace(
         sir_trace: *mut RawSirTrace,
         error_msg: *mut *mut c_char,
     ) -> *mut RawCompiledTrace;
     fn __ykshim_compiled_trace_get_ptr(compiled_trace: *const RawCompiledTrace) -> *const c_void;
+    fn __ykshim_compiled_trace_code_size(compiled_trace: *const RawCompiledTrace) -> usize;
+    fn __ykshim_compiled_trace_op_counts(compiled_trace: *const RawCompiledTrace) -> *mut c_char;
+    fn __ykshim_compiled_trace_guard_count(compiled_trace: *const RawCompiledTrace) -> usize;
+    fn __ykshim_compiled_trace_guard_failures(
+        compiled_trace: *const RawCompiledTrace,
+        counts: *mut u64,
+    );
+    fn __ykshim_compiled_trace_guard_offset(
+        compiled_trace: *const RawCompiledTrace,
+        guard: usize,
+    ) -> isize;
+    fn __ykshim_compiled_trace_disassemble_range(
+        compiled_trace: *const RawCompiledTrace,
+        start: usize,
+        end: usize,
+        error_msg: *mut *mut c_char,
+    ) -> *mut c_char;
+    fn __ykshim_compiled_trace_exit_ptr(compiled_trace: *const RawCompiledTrace) -> *const c_void;
+    fn __ykshim_compiled_trace_patch_guard(
+        compiled_trace: *const RawCompiledTrace,
+        guard: usize,
+        target: *const c_void,
+        error_msg: *mut *mut c_char,
+    ) -> bool;
     fn __ykshim_compiled_trace_drop(compiled_trace: *mut RawCompiledTrace);
+    fn __ykshim_sirtrace_block_hash(trace: *const RawSirTrace) -> u64;
+    fn __ykshim_sirtrace_blocks(trace: *const RawSirTrace) -> *mut c_char;
     fn __ykshim_sirtrace_drop(trace: *mut RawSirTrace);
     fn __ykshim_si_interpret(interp: *mut RawStopgapInterpreter);
     fn __ykshim_sirinterpreter_drop(interp: *mut RawStopgapInterpreter);
 }
 
 /// The different ways by which we can collect a trace.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum TracingKind {
     /// Software tracing via ykrustc.
@@ -71,6 +110,31 @@ pub fn start_tracing(tracing_kind: TracingKind) -> ThreadTracer {
     ThreadTracer(tracer)
 }
 
+/// Start tracing using the specified kind of tracing, limiting the trace to `max_blocks` blocks.
+/// If the trace is longer than this, stopping tracing returns an error.
+pub fn start_tracing_with_budget(tracing_kind: TracingKind, max_blocks: usize) -> ThreadTracer {
+    let tracer = unsafe { __ykshim_start_tracing_with_budget(tracing_kind as u8, max_blocks) };
+    debug_assert!(!tracer.is_null());
+    ThreadTracer(tracer)
+}
+
+/// Is the current thread being traced?
+pub fn is_tracing() -> bool {
+    unsafe { __ykshim_is_tracing() }
+}
+
+/// Return the number of tracers active on the current thread. Since nested tracing is not yet
+/// supported, this is currently always 0 or 1.
+pub fn tracing_depth() -> usize {
+    unsafe { __ykshim_tracing_depth() }
+}
+
+/// Install a panic hook which, if the trace compiler panics while `YKD_DUMP_TRACE_ON_ABORT` is
+/// set, writes out the trace being compiled. Any previously installed panic hook is still called.
+pub fn install_trace_dump_hook() {
+    unsafe { __ykshim_install_trace_dump_hook() }
+}
+
 impl ThreadTracer {
     pub fn stop_tracing(mut self) -> Result<SirTrace, CString> {
         let mut err_msg = std::ptr::null_mut();
@@ -112,6 +176,28 @@ pub struct SirTrace(pub(crate) *mut RawSirTrace);
 unsafe impl Send for SirTrace {}
 unsafe impl Sync for SirTrace {}
 
+impl SirTrace {
+    /// Return a hash of the sequence of blocks in this trace. Traces which visit the same blocks
+    /// in the same order have the same hash.
+    pub fn block_hash(&self) -> u64 {
+        unsafe { __ykshim_sirtrace_block_hash(self.0) }
+    }
+
+    /// Return the sequence of blocks in this trace as `(symbol name, block index)` pairs.
+    pub fn blocks(&self) -> Vec<(String, u32)> {
+        let blocks = unsafe { CString::from_raw(__ykshim_sirtrace_blocks(self.0)) };
+        blocks
+            .to_str()
+            .unwrap()
+            .lines()
+            .map(|l| {
+                let (sym, bb) = l.split_at(l.rfind(' ').unwrap());
+                (sym.to_owned(), bb[1..].parse().unwrap())
+            })
+            .collect()
+    }
+}
+
 impl Drop for SirTrace {
     fn drop(&mut self) {
         if !self.0.is_null() {
@@ -146,6 +232,80 @@ impl<I> CompiledTrace<I> {
         unsafe { __ykshim_compiled_trace_get_ptr(self.compiled) as *const u8 }
     }
 
+    /// Return the size, in bytes, of the trace's machine code.
+    pub fn code_size(&self) -> usize {
+        unsafe { __ykshim_compiled_trace_code_size(self.compiled) }
+    }
+
+    /// Return the number of ops of each kind (e.g. `"BinaryOp"` or `"Guard"`) in the TIR trace
+    /// this trace was compiled from.
+    pub fn op_counts(&self) -> HashMap<String, usize> {
+        let counts = unsafe { CString::from_raw(__ykshim_compiled_trace_op_counts(self.compiled)) };
+        counts
+            .to_str()
+            .unwrap()
+            .lines()
+            .map(|l| {
+                let (kind, count) = l.split_at(l.rfind(' ').unwrap());
+                (kind.to_owned(), count[1..].parse().unwrap())
+            })
+            .collect()
+    }
+
+    /// Return the number of times each of the trace's guards has failed, in the order that the
+    /// guards appear in the trace.
+    pub fn guard_failures(&self) -> Vec<u64> {
+        let mut counts = vec![0; unsafe { __ykshim_compiled_trace_guard_count(self.compiled) }];
+        unsafe { __ykshim_compiled_trace_guard_failures(self.compiled, counts.as_mut_ptr()) };
+        counts
+    }
+
+    /// Return the offset, in bytes from the start of the trace, of the first instruction of the
+    /// `guard`th guard, or `None` if the trace has no such guard.
+    pub fn find_guard_offset(&self, guard: usize) -> Option<usize> {
+        let off = unsafe { __ykshim_compiled_trace_guard_offset(self.compiled, guard) };
+        usize::try_from(off).ok()
+    }
+
+    /// Disassemble the trace's machine code. Requires `rasm2`.
+    pub fn disassemble(&self) -> Result<String, CString> {
+        self.disassemble_range(0, self.code_size())
+    }
+
+    /// Disassemble the machine code between byte offsets `start` (inclusive) and `end`
+    /// (exclusive).
+    pub fn disassemble_range(&self, start: usize, end: usize) -> Result<String, CString> {
+        let mut err_msg = std::ptr::null_mut();
+        let asm = unsafe {
+            __ykshim_compiled_trace_disassemble_range(self.compiled, start, end, &mut err_msg)
+        };
+        if asm.is_null() {
+            return Err(unsafe { CString::from_raw(err_msg) });
+        }
+        Ok(unsafe { CString::from_raw(asm) }.into_string().unwrap())
+    }
+
+    /// Return a pointer to the code which returns from the trace as if no guard had failed.
+    pub fn exit_ptr(&self) -> *const u8 {
+        unsafe { __ykshim_compiled_trace_exit_ptr(self.compiled) as *const u8 }
+    }
+
+    /// Patch the `guard`th guard so that, when it fails, it jumps to `target` rather than handing
+    /// over to the stopgap interpreter.
+    ///
+    /// # Safety
+    ///
+    /// No thread may be running the guard's failure code while it is patched, and `target` must
+    /// be code which can cope with the trace's stack frame and registers.
+    pub unsafe fn patch_guard(&self, guard: usize, target: *const u8) -> Result<(), CString> {
+        let mut err_msg = std::ptr::null_mut();
+        let target = target as *const c_void;
+        if !__ykshim_compiled_trace_patch_guard(self.compiled, guard, target, &mut err_msg) {
+            return Err(CString::from_raw(err_msg));
+        }
+        Ok(())
+    }
+
     /// Execute the trace with the given interpreter context.
     pub unsafe fn execute(&self, ctx: &mut I) -> *mut RawStopgapInterpreter {
         let f = mem::transmute::<_, fn(&mut I) -> *mut RawStopgapInterpreter>(self.ptr());
@@ -153,6 +313,23 @@ impl<I> CompiledTrace<I> {
     }
 }
 
+impl<I> CompiledTrace<I> {
+    /// Reinterpret this trace as one which takes an interpreter context of type `J`.
+    ///
+    /// # Safety
+    ///
+    /// The trace must have been recorded from an interpreter whose context has type `J`, since
+    /// the trace's code accesses the context according to that type's layout.
+    pub unsafe fn cast<J>(self) -> CompiledTrace<J> {
+        let compiled = self.compiled;
+        mem::forget(self);
+        CompiledTrace {
+            compiled,
+            _marker: PhantomData,
+        }
+    }
+}
+
 impl<I> Drop for CompiledTrace<I> {
     fn drop(&mut self) {
         unsafe { __ykshim_compiled_trace_drop(self.compiled) }
@@ -22,7 +22,18 @@ pub struct TypeId {
 }
 
 extern "C" {
-    fn __ykshimtest_compile_tir_trace(tir_trace: *mut RawTirTrace) -> *mut RawCompiledTrace;
+    fn __ykshimtest_compile_tir_trace(
+        tir_trace: *mut RawTirTrace,
+        error_msg: *mut *mut c_char,
+    ) -> *mut RawCompiledTrace;
+    fn __ykshimtest_compile_tir_trace_with_options(
+        tir_trace: *mut RawTirTrace,
+        reg_pool: *const u8,
+        reg_pool_len: usize,
+        max_stack_bytes: i64,
+        loop_iterations: i64,
+        error_msg: *mut *mut c_char,
+    ) -> *mut RawCompiledTrace;
     fn __ykshimtest_sirtrace_len(sir_trace: *mut RawSirTrace) -> size_t;
     fn __ykshimtest_tirtrace_new(sir_trace: *mut RawSirTrace) -> *mut RawTirTrace;
     fn __ykshim_tirtrace_drop(tir_trace: *mut RawTirTrace);
@@ -41,6 +52,8 @@ extern "C" {
         tc: *mut RawTraceCompiler,
         local: Local,
     ) -> *mut c_char;
+    fn __ykshimtest_tracecompiler_set_last_use(tc: *mut RawTraceCompiler, local: Local, idx: usize);
+    fn __ykshimtest_tracecompiler_local_live(tc: *mut RawTraceCompiler, local: Local);
     fn __ykshimtest_tracecompiler_local_dead(tc: *mut RawTraceCompiler, local: Local);
     fn __ykshimtest_find_symbol(sym: *const c_char) -> *mut c_void;
     fn __ykshimtest_interpret_body(body_name: *const c_char, ctx: *mut u8);
@@ -117,6 +130,14 @@ impl TraceCompiler {
         String::from(unsafe { CString::from_raw(ptr).to_str().unwrap() })
     }
 
+    pub fn set_last_use(&mut self, local: Local, idx: usize) {
+        unsafe { __ykshimtest_tracecompiler_set_last_use(self.0, local, idx) };
+    }
+
+    pub fn local_live(&mut self, local: Local) {
+        unsafe { __ykshimtest_tracecompiler_local_live(self.0, local) };
+    }
+
     pub fn local_dead(&mut self, local: Local) {
         unsafe { __ykshimtest_tracecompiler_local_dead(self.0, local) };
     }
@@ -148,8 +169,56 @@ pub fn reg_pool_size() -> usize {
 }
 
 pub fn compile_tir_trace<T>(mut tir_trace: TirTrace) -> Result<CompiledTrace<T>, CString> {
-    let compiled = unsafe { __ykshimtest_compile_tir_trace(tir_trace.0) };
+    let mut err_msg = ptr::null_mut();
+    let compiled = unsafe { __ykshimtest_compile_tir_trace(tir_trace.0, &mut err_msg) };
     tir_trace.0 = ptr::null_mut(); // consumed.
+    if compiled.is_null() {
+        return Err(unsafe { CString::from_raw(err_msg) });
+    }
+    Ok(CompiledTrace {
+        compiled,
+        _marker: PhantomData,
+    })
+}
+
+/// Options controlling the compilation of a trace. Fields left as `None` take their default values.
+#[derive(Default)]
+pub struct CompileOptions {
+    /// The registers (by x86_64 register number) that the register allocator may use. These must
+    /// be a subset of the default register pool.
+    pub register_pool: Option<Vec<u8>>,
+    /// If set, compilation fails if the trace's locals need more than this many bytes of stack.
+    pub max_stack_bytes: Option<u32>,
+    /// If set, the trace jumps back to its start after each run, running (at most) this many
+    /// times before returning.
+    pub loop_iterations: Option<u32>,
+}
+
+pub fn compile_tir_trace_with_options<T>(
+    mut tir_trace: TirTrace,
+    opts: &CompileOptions,
+) -> Result<CompiledTrace<T>, CString> {
+    let (reg_pool, reg_pool_len) = match &opts.register_pool {
+        Some(pool) => (pool.as_ptr(), pool.len()),
+        None => (ptr::null(), 0),
+    };
+    let max_stack_bytes = opts.max_stack_bytes.map(i64::from).unwrap_or(-1);
+    let loop_iterations = opts.loop_iterations.map(i64::from).unwrap_or(-1);
+    let mut err_msg = ptr::null_mut();
+    let compiled = unsafe {
+        __ykshimtest_compile_tir_trace_with_options(
+            tir_trace.0,
+            reg_pool,
+            reg_pool_len,
+            max_stack_bytes,
+            loop_iterations,
+            &mut err_msg,
+        )
+    };
+    tir_trace.0 = ptr::null_mut(); // consumed.
+    if compiled.is_null() {
+        return Err(unsafe { CString::from_raw(err_msg) });
+    }
     Ok(CompiledTrace {
         compiled,
         _marker: PhantomData,